@@ -0,0 +1,1299 @@
+use crate::{
+    label_policy::LabelPolicy,
+    logging::{log_filter, LogFormat, DEFAULT_LOG_LEVEL},
+    negative_cache::DEFAULT_NEGATIVE_CACHE_SIZE,
+    nostr_events_repository::DEFAULT_ZONE_EVENT_KIND,
+    origin_zone::{NameServer, OriginZone},
+    rate_limit::{RateLimits, DEFAULT_IPV4_PREFIX_LEN, DEFAULT_IPV6_PREFIX_LEN, DEFAULT_SLIP},
+    zone_cache::DEFAULT_ZONE_CACHE_SIZE,
+    zone_policy::ZonePolicy,
+    zone_signer::{SigningAlgorithm, DEFAULT_SIGNATURE_LIFETIME},
+    zone_transfer::{ZoneTransfers, DEFAULT_REFRESH_INTERVAL},
+    zone_update::{LabelUpdateKeys, TsigKeyAlgorithm, MAX_CLOCK_SKEW},
+};
+use base64::Engine;
+use hickory_server::proto::rr::{dnssec::tsig::TSigner, domain::Label, LowerName, Name};
+use ipnet::IpNet;
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing_subscriber::EnvFilter;
+
+/// Command-line arguments of the DNS-Nostr server.
+///
+/// Every option, except `--config` and `--pending-zone-events`, overrides the matching value of
+/// the configuration file.
+#[derive(Debug, Default, Clone, clap::Parser)]
+#[command(
+    name = "dns_nostr_server",
+    about = "Serve DNS zones anchored on Bitcoin and Nostr",
+    long_about = None
+)]
+pub struct Cli {
+    /// Path to the TOML configuration file.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Zone served by this server, e.g. "nostr.dns.name.". Can be repeated to serve several
+    /// zones, replacing the configured origins.
+    #[arg(long)]
+    pub origin: Vec<String>,
+
+    /// URL of a Nostr relay the zones are fetched from. Can be repeated, replacing the relays
+    /// of every origin.
+    #[arg(long)]
+    pub relay: Vec<String>,
+
+    /// Address the DNS server listens on, both for UDP and TCP.
+    #[arg(long)]
+    pub listen_addr: Option<SocketAddr>,
+
+    /// Seconds an idle TCP connection is kept open.
+    #[arg(long)]
+    pub tcp_timeout_secs: Option<u64>,
+
+    /// Largest UDP response sent to EDNS clients.
+    #[arg(long)]
+    pub edns_max_payload: Option<u16>,
+
+    /// PEM file with the certificate chain of the DNS-over-TLS and DNS-over-HTTPS listeners.
+    #[arg(long)]
+    pub tls_cert_path: Option<PathBuf>,
+
+    /// PEM file with the private key of the DNS-over-TLS and DNS-over-HTTPS listeners.
+    #[arg(long)]
+    pub tls_key_path: Option<PathBuf>,
+
+    /// Serve DNS-over-TLS on this address, usually on port 853.
+    #[arg(long)]
+    pub dot_listen_addr: Option<SocketAddr>,
+
+    /// Serve DNS-over-HTTPS on this address, usually on port 443.
+    #[arg(long)]
+    pub doh_listen_addr: Option<SocketAddr>,
+
+    /// Only accept DNS-over-HTTPS requests for this host name.
+    #[arg(long)]
+    pub doh_hostname: Option<String>,
+
+    /// Serve the Prometheus metrics on the `/metrics` path of this address, and the sync status
+    /// of the indexer on `/health`.
+    #[arg(long)]
+    pub metrics_listen_addr: Option<SocketAddr>,
+
+    /// URL of the Bitcoin Core RPC server.
+    #[arg(long)]
+    pub bitcoin_rpc_url: Option<String>,
+
+    /// User of the Bitcoin Core RPC server.
+    #[arg(long)]
+    pub bitcoin_rpc_user: Option<String>,
+
+    /// Password of the Bitcoin Core RPC server.
+    #[arg(long)]
+    pub bitcoin_rpc_password: Option<String>,
+
+    /// Cookie file used to authenticate on the Bitcoin Core RPC server.
+    #[arg(long)]
+    pub bitcoin_rpc_cookie_file: Option<PathBuf>,
+
+    /// Path to the SQLite database of indexed Name-Tokens.
+    #[arg(long)]
+    pub database_path: Option<PathBuf>,
+
+    /// Filter of the logs, e.g. "info" or "warn,lib::nostr_events_repository=debug".
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Format of the logs.
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Print the zone events queued for the wallet of this Nostr public key to sign and publish,
+    /// one JSON object per line, and exit instead of serving.
+    #[arg(long, value_name = "PUBKEY")]
+    pub pending_zone_events: Option<nostr_sdk::PublicKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Path to the SQLite database of indexed Name-Tokens.
+    pub database_path: PathBuf,
+
+    pub dns: DnsConfig,
+
+    /// Certificate and key of the encrypted listeners.
+    pub tls: Option<TlsConfig>,
+
+    /// DNS-over-TLS listener, disabled when not configured.
+    pub dns_over_tls: Option<DnsOverTlsConfig>,
+
+    /// DNS-over-HTTPS listener, disabled when not configured.
+    pub dns_over_https: Option<DnsOverHttpsConfig>,
+
+    /// HTTP listener of the Prometheus metrics, disabled when not configured.
+    pub metrics: Option<MetricsConfig>,
+
+    /// Zones served by this server. They all share the same indexed Name-Tokens.
+    pub origins: Vec<OriginConfig>,
+
+    pub bitcoin_rpc: BitcoinRpcConfig,
+
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Address the DNS server listens on, both for UDP and TCP.
+    pub listen_addr: SocketAddr,
+
+    /// Seconds an idle TCP connection is kept open.
+    pub tcp_timeout_secs: u64,
+
+    /// Largest UDP response sent to EDNS clients. Larger responses are truncated, so the clients
+    /// retry over TCP. Defaults to 1232 bytes, which avoids IP fragmentation.
+    pub edns_max_payload: u16,
+
+    /// Parsed zones cached per origin. Zero disables the cache.
+    pub zone_cache_size: usize,
+
+    /// Labels without a Name-Token remembered per origin until a block is indexed or rolled
+    /// back, so their queries skip the database. Zero disables the cache.
+    pub negative_cache_size: usize,
+
+    /// Seconds a persisted zone event is still served after the relays last confirmed it, while
+    /// the relays are unreachable. Zero disables serving stale zones.
+    pub max_stale_secs: u64,
+
+    /// Limit the rate of the queries and responses of each client, unlimited when not
+    /// configured.
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Queries accepted per second from each source prefix, the others being dropped, or
+    /// refused over TCP. Zero disables the limit.
+    pub queries_per_second: u32,
+
+    /// Identical UDP responses sent per second to each source prefix, as in
+    /// response-rate-limiting (RRL). Zero disables the limit.
+    pub responses_per_second: u32,
+
+    /// One in `slip` responses over the limit is sent truncated, so legitimate clients retry
+    /// over TCP. Zero drops them all.
+    pub slip: u32,
+
+    /// Length of the IPv4 prefixes the clients are grouped by.
+    pub ipv4_prefix_len: u8,
+
+    /// Length of the IPv6 prefixes the clients are grouped by.
+    pub ipv6_prefix_len: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain.
+    pub cert_path: PathBuf,
+
+    /// PEM file with the private key.
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsOverTlsConfig {
+    pub listen_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter of the logs, in the `RUST_LOG` syntax, e.g. "info" or
+    /// "warn,lib::nostr_events_repository=debug".
+    pub level: String,
+
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address of the listener. The metrics are served on the `/metrics` path, and the sync
+    /// status of the indexer on `/health`.
+    pub listen_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsOverHttpsConfig {
+    /// Address of the listener. Queries are served on the `/dns-query` path.
+    pub listen_addr: SocketAddr,
+
+    /// Only accept requests for this host name, any host name is accepted when unset.
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OriginConfig {
+    /// Zone served for the Name-Token labels, e.g. "nostr.dns.name.".
+    pub name: String,
+
+    /// URLs of the Nostr relays the zones of this origin are fetched from.
+    #[serde(default = "default_relays")]
+    pub relays: Vec<String>,
+
+    /// Number of relays that must answer a fetch before the newest event is served. A majority
+    /// of the relays when unset.
+    #[serde(default)]
+    pub relay_quorum: Option<usize>,
+
+    /// Kind of the addressable events the zones are published with, their `d` tag being the
+    /// Name-Token label.
+    #[serde(default = "default_zone_event_kind")]
+    pub zone_event_kind: u16,
+
+    /// Serve the last text note of the owner when no zone event is found, as zones were
+    /// published before the dedicated event kind.
+    #[serde(default)]
+    pub text_note_fallback: bool,
+
+    #[serde(default)]
+    pub label_policy: LabelPolicy,
+
+    #[serde(default)]
+    pub zone_policy: ZonePolicy,
+
+    /// Name servers of the origin, served as its NS records.
+    #[serde(default)]
+    pub name_servers: Vec<NameServerConfig>,
+
+    /// SOA served for the origin. Its serial is the height of the next block to index.
+    #[serde(default)]
+    pub soa: SoaConfig,
+
+    /// Sign the origin and the zones of its labels with a key of the operator.
+    #[serde(default)]
+    pub dnssec: Option<DnssecConfig>,
+
+    /// Accept RFC 2136 updates of the zones of the labels, refused when not configured.
+    #[serde(default)]
+    pub updates: Option<UpdatesConfig>,
+
+    /// Serve AXFR and IXFR of the whole origin zone to secondaries, refused when not configured.
+    #[serde(default)]
+    pub transfers: Option<TransfersConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdatesConfig {
+    /// Keys handed to the operator by the owners of some labels. Updates signed with SIG(0) by
+    /// a KEY record of the zone are accepted for every label.
+    pub labels: Vec<LabelUpdatesConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabelUpdatesConfig {
+    pub label: String,
+
+    /// TSIG keys the updates of the label can be signed with.
+    #[serde(default)]
+    pub tsig_keys: Vec<TsigKeyConfig>,
+
+    /// Nostr secret key of the Name-Token, as nsec or hex, delegated by the owner so the updated
+    /// zones are published right away instead of being queued for its wallet.
+    #[serde(default)]
+    pub nostr_secret_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransfersConfig {
+    /// Networks of the secondaries allowed to transfer the zone, e.g. "192.0.2.0/24".
+    pub allow_from: Vec<IpNet>,
+
+    /// TSIG keys allowing the transfers signed with them from any address.
+    pub tsig_keys: Vec<TsigKeyConfig>,
+
+    /// Addresses of the secondaries sent a NOTIFY when the zone changes.
+    pub notify: Vec<SocketAddr>,
+
+    /// Seconds between two checks of the Name-Tokens and zones for changes, besides the checks
+    /// of every indexed block.
+    pub refresh_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TsigKeyConfig {
+    /// Name of the key, e.g. "alice-key.".
+    pub name: String,
+
+    pub algorithm: TsigKeyAlgorithm,
+
+    /// Base64 secret of the key, as printed by `tsig-keygen`.
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnssecConfig {
+    /// PKCS#8 DER file with the private key signing the zones.
+    pub key_path: PathBuf,
+
+    pub algorithm: SigningAlgorithm,
+
+    /// Validity of the signatures, which are renewed when half of it has elapsed.
+    #[serde(default = "default_signature_lifetime_secs")]
+    pub signature_lifetime_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NameServerConfig {
+    /// Host name of the name server, e.g. "ns1.nostr.dns.name.".
+    pub name: String,
+
+    /// Addresses of the name server, served as glue when it is inside the origin.
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SoaConfig {
+    /// Mailbox of the operator as a domain name, "hostmaster.<origin>" when unset.
+    pub rname: Option<String>,
+
+    /// TTL of the SOA, NS and glue records.
+    pub ttl: u32,
+
+    pub refresh: i32,
+
+    pub retry: i32,
+
+    pub expire: i32,
+
+    /// TTL of the negative answers.
+    pub minimum: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BitcoinRpcConfig {
+    pub url: String,
+
+    pub user: Option<String>,
+
+    pub password: Option<String>,
+
+    /// Cookie file written by Bitcoin Core. Takes precedence over `user` and `password`.
+    pub cookie_file: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Read(PathBuf, std::io::Error),
+
+    /// The configuration file is not valid TOML or has unknown fields.
+    Parse(PathBuf, toml::de::Error),
+
+    /// The configured origin is not a valid domain name.
+    InvalidOrigin(String),
+
+    /// The same origin is configured more than once.
+    DuplicateOrigin(String),
+
+    /// No origin is configured.
+    NoOrigins,
+
+    /// The relay quorum of the origin is zero or larger than its number of relays.
+    InvalidRelayQuorum(String),
+
+    /// The zone event kind of the origin is not an addressable kind.
+    InvalidZoneEventKind(String),
+
+    /// A name server or the SOA mailbox of the origin is not a valid domain name.
+    InvalidDomainName(String),
+
+    /// An encrypted listener is configured without a TLS certificate and key.
+    MissingTlsConfig,
+
+    /// A label or a key of the updates of the origin is not valid.
+    InvalidUpdateKey(String),
+
+    /// A key of the transfers of the origin is not valid.
+    InvalidTransferKey(String),
+
+    /// A prefix length of the rate limits is longer than the addresses.
+    InvalidRateLimitPrefix,
+
+    /// The log level is not a valid filter.
+    InvalidLogLevel(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse {}: {}", path.display(), e),
+            ConfigError::InvalidOrigin(origin) => write!(f, "invalid origin: {}", origin),
+            ConfigError::DuplicateOrigin(origin) => write!(f, "duplicate origin: {}", origin),
+            ConfigError::NoOrigins => write!(f, "no origin configured"),
+            ConfigError::InvalidRelayQuorum(origin) => {
+                write!(f, "invalid relay quorum for origin: {}", origin)
+            }
+            ConfigError::InvalidDomainName(name) => write!(f, "invalid domain name: {}", name),
+            ConfigError::InvalidZoneEventKind(origin) => write!(
+                f,
+                "zone event kind of origin {} is not addressable (30000-39999)",
+                origin
+            ),
+            ConfigError::MissingTlsConfig => write!(
+                f,
+                "DNS-over-TLS and DNS-over-HTTPS require a TLS certificate and key"
+            ),
+            ConfigError::InvalidUpdateKey(label) => {
+                write!(f, "invalid update key for label: {}", label)
+            }
+            ConfigError::InvalidTransferKey(name) => {
+                write!(f, "invalid transfer key: {}", name)
+            }
+            ConfigError::InvalidRateLimitPrefix => write!(
+                f,
+                "rate limit prefix lengths must be at most 32 for IPv4 and 128 for IPv6"
+            ),
+            ConfigError::InvalidLogLevel(e) => write!(f, "invalid log level: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            database_path: "./data/name-tokens.sqlite".into(),
+            dns: DnsConfig::default(),
+            tls: None,
+            dns_over_tls: None,
+            dns_over_https: None,
+            metrics: None,
+            origins: vec![OriginConfig::default()],
+            bitcoin_rpc: BitcoinRpcConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: DEFAULT_LOG_LEVEL.into(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0:1053".parse().unwrap(),
+            tcp_timeout_secs: 5,
+            edns_max_payload: 1232,
+            zone_cache_size: DEFAULT_ZONE_CACHE_SIZE,
+            negative_cache_size: DEFAULT_NEGATIVE_CACHE_SIZE,
+            max_stale_secs: 86400,
+            rate_limit: None,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            queries_per_second: 100,
+            responses_per_second: 10,
+            slip: DEFAULT_SLIP,
+            ipv4_prefix_len: DEFAULT_IPV4_PREFIX_LEN,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:9153".parse().unwrap(),
+        }
+    }
+}
+
+impl Default for DnsOverTlsConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0:853".parse().unwrap(),
+        }
+    }
+}
+
+impl Default for DnsOverHttpsConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0:443".parse().unwrap(),
+            hostname: None,
+        }
+    }
+}
+
+impl Default for OriginConfig {
+    fn default() -> Self {
+        Self {
+            name: "nostr.dns.name.".into(),
+            relays: default_relays(),
+            relay_quorum: None,
+            zone_event_kind: default_zone_event_kind(),
+            text_note_fallback: false,
+            label_policy: LabelPolicy::default(),
+            zone_policy: ZonePolicy::default(),
+            name_servers: vec![],
+            soa: SoaConfig::default(),
+            dnssec: None,
+            updates: None,
+            transfers: None,
+        }
+    }
+}
+
+impl Default for SoaConfig {
+    fn default() -> Self {
+        Self {
+            rname: None,
+            ttl: 3600,
+            refresh: 3600,
+            retry: 600,
+            expire: 604800,
+            minimum: 300,
+        }
+    }
+}
+
+fn default_relays() -> Vec<String> {
+    vec!["ws://localhost:8080".into()]
+}
+
+fn default_zone_event_kind() -> u16 {
+    DEFAULT_ZONE_EVENT_KIND
+}
+
+fn default_signature_lifetime_secs() -> u64 {
+    DEFAULT_SIGNATURE_LIFETIME.as_secs()
+}
+
+impl Default for TransfersConfig {
+    fn default() -> Self {
+        Self {
+            allow_from: vec![],
+            tsig_keys: vec![],
+            notify: vec![],
+            refresh_secs: DEFAULT_REFRESH_INTERVAL.as_secs(),
+        }
+    }
+}
+
+impl Default for BitcoinRpcConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://0.0.0.0:18443".into(),
+            user: Some("rpcuser".into()),
+            password: Some("rpcpassword".into()),
+            cookie_file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration file given in the command line, if any, and apply the
+    /// command-line overrides on top of it.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            None => Config::default(),
+            Some(path) => Config::from_file(path)?,
+        };
+        config.apply_overrides(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.into(), e))
+    }
+
+    fn apply_overrides(&mut self, cli: &Cli) {
+        if !cli.origin.is_empty() {
+            self.origins = cli
+                .origin
+                .iter()
+                .map(|name| OriginConfig {
+                    name: name.clone(),
+                    ..OriginConfig::default()
+                })
+                .collect();
+        }
+        if !cli.relay.is_empty() {
+            for origin in self.origins.iter_mut() {
+                origin.relays = cli.relay.clone();
+            }
+        }
+        if let Some(listen_addr) = cli.listen_addr {
+            self.dns.listen_addr = listen_addr;
+        }
+        if let Some(tcp_timeout_secs) = cli.tcp_timeout_secs {
+            self.dns.tcp_timeout_secs = tcp_timeout_secs;
+        }
+        if let Some(edns_max_payload) = cli.edns_max_payload {
+            self.dns.edns_max_payload = edns_max_payload;
+        }
+        match (&mut self.tls, &cli.tls_cert_path, &cli.tls_key_path) {
+            (Some(tls), cert_path, key_path) => {
+                if let Some(cert_path) = cert_path {
+                    tls.cert_path = cert_path.clone();
+                }
+                if let Some(key_path) = key_path {
+                    tls.key_path = key_path.clone();
+                }
+            }
+            (None, Some(cert_path), Some(key_path)) => {
+                self.tls = Some(TlsConfig {
+                    cert_path: cert_path.clone(),
+                    key_path: key_path.clone(),
+                });
+            }
+            (None, _, _) => {}
+        }
+        if let Some(listen_addr) = cli.dot_listen_addr {
+            self.dns_over_tls = Some(DnsOverTlsConfig { listen_addr });
+        }
+        if let Some(listen_addr) = cli.doh_listen_addr {
+            self.dns_over_https
+                .get_or_insert_with(Default::default)
+                .listen_addr = listen_addr;
+        }
+        if let Some(hostname) = &cli.doh_hostname {
+            self.dns_over_https
+                .get_or_insert_with(Default::default)
+                .hostname = Some(hostname.clone());
+        }
+        if let Some(listen_addr) = cli.metrics_listen_addr {
+            self.metrics = Some(MetricsConfig { listen_addr });
+        }
+        if let Some(url) = &cli.bitcoin_rpc_url {
+            self.bitcoin_rpc.url = url.clone();
+        }
+        if cli.bitcoin_rpc_user.is_some() || cli.bitcoin_rpc_password.is_some() {
+            // Credentials given in the command line win over a configured cookie file.
+            self.bitcoin_rpc.cookie_file = None;
+        }
+        if let Some(user) = &cli.bitcoin_rpc_user {
+            self.bitcoin_rpc.user = Some(user.clone());
+        }
+        if let Some(password) = &cli.bitcoin_rpc_password {
+            self.bitcoin_rpc.password = Some(password.clone());
+        }
+        if let Some(cookie_file) = &cli.bitcoin_rpc_cookie_file {
+            self.bitcoin_rpc.cookie_file = Some(cookie_file.clone());
+        }
+        if let Some(database_path) = &cli.database_path {
+            self.database_path = database_path.clone();
+        }
+        if let Some(log_level) = &cli.log_level {
+            self.log.level = log_level.clone();
+        }
+        if let Some(log_format) = cli.log_format {
+            self.log.format = log_format;
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let has_encrypted_listener = self.dns_over_tls.is_some() || self.dns_over_https.is_some();
+        if has_encrypted_listener && self.tls.is_none() {
+            return Err(ConfigError::MissingTlsConfig);
+        }
+        if self.origins.is_empty() {
+            return Err(ConfigError::NoOrigins);
+        }
+        self.log.filter()?;
+        if let Some(rate_limit) = &self.dns.rate_limit {
+            if rate_limit.ipv4_prefix_len > 32 || rate_limit.ipv6_prefix_len > 128 {
+                return Err(ConfigError::InvalidRateLimitPrefix);
+            }
+        }
+        let mut origin_names = HashSet::new();
+        for origin in &self.origins {
+            if !origin_names.insert(origin.name()?) {
+                return Err(ConfigError::DuplicateOrigin(origin.name.clone()));
+            }
+            if origin
+                .relay_quorum
+                .is_some_and(|quorum| quorum == 0 || quorum > origin.relays.len())
+            {
+                return Err(ConfigError::InvalidRelayQuorum(origin.name.clone()));
+            }
+            if !nostr_sdk::Kind::from(origin.zone_event_kind).is_addressable() {
+                return Err(ConfigError::InvalidZoneEventKind(origin.name.clone()));
+            }
+            origin.origin_zone()?;
+            if let Some(updates) = &origin.updates {
+                updates.label_keys()?;
+            }
+            if let Some(transfers) = &origin.transfers {
+                transfers.tsig_signers()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LogConfig {
+    /// Filter of the logs at the configured level.
+    pub fn filter(&self) -> Result<EnvFilter, ConfigError> {
+        log_filter(&self.level).map_err(ConfigError::InvalidLogLevel)
+    }
+}
+
+impl OriginConfig {
+    pub fn name(&self) -> Result<LowerName, ConfigError> {
+        let mut name = Name::from_str(&self.name)
+            .map_err(|_| ConfigError::InvalidOrigin(self.name.clone()))?;
+        name.set_fqdn(true);
+        Ok(LowerName::new(&name))
+    }
+
+    /// SOA, name servers and glue records served for the origin itself.
+    pub fn origin_zone(&self) -> Result<OriginZone, ConfigError> {
+        let mut origin_zone = OriginZone::new(Name::from(&self.name()?));
+        for name_server in &self.name_servers {
+            origin_zone.name_servers.push(NameServer {
+                name: fqdn(&name_server.name)?,
+                addresses: name_server.addresses.clone(),
+            });
+        }
+        if let Some(rname) = &self.soa.rname {
+            origin_zone.soa.rname = fqdn(rname)?;
+        }
+        origin_zone.soa.ttl = self.soa.ttl;
+        origin_zone.soa.refresh = self.soa.refresh;
+        origin_zone.soa.retry = self.soa.retry;
+        origin_zone.soa.expire = self.soa.expire;
+        origin_zone.soa.minimum = self.soa.minimum;
+        Ok(origin_zone)
+    }
+}
+
+fn fqdn(name: &str) -> Result<Name, ConfigError> {
+    let mut fqdn =
+        Name::from_str(name).map_err(|_| ConfigError::InvalidDomainName(name.to_string()))?;
+    fqdn.set_fqdn(true);
+    Ok(fqdn)
+}
+
+impl UpdatesConfig {
+    /// Keys of the updates of each configured label.
+    pub fn label_keys(&self) -> Result<Vec<(Label, LabelUpdateKeys)>, ConfigError> {
+        self.labels
+            .iter()
+            .map(|label_updates| {
+                let invalid_update_key =
+                    || ConfigError::InvalidUpdateKey(label_updates.label.clone());
+                let label =
+                    Label::from_utf8(&label_updates.label).map_err(|_| invalid_update_key())?;
+                let tsig_signers = label_updates
+                    .tsig_keys
+                    .iter()
+                    .map(|tsig_key| tsig_key.tsig_signer().ok_or_else(invalid_update_key))
+                    .collect::<Result<_, _>>()?;
+                let nostr_keys = label_updates
+                    .nostr_secret_key
+                    .as_deref()
+                    .map(nostr_sdk::Keys::parse)
+                    .transpose()
+                    .map_err(|_| invalid_update_key())?;
+                let label_keys = LabelUpdateKeys {
+                    tsig_signers,
+                    nostr_keys,
+                };
+                Ok((label, label_keys))
+            })
+            .collect()
+    }
+}
+
+impl RateLimitConfig {
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits::new()
+            .with_prefix_lens(self.ipv4_prefix_len, self.ipv6_prefix_len)
+            .with_query_rate(self.queries_per_second)
+            .with_response_rate(self.responses_per_second)
+            .with_slip(self.slip)
+    }
+}
+
+impl TransfersConfig {
+    /// Signers of the TSIG keys the transfers can be signed with.
+    pub fn tsig_signers(&self) -> Result<Vec<TSigner>, ConfigError> {
+        self.tsig_keys
+            .iter()
+            .map(|tsig_key| {
+                tsig_key
+                    .tsig_signer()
+                    .ok_or_else(|| ConfigError::InvalidTransferKey(tsig_key.name.clone()))
+            })
+            .collect()
+    }
+
+    /// Transfers allowed to the configured networks and TSIG keys.
+    pub fn zone_transfers(&self) -> Result<ZoneTransfers, ConfigError> {
+        let tsig_key_names = self
+            .tsig_signers()?
+            .iter()
+            .map(|tsig_signer| tsig_signer.signer_name().clone())
+            .collect();
+        Ok(ZoneTransfers::new()
+            .with_allowed_networks(self.allow_from.clone())
+            .with_tsig_key_names(tsig_key_names)
+            .with_notify_addrs(self.notify.clone()))
+    }
+}
+
+impl TsigKeyConfig {
+    /// Signer of the key, `None` when its name or secret is not valid.
+    pub fn tsig_signer(&self) -> Option<TSigner> {
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(&self.secret)
+            .ok()?;
+        let name = fqdn(&self.name).ok()?;
+        TSigner::new(secret, self.algorithm.into(), name, MAX_CLOCK_SKEW).ok()
+    }
+}
+
+impl BitcoinRpcConfig {
+    pub fn auth(&self) -> bitcoincore_rpc::Auth {
+        match (&self.cookie_file, &self.user, &self.password) {
+            (Some(cookie_file), _, _) => bitcoincore_rpc::Auth::CookieFile(cookie_file.clone()),
+            (None, Some(user), password) => {
+                bitcoincore_rpc::Auth::UserPass(user.clone(), password.clone().unwrap_or_default())
+            }
+            (None, None, _) => bitcoincore_rpc::Auth::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            database_path = "/var/lib/dns-nostr/name-tokens.sqlite"
+
+            [[origins]]
+            name = "nostr.example.com"
+            relays = ["wss://relay.example.com", "wss://relay.example.net"]
+
+            [[origins]]
+            name = "names.example.org."
+
+            [origins.label_policy]
+            min_length = 3
+
+            [origins.zone_policy]
+            allow_delegations = true
+
+            [origins.dnssec]
+            key_path = "/var/lib/dns-nostr/names.example.org.pk8"
+            algorithm = "ECDSAP256SHA256"
+
+            [bitcoin_rpc]
+            url = "http://bitcoind:8332"
+            cookie_file = "/var/lib/bitcoind/.cookie"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.database_path,
+            PathBuf::from("/var/lib/dns-nostr/name-tokens.sqlite")
+        );
+        assert_eq!(config.dns, DnsConfig::default());
+        assert_eq!(config.origins.len(), 2);
+        assert_eq!(
+            config.origins[0].name().unwrap(),
+            LowerName::from_str("nostr.example.com.").unwrap()
+        );
+        assert_eq!(
+            config.origins[0].relays,
+            vec!["wss://relay.example.com", "wss://relay.example.net"]
+        );
+        assert_eq!(config.origins[0].label_policy, LabelPolicy::default());
+        assert_eq!(config.origins[1].relays, default_relays());
+        assert_eq!(config.origins[1].label_policy.min_length, Some(3));
+        assert!(!config.origins[0].zone_policy.allow_delegations);
+        assert!(config.origins[1].zone_policy.allow_delegations);
+        assert_eq!(config.origins[0].dnssec, None);
+        assert_eq!(
+            config.origins[1].dnssec,
+            Some(DnssecConfig {
+                key_path: "/var/lib/dns-nostr/names.example.org.pk8".into(),
+                algorithm: SigningAlgorithm::EcdsaP256Sha256,
+                signature_lifetime_secs: DEFAULT_SIGNATURE_LIFETIME.as_secs(),
+            })
+        );
+        assert_eq!(config.bitcoin_rpc.url, "http://bitcoind:8332");
+        assert_eq!(
+            config.bitcoin_rpc.auth(),
+            bitcoincore_rpc::Auth::CookieFile("/var/lib/bitcoind/.cookie".into())
+        );
+    }
+
+    #[test]
+    fn test_example_config() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [[origins]]
+            name = "nostr.example.com."
+            zone = "nostr.example.com."
+            "#,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut config = Config {
+            bitcoin_rpc: BitcoinRpcConfig {
+                cookie_file: Some("/var/lib/bitcoind/.cookie".into()),
+                ..BitcoinRpcConfig::default()
+            },
+            ..Config::default()
+        };
+        config.apply_overrides(&Cli {
+            origin: vec!["nostr.example.com.".into(), "names.example.org.".into()],
+            relay: vec!["wss://relay.example.com".into()],
+            listen_addr: Some("127.0.0.1:53".parse().unwrap()),
+            bitcoin_rpc_user: Some("alice".into()),
+            bitcoin_rpc_password: Some("secret".into()),
+            ..Cli::default()
+        });
+        assert_eq!(config.origins.len(), 2);
+        assert_eq!(config.origins[1].name, "names.example.org.");
+        assert!(config
+            .origins
+            .iter()
+            .all(|origin| origin.relays == vec!["wss://relay.example.com"]));
+        assert_eq!(config.dns.listen_addr, "127.0.0.1:53".parse().unwrap());
+        assert_eq!(
+            config.bitcoin_rpc.auth(),
+            bitcoincore_rpc::Auth::UserPass("alice".into(), "secret".into())
+        );
+    }
+
+    #[test]
+    fn test_encrypted_listeners() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [tls]
+            cert_path = "/etc/dns-nostr/cert.pem"
+            key_path = "/etc/dns-nostr/key.pem"
+
+            [dns_over_tls]
+
+            [dns_over_https]
+            hostname = "dns.example.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.dns_over_tls, Some(DnsOverTlsConfig::default()));
+        assert_eq!(
+            config.dns_over_https,
+            Some(DnsOverHttpsConfig {
+                hostname: Some("dns.example.com".into()),
+                ..DnsOverHttpsConfig::default()
+            })
+        );
+        assert!(config.validate().is_ok());
+
+        config.tls = None;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::MissingTlsConfig)
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_listeners_overrides() {
+        let cli = Cli {
+            tls_cert_path: Some("cert.pem".into()),
+            tls_key_path: Some("key.pem".into()),
+            doh_listen_addr: Some("127.0.0.1:8443".parse().unwrap()),
+            ..Cli::default()
+        };
+        let config = Config::load(&cli).unwrap();
+        assert_eq!(
+            config.tls,
+            Some(TlsConfig {
+                cert_path: "cert.pem".into(),
+                key_path: "key.pem".into(),
+            })
+        );
+        assert_eq!(config.dns_over_tls, None);
+        assert_eq!(
+            config.dns_over_https.map(|doh| doh.listen_addr),
+            Some("127.0.0.1:8443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        let config: Config = toml::from_str("[metrics]").unwrap();
+        assert_eq!(config.metrics, Some(MetricsConfig::default()));
+        let cli = Cli {
+            metrics_listen_addr: Some("0.0.0.0:9100".parse().unwrap()),
+            ..Cli::default()
+        };
+        let config = Config::load(&cli).unwrap();
+        assert_eq!(
+            config.metrics.map(|metrics| metrics.listen_addr),
+            Some("0.0.0.0:9100".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_log() {
+        let config: Config = toml::from_str(
+            "[log]\nlevel = \"warn,lib::dns_request_handler=debug\"\nformat = \"json\"",
+        )
+        .unwrap();
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert!(config.log.filter().is_ok());
+        let cli = Cli {
+            log_level: Some("debug".into()),
+            log_format: Some(LogFormat::Text),
+            ..Cli::default()
+        };
+        let config = Config::load(&cli).unwrap();
+        assert_eq!(config.log.level, "debug");
+        assert_eq!(config.log.format, LogFormat::Text);
+        let cli = Cli {
+            log_level: Some("lib=loud".into()),
+            ..Cli::default()
+        };
+        assert!(matches!(
+            Config::load(&cli),
+            Err(ConfigError::InvalidLogLevel(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_origin() {
+        let cli = Cli {
+            origin: vec!["not a..name".into()],
+            ..Cli::default()
+        };
+        assert!(matches!(
+            Config::load(&cli),
+            Err(ConfigError::InvalidOrigin(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_origin() {
+        let cli = Cli {
+            origin: vec!["nostr.example.com".into(), "NOSTR.example.com.".into()],
+            ..Cli::default()
+        };
+        assert!(matches!(
+            Config::load(&cli),
+            Err(ConfigError::DuplicateOrigin(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_relay_quorum() {
+        let mut config = Config::default();
+        config.origins[0].relays = vec![
+            "wss://relay.example.com".into(),
+            "wss://relay.example.net".into(),
+        ];
+        config.origins[0].relay_quorum = Some(2);
+        assert!(config.validate().is_ok());
+        config.origins[0].relay_quorum = Some(3);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidRelayQuorum(_))
+        ));
+        config.origins[0].relay_quorum = Some(0);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidRelayQuorum(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_zone_event_kind() {
+        let mut config = Config::default();
+        config.origins[0].zone_event_kind = 1;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidZoneEventKind(_))
+        ));
+    }
+
+    #[test]
+    fn test_updates() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [[origins]]
+            name = "nostr.example.com"
+
+            [[origins.updates.labels]]
+            label = "alice"
+            nostr_secret_key = "6b911fd37cdf5c81d4c0adb1ab7fa822ed253ab0ad9aa18d77257c88b29b718e"
+
+            [[origins.updates.labels.tsig_keys]]
+            name = "alice-key"
+            algorithm = "hmac-sha256"
+            secret = "b3duZXIgc2VjcmV0"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let updates = config.origins[0].updates.as_ref().unwrap();
+        let label_keys = updates.label_keys().unwrap();
+        assert_eq!(label_keys[0].0, Label::from_utf8("alice").unwrap());
+        let tsig_signer = &label_keys[0].1.tsig_signers[0];
+        assert_eq!(
+            tsig_signer.signer_name(),
+            &Name::from_str("alice-key.").unwrap()
+        );
+        assert_eq!(tsig_signer.key(), b"owner secret");
+        assert!(label_keys[0].1.nostr_keys.is_some());
+
+        let updates = config.origins[0].updates.as_mut().unwrap();
+        updates.labels[0].tsig_keys[0].secret = "not base64!".into();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidUpdateKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [dns.rate_limit]
+            queries_per_second = 20
+            ipv6_prefix_len = 48
+
+            [[origins]]
+            name = "nostr.example.com"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let rate_limit = config.dns.rate_limit.as_ref().unwrap();
+        assert_eq!(rate_limit.queries_per_second, 20);
+        assert_eq!(rate_limit.responses_per_second, 10);
+        assert_eq!(rate_limit.ipv4_prefix_len, DEFAULT_IPV4_PREFIX_LEN);
+
+        config.dns.rate_limit.as_mut().unwrap().ipv4_prefix_len = 33;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidRateLimitPrefix)
+        ));
+    }
+
+    #[test]
+    fn test_transfers() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [[origins]]
+            name = "nostr.example.com"
+
+            [origins.transfers]
+            allow_from = ["192.0.2.0/24"]
+            notify = ["192.0.2.53:53"]
+
+            [[origins.transfers.tsig_keys]]
+            name = "transfer-key"
+            algorithm = "hmac-sha256"
+            secret = "b3duZXIgc2VjcmV0"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        let transfers = config.origins[0].transfers.as_ref().unwrap();
+        assert_eq!(transfers.refresh_secs, DEFAULT_REFRESH_INTERVAL.as_secs());
+        let zone_transfers = transfers.zone_transfers().unwrap();
+        assert!(zone_transfers.allows("192.0.2.1".parse().unwrap(), None));
+        let tsig_key_name = Name::from_str("transfer-key.").unwrap();
+        assert!(zone_transfers.allows("198.51.100.1".parse().unwrap(), Some(&tsig_key_name)));
+
+        let transfers = config.origins[0].transfers.as_mut().unwrap();
+        transfers.tsig_keys[0].secret = "not base64!".into();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidTransferKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_origin_zone() {
+        let config: Config = toml::from_str(
+            r#"
+            [[origins]]
+            name = "nostr.example.com"
+
+            [[origins.name_servers]]
+            name = "ns1.nostr.example.com"
+            addresses = ["192.0.2.1"]
+
+            [origins.soa]
+            rname = "admin.example.com"
+            minimum = 60
+            "#,
+        )
+        .unwrap();
+        let origin_zone = config.origins[0].origin_zone().unwrap();
+        assert_eq!(
+            origin_zone.name_servers,
+            vec![NameServer {
+                name: "ns1.nostr.example.com.".parse().unwrap(),
+                addresses: vec!["192.0.2.1".parse().unwrap()],
+            }]
+        );
+        assert_eq!(origin_zone.soa.rname, "admin.example.com.".parse().unwrap());
+        assert_eq!(origin_zone.soa.minimum, 60);
+        assert_eq!(origin_zone.soa.ttl, 3600);
+    }
+}
@@ -1,11 +1,22 @@
 use crate::name_token::NameToken;
-use hickory_server::proto::rr::domain::Label;
+use bitcoin::OutPoint;
+use hickory_server::proto::rr::{
+    dnssec::{rdata::DS, Algorithm, DigestType},
+    domain::Label,
+};
 use nostr_sdk::PublicKey;
 
 #[derive(Debug, Clone)]
 pub struct DnsNostrToken {
     pub label: Label,
     pub nostr_pubkey: PublicKey,
+
+    /// Output holding the Name-Token, which changes on every transfer or update.
+    pub outpoint: OutPoint,
+
+    /// DS records of the keys the owner signs its zone with, committed to by the `dnssec`
+    /// section of the inscription. Empty when the zone is signed by the operator, if at all.
+    pub ds_records: Vec<DS>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -13,12 +24,14 @@ pub enum DnsNostrTokenFromNameTokenError {
     InvalidLabel,
     MissingProtocolArgs,
     InvalidPublicKey,
+    InvalidDsRecord,
 }
 
 impl TryFrom<NameToken> for DnsNostrToken {
     type Error = DnsNostrTokenFromNameTokenError;
 
     fn try_from(value: NameToken) -> Result<Self, Self::Error> {
+        let outpoint = value.last_outpoint();
         let label = match Label::from_raw_bytes(&value.label) {
             Err(_) => Err(DnsNostrTokenFromNameTokenError::InvalidLabel),
             Ok(label) => {
@@ -34,20 +47,45 @@ impl TryFrom<NameToken> for DnsNostrToken {
             None => Err(DnsNostrTokenFromNameTokenError::MissingProtocolArgs),
             Some(args) => Ok(args),
         }?;
-        let nostr_pubkey = match protocol_args.get(0) {
+        let nostr_pubkey = match protocol_args.first() {
             None => Err(DnsNostrTokenFromNameTokenError::InvalidPublicKey),
-            Some(arg) => match PublicKey::from_slice(&arg) {
+            Some(arg) => match PublicKey::from_slice(arg) {
                 Err(_) => Err(DnsNostrTokenFromNameTokenError::InvalidPublicKey),
                 Ok(pubkey) => Ok(pubkey),
             },
         }?;
+        let ds_records = value
+            .protocol_args(&b"dnssec".into())
+            .unwrap_or_default()
+            .iter()
+            .map(|arg| parse_ds_record(arg))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(DnsNostrTokenFromNameTokenError::InvalidDsRecord)?;
         Ok(DnsNostrToken {
             label,
             nostr_pubkey,
+            outpoint,
+            ds_records,
         })
     }
 }
 
+/// DS record from its RDATA in wire format, as in RFC 4034 section 5.1.
+fn parse_ds_record(rdata: &[u8]) -> Option<DS> {
+    let [key_tag_high, key_tag_low, algorithm, digest_type, digest @ ..] = rdata else {
+        return None;
+    };
+    if digest.is_empty() {
+        return None;
+    }
+    Some(DS::new(
+        u16::from_be_bytes([*key_tag_high, *key_tag_low]),
+        Algorithm::from_u8(*algorithm),
+        DigestType::from_u8(*digest_type).ok()?,
+        digest.to_vec(),
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use bitcoin::{hashes::Hash, Txid};
@@ -63,9 +101,7 @@ mod tests {
                 label: b"domain".into(),
                 sections: vec![InscriptionSection {
                     protocol: b"dns-nostr".into(),
-                    arguments: vec![
-                        nostr_pubkey.to_bytes().into(),
-                    ],
+                    arguments: vec![nostr_pubkey.to_bytes().into()],
                 }],
             },
             InscriptionMetadata {
@@ -77,5 +113,45 @@ mod tests {
         );
         let dns_nostr_token = DnsNostrToken::try_from(name_token).unwrap();
         assert_eq!(dns_nostr_token.label.to_string(), "domain");
+        assert!(dns_nostr_token.ds_records.is_empty());
+    }
+
+    #[test]
+    fn test_try_from_name_token_with_dnssec() {
+        let nostr_pubkey = PublicKey::from_slice(&[0; 32]).unwrap();
+        let ds_rdata = [[0x30, 0x39, 15, 2].as_slice(), &[0xab; 32]].concat();
+        let name_token = |ds_rdata: &[u8]| {
+            NameToken::create(
+                Inscription {
+                    label: b"domain".into(),
+                    sections: vec![
+                        InscriptionSection {
+                            protocol: b"dns-nostr".into(),
+                            arguments: vec![nostr_pubkey.to_bytes().into()],
+                        },
+                        InscriptionSection {
+                            protocol: b"dnssec".into(),
+                            arguments: vec![ds_rdata.into()],
+                        },
+                    ],
+                },
+                InscriptionMetadata {
+                    blockheight: 0,
+                    blockindex: 0,
+                    vout: 0,
+                    txid: Txid::all_zeros(),
+                },
+            )
+        };
+
+        let dns_nostr_token = DnsNostrToken::try_from(name_token(&ds_rdata)).unwrap();
+        assert_eq!(dns_nostr_token.ds_records.len(), 1);
+        assert_eq!(dns_nostr_token.ds_records[0].key_tag(), 12345);
+        assert_eq!(dns_nostr_token.ds_records[0].digest(), &[0xab; 32]);
+
+        assert_eq!(
+            DnsNostrToken::try_from(name_token(&[0x30])).unwrap_err(),
+            DnsNostrTokenFromNameTokenError::InvalidDsRecord
+        );
     }
 }
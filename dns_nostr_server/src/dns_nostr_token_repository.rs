@@ -1,12 +1,28 @@
 use std::{future::Future, sync::Arc};
 
 use crate::{
-    dns_nostr_token::DnsNostrToken, name_token::Bytes, name_token_repository::NameTokenRepository,
+    dns_nostr_token::DnsNostrToken,
+    name_token::Bytes,
+    name_token_repository::{IndexerError, NameTokenRepository},
 };
 use hickory_server::proto::rr::domain::Label;
+use tokio::sync::watch;
 
 pub trait GetDnsNostrToken: Send + Sync {
-    fn get_token(&self, label: &Label) -> impl Future<Output = Option<DnsNostrToken>> + Send;
+    fn get_token(
+        &self,
+        label: &Label,
+    ) -> impl Future<Output = Result<Option<DnsNostrToken>, IndexerError>> + Send;
+
+    /// DNS-Nostr Token of every indexed label.
+    fn get_tokens(&self) -> impl Future<Output = Result<Vec<DnsNostrToken>, IndexerError>> + Send;
+
+    /// Height of the next block to index, used as the serial of the origin SOA.
+    fn next_block_height(&self) -> u64;
+
+    /// Generation of the indexed Name-Tokens, which changes with every block applied or rolled
+    /// back.
+    fn generation(&self) -> u64;
 }
 
 pub struct DnsNostrTokenRepository {
@@ -20,22 +36,45 @@ impl DnsNostrTokenRepository {
         }
     }
 
-    pub async fn get_token(&self, label: &Label) -> Option<DnsNostrToken> {
+    pub async fn get_token(&self, label: &Label) -> Result<Option<DnsNostrToken>, IndexerError> {
         let label = Bytes::from(label.as_bytes());
-        let name_token = self.name_token_repository.get_name_token(&label).await;
+        let name_token = self.name_token_repository.get_name_token(&label).await?;
         let dns_nostr_token = match name_token {
-            None => return None,
+            None => return Ok(None),
             Some(name_token) => DnsNostrToken::try_from(name_token),
         };
-        match dns_nostr_token {
-            Err(_) => None,
-            Ok(dns_nostr_token) => Some(dns_nostr_token),
-        }
+        Ok(dns_nostr_token.ok())
+    }
+
+    /// DNS-Nostr Token of every indexed label, skipping the Name-Tokens of other protocols.
+    pub async fn get_tokens(&self) -> Result<Vec<DnsNostrToken>, IndexerError> {
+        let name_tokens = self.name_token_repository.get_name_tokens().await?;
+        Ok(name_tokens
+            .into_iter()
+            .filter_map(|name_token| DnsNostrToken::try_from(name_token).ok())
+            .collect())
+    }
+
+    /// Receiver of the generation of the indexed Name-Tokens, notified every time they change.
+    pub fn watch_updates(&self) -> watch::Receiver<u64> {
+        self.name_token_repository.watch_generation()
     }
 }
 
 impl GetDnsNostrToken for DnsNostrTokenRepository {
-    async fn get_token(&self, label: &Label) -> Option<DnsNostrToken> {
+    async fn get_token(&self, label: &Label) -> Result<Option<DnsNostrToken>, IndexerError> {
         self.get_token(label).await
     }
+
+    async fn get_tokens(&self) -> Result<Vec<DnsNostrToken>, IndexerError> {
+        self.get_tokens().await
+    }
+
+    fn next_block_height(&self) -> u64 {
+        self.name_token_repository.next_block_height()
+    }
+
+    fn generation(&self) -> u64 {
+        self.name_token_repository.generation()
+    }
 }
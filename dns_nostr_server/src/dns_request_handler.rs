@@ -0,0 +1,515 @@
+use crate::{
+    metrics::{metrics, RateLimitKind},
+    nostr_authority::scope_request,
+    rate_limit::{RateLimits, ResponseLimit},
+    zone_update::verify_request_tsig,
+};
+use hickory_server::{
+    authority::{MessageRequest, MessageResponse, MessageResponseBuilder},
+    proto::{
+        op::{Header, LowerQuery, OpCode, ResponseCode},
+        rr::{
+            dnssec::{
+                rdata::tsig::{make_tsig_record, TSIG},
+                tsig::TSigner,
+            },
+            Name, Record, RecordType,
+        },
+        serialize::binary::{BinDecodable, BinEncoder},
+    },
+    server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+use std::{
+    cell::Cell,
+    io,
+    net::IpAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info_span, warn, Instrument};
+
+/// Smallest payload every DNS client must accept, see RFC 1035 section 2.3.4.
+const MIN_UDP_PAYLOAD: u16 = 512;
+
+tokio::task_local! {
+    /// Whether an authority failed to resolve the request being handled.
+    static SERVER_FAILURE: Cell<bool>;
+
+    /// Name of the TSIG key that signed the query being handled, once verified.
+    static TSIG_KEY_NAME: Option<Name>;
+}
+
+/// Answer the request being handled with SERVFAIL, e.g. when the relays cannot be reached.
+///
+/// The `Catalog` answers NOERROR to every lookup error other than NXDOMAIN and REFUSED, which
+/// resolvers would cache as an empty answer, so the failure is reported out of band to the
+/// `DnsRequestHandler`. Outside of a request handled by it, this does nothing.
+pub fn report_server_failure() {
+    let _ = SERVER_FAILURE.try_with(|server_failure| server_failure.set(true));
+}
+
+fn is_server_failure() -> bool {
+    SERVER_FAILURE.try_with(Cell::get).unwrap_or(false)
+}
+
+/// Name of the TSIG key that signed the query being handled, e.g. a zone transfer, when one of
+/// the keys of the `DnsRequestHandler` verified it.
+pub fn request_tsig_key_name() -> Option<Name> {
+    TSIG_KEY_NAME.try_with(Clone::clone).ok().flatten()
+}
+
+/// Request handler applying the server-wide response limits on top of another handler,
+/// usually the `Catalog` of the Nostr authorities.
+pub struct DnsRequestHandler<H: RequestHandler> {
+    inner: H,
+    max_udp_payload: u16,
+    tsig_signers: Vec<TSigner>,
+    rate_limits: Option<Arc<RateLimits>>,
+}
+
+impl<H: RequestHandler> DnsRequestHandler<H> {
+    /// Advertise and use at most `max_udp_payload` bytes for UDP responses to EDNS requests.
+    ///
+    /// Larger responses are truncated, so the client retries the query over TCP.
+    pub fn new(inner: H, max_udp_payload: u16) -> Self {
+        Self {
+            inner,
+            max_udp_payload: max_udp_payload.max(MIN_UDP_PAYLOAD),
+            tsig_signers: vec![],
+            rate_limits: None,
+        }
+    }
+
+    /// Verify the queries signed with TSIG by one of `tsig_signers`, e.g. zone transfers, and
+    /// sign the responses to them. Queries signed with other keys are answered with NOTAUTH.
+    pub fn with_tsig_signers(mut self, tsig_signers: Vec<TSigner>) -> Self {
+        self.tsig_signers = tsig_signers;
+        self
+    }
+
+    /// Drop the queries and UDP responses over the `rate_limits` of their source prefix.
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = Some(Arc::new(rate_limits));
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<H: RequestHandler> RequestHandler for DnsRequestHandler<H> {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let span = info_span!(
+            "dns_request",
+            id = request.id(),
+            src = %request.src(),
+            protocol = %request.protocol(),
+            qname = %request.query().name(),
+            qtype = %request.query().query_type(),
+        );
+        self.handle(request, response_handle).instrument(span).await
+    }
+}
+
+impl<H: RequestHandler> DnsRequestHandler<H> {
+    /// Handle `request`, within the span of the request.
+    async fn handle<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let is_udp = matches!(request.protocol(), Protocol::Udp);
+        if let Some(rate_limits) = &self.rate_limits {
+            if !rate_limits.allows_query(request.src().ip()) {
+                metrics().observe_rate_limited(RateLimitKind::Query);
+                debug!("query over the rate limit");
+                // Over TCP the source is not spoofed, it is told to back off.
+                if !is_udp {
+                    return send_error(request, response_handle, ResponseCode::Refused).await;
+                }
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::Refused);
+                return header.into();
+            }
+        }
+        let is_signed_query = request.op_code() == OpCode::Query
+            && request
+                .sig0()
+                .iter()
+                .any(|signature| signature.record_type() == RecordType::TSIG);
+        let request_tsig = if is_signed_query {
+            let Some(request_tsig) = verify_request_tsig(request, &self.tsig_signers) else {
+                warn!("refused query with an invalid TSIG");
+                return send_error(request, response_handle, ResponseCode::NotAuth).await;
+            };
+            Some(request_tsig)
+        } else {
+            None
+        };
+        let tsig_key_name = request_tsig
+            .as_ref()
+            .map(|(tsig_signer, _)| tsig_signer.signer_name().clone());
+        let response_handle = EdnsPayloadResponseHandle {
+            inner: response_handle,
+            max_udp_payload: self.max_udp_payload,
+            query_type: request.query().query_type(),
+            is_udp,
+            request_tsig,
+            response_limit: self
+                .rate_limits
+                .clone()
+                .filter(|_| is_udp)
+                .map(|rate_limits| (rate_limits, request.src().ip(), request.query().clone())),
+        };
+        let handle_request = self.inner.handle_request(request, response_handle);
+        let handle_request = TSIG_KEY_NAME.scope(tsig_key_name, scope_request(handle_request));
+        SERVER_FAILURE.scope(Cell::new(false), handle_request).await
+    }
+}
+
+/// Answer `request` with `response_code` and no records.
+async fn send_error<R: ResponseHandler>(
+    request: &Request,
+    mut response_handle: R,
+    response_code: ResponseCode,
+) -> ResponseInfo {
+    let response = MessageResponseBuilder::from_message_request(request)
+        .error_msg(request.header(), response_code);
+    metrics().observe_query(response_code, request.query().query_type());
+    debug!(rcode = %response_code, "sent response");
+    response_handle
+        .send_response(response)
+        .await
+        .unwrap_or_else(|e| {
+            error!(error = %e, "failed to send response");
+            let mut header = *request.header();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        })
+}
+
+/// Response handler clamping the EDNS payload size of the responses, turning them into
+/// SERVFAIL when an authority reported a failure, limiting the rate of the UDP responses, and
+/// signing them when the request was signed with TSIG.
+///
+/// The payload size of the response EDNS is the one used to truncate UDP responses, so clamping
+/// it keeps the responses under the size that is safe to send without IP fragmentation.
+/// Clients without EDNS get at most 512 bytes over UDP, the larger responses being sent
+/// truncated so they retry over TCP.
+#[derive(Clone)]
+struct EdnsPayloadResponseHandle<R: ResponseHandler> {
+    inner: R,
+    max_udp_payload: u16,
+    query_type: RecordType,
+
+    /// Whether the request came over UDP, whose responses to clients without EDNS are limited
+    /// to 512 bytes.
+    is_udp: bool,
+
+    /// Key that signed the request, and the MAC of the request.
+    request_tsig: Option<(TSigner, Vec<u8>)>,
+
+    /// Limits of the UDP responses, with the source and the query of the request.
+    response_limit: Option<(Arc<RateLimits>, IpAddr, LowerQuery)>,
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for EdnsPayloadResponseHandle<R> {
+    async fn send_response<'a>(
+        &mut self,
+        mut response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        if let Some(edns) = response.get_edns() {
+            let mut edns = edns.clone();
+            edns.set_max_payload(clamp_max_payload(edns.max_payload(), self.max_udp_payload));
+            response.set_edns(edns);
+        }
+        if is_server_failure() {
+            let header = response.header_mut();
+            header.set_response_code(ResponseCode::ServFail);
+            header.set_authoritative(false);
+        }
+        if let Some((rate_limits, src, query)) = &self.response_limit {
+            let response_code = response.header().response_code();
+            match rate_limits.limit_response(*src, response_code, query) {
+                ResponseLimit::Send => {}
+                ResponseLimit::Drop => {
+                    metrics().observe_rate_limited(RateLimitKind::Response);
+                    debug!(rcode = %response_code, "dropped response over the rate limit");
+                    return Ok(ResponseInfo::from(*response.header()));
+                }
+                ResponseLimit::Slip => {
+                    metrics().observe_rate_limited(RateLimitKind::Response);
+                    debug!(rcode = %response_code, "truncated response over the rate limit");
+                    return self.send_truncated_response(response).await;
+                }
+            }
+        }
+        let response_code = response.header().response_code();
+        metrics().observe_query(response_code, self.query_type);
+        debug!(rcode = %response_code, "sent response");
+        if self.is_udp && response.get_edns().is_none() {
+            // hickory allows 4096 bytes to clients without EDNS, RFC 1035 only 512.
+            let mut response_bytes = Vec::new();
+            response
+                .destructive_emit(&mut BinEncoder::new(&mut response_bytes))
+                .map_err(io::Error::other)?;
+            let message = MessageRequest::from_bytes(&response_bytes).map_err(io::Error::other)?;
+            if response_bytes.len() > usize::from(MIN_UDP_PAYLOAD) {
+                let mut header = *message.header();
+                header.set_truncated(true);
+                let response =
+                    MessageResponseBuilder::from_message_request(&message).build_no_records(header);
+                return self.inner.send_response(response).await;
+            }
+            return self
+                .send_final_response(rebuild_response(&message, None, None))
+                .await;
+        }
+        self.send_final_response(response).await
+    }
+}
+
+impl<R: ResponseHandler> EdnsPayloadResponseHandle<R> {
+    /// Send `response`, signed when the request was signed.
+    async fn send_final_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        if let Some((tsig_signer, request_mac)) = self.request_tsig.clone() {
+            return self
+                .send_signed_response(response, &tsig_signer, &request_mac)
+                .await;
+        }
+        self.inner.send_response(response).await
+    }
+
+    /// Send `response` truncated and without records, for the client to retry over TCP.
+    async fn send_truncated_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut response_bytes = Vec::new();
+        response
+            .destructive_emit(&mut BinEncoder::new(&mut response_bytes))
+            .map_err(io::Error::other)?;
+        let message = MessageRequest::from_bytes(&response_bytes).map_err(io::Error::other)?;
+        let mut header = *message.header();
+        header.set_truncated(true);
+        let response =
+            MessageResponseBuilder::from_message_request(&message).build_no_records(header);
+        self.inner.send_response(response).await
+    }
+
+    /// Send `response` signed with TSIG by `tsig_signer`, as in RFC 8945 section 5.3.
+    ///
+    /// The responses of hickory cannot carry a TSIG record, so the response is encoded and built
+    /// again with the TSIG record appended after its EDNS record.
+    async fn send_signed_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+        tsig_signer: &TSigner,
+        request_mac: &[u8],
+    ) -> io::Result<ResponseInfo> {
+        let mut response_bytes = Vec::new();
+        response
+            .destructive_emit(&mut BinEncoder::new(&mut response_bytes))
+            .map_err(io::Error::other)?;
+        let message = MessageRequest::from_bytes(&response_bytes).map_err(io::Error::other)?;
+        let edns = message.edns().map(Record::from);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let pre_tsig = TSIG::new(
+            tsig_signer.algorithm().clone(),
+            now,
+            tsig_signer.fudge(),
+            vec![],
+            message.id(),
+            0,
+            vec![],
+        );
+        let mut tbs = Vec::new();
+        let mut encoder = BinEncoder::new(&mut tbs);
+        encoder
+            .emit_u16(request_mac.len() as u16)
+            .and_then(|_| encoder.emit_vec(request_mac))
+            .and_then(|_| {
+                rebuild_response(&message, edns.as_ref(), None)
+                    .destructive_emit(&mut encoder)
+                    .map(|_| ())
+            })
+            .and_then(|_| pre_tsig.emit_tsig_for_mac(&mut encoder, tsig_signer.signer_name()))
+            .map_err(io::Error::other)?;
+        let mac = tsig_signer.sign(&tbs).map_err(io::Error::other)?;
+        let tsig = make_tsig_record(tsig_signer.signer_name().clone(), pre_tsig.set_mac(mac));
+        let response = rebuild_response(&message, edns.as_ref(), Some(&tsig));
+        self.inner.send_response(response).await
+    }
+}
+
+/// Response holding the records of the decoded `message`, followed by its `edns` and `tsig`
+/// records.
+fn rebuild_response<'a>(
+    message: &'a MessageRequest,
+    edns: Option<&'a Record>,
+    tsig: Option<&'a Record>,
+) -> MessageResponse<
+    'a,
+    'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+> {
+    MessageResponseBuilder::from_message_request(message).build(
+        *message.header(),
+        message.answers(),
+        message.name_servers(),
+        [],
+        message.additionals().iter().chain(edns).chain(tsig),
+    )
+}
+
+/// Payload size to use for a client advertising `requested_max_payload`.
+fn clamp_max_payload(requested_max_payload: u16, max_udp_payload: u16) -> u16 {
+    requested_max_payload.clamp(MIN_UDP_PAYLOAD, max_udp_payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::{
+        op::{Message, Query},
+        rr::{rdata::TXT, RData},
+    };
+
+    #[test]
+    fn test_clamp_max_payload() {
+        assert_eq!(clamp_max_payload(4096, 1232), 1232);
+        assert_eq!(clamp_max_payload(1232, 1232), 1232);
+        assert_eq!(clamp_max_payload(1024, 1232), 1024);
+        assert_eq!(clamp_max_payload(256, 1232), 512);
+    }
+
+    /// Response handler keeping the bytes of the last response sent.
+    #[derive(Clone, Default)]
+    struct CapturingResponseHandle {
+        response_bytes: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ResponseHandler for CapturingResponseHandle {
+        async fn send_response<'a>(
+            &mut self,
+            response: MessageResponse<
+                '_,
+                'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+            >,
+        ) -> io::Result<ResponseInfo> {
+            let mut response_bytes = self.response_bytes.lock().unwrap();
+            response_bytes.clear();
+            response
+                .destructive_emit(&mut BinEncoder::new(&mut response_bytes))
+                .map_err(io::Error::other)
+        }
+    }
+
+    /// Response to `request` with the `records`, as sent by an [`EdnsPayloadResponseHandle`].
+    async fn send_response(request: &MessageRequest, is_udp: bool, records: &[Record]) -> Message {
+        let capturing_handle = CapturingResponseHandle::default();
+        let mut response_handle = EdnsPayloadResponseHandle {
+            inner: capturing_handle.clone(),
+            max_udp_payload: 1232,
+            query_type: RecordType::TXT,
+            is_udp,
+            request_tsig: None,
+            response_limit: None,
+        };
+        let header = Header::response_from_request(request.header());
+        let response = MessageResponseBuilder::from_message_request(request).build(
+            header,
+            records.iter(),
+            [],
+            [],
+            [],
+        );
+        response_handle.send_response(response).await.unwrap();
+        let response_bytes = capturing_handle.response_bytes.lock().unwrap().clone();
+        Message::from_vec(&response_bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_udp_response_without_edns() {
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            "alice.nostr.dns.name.".parse().unwrap(),
+            RecordType::TXT,
+        ));
+        let query_bytes = query.to_vec().unwrap();
+        let request = MessageRequest::from_bytes(&query_bytes).unwrap();
+        let txt = RData::TXT(TXT::new(vec!["x".repeat(100)]));
+        let records =
+            vec![Record::from_rdata("alice.nostr.dns.name.".parse().unwrap(), 300, txt); 20];
+        let response = send_response(&request, true, &records).await;
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        let response = send_response(&request, true, &records[..2]).await;
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 2);
+        let response = send_response(&request, false, &records).await;
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_report_server_failure() {
+        report_server_failure();
+        assert!(!is_server_failure());
+
+        let server_failure = SERVER_FAILURE
+            .scope(Cell::new(false), async {
+                assert!(!is_server_failure());
+                report_server_failure();
+                is_server_failure()
+            })
+            .await;
+        assert!(server_failure);
+    }
+}
@@ -0,0 +1,60 @@
+use hickory_server::proto::rr::domain::Label;
+
+/// Restrictions an operator puts on the Name-Token labels served under an origin.
+///
+/// Labels rejected by the policy are answered as if no Name-Token existed for them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LabelPolicy {
+    /// Minimum length in bytes of a served label, as sent on the wire, so in punycode for
+    /// internationalized labels.
+    pub min_length: Option<usize>,
+
+    /// Maximum length in bytes of a served label, as sent on the wire.
+    pub max_length: Option<usize>,
+
+    /// Labels that are never served, e.g. the ones kept by the operator for its own hosts.
+    pub reserved_labels: Vec<String>,
+}
+
+impl LabelPolicy {
+    pub fn allows(&self, label: &Label) -> bool {
+        let label_length = label.as_bytes().len();
+        if self.min_length.is_some_and(|min| label_length < min) {
+            return false;
+        }
+        if self.max_length.is_some_and(|max| label_length > max) {
+            return false;
+        }
+        !self
+            .reserved_labels
+            .iter()
+            .any(|reserved| reserved.as_bytes().eq_ignore_ascii_case(label.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_allows_any_label() {
+        let policy = LabelPolicy::default();
+        assert!(policy.allows(&Label::from_utf8("a").unwrap()));
+        assert!(policy.allows(&Label::from_utf8("www").unwrap()));
+    }
+
+    #[test]
+    fn test_allows() {
+        let policy = LabelPolicy {
+            min_length: Some(3),
+            max_length: Some(8),
+            reserved_labels: vec!["www".into()],
+        };
+        assert!(policy.allows(&Label::from_utf8("alice").unwrap()));
+        assert!(!policy.allows(&Label::from_utf8("al").unwrap()));
+        assert!(!policy.allows(&Label::from_utf8("alice-and-bob").unwrap()));
+        assert!(!policy.allows(&Label::from_utf8("www").unwrap()));
+        assert!(!policy.allows(&Label::from_utf8("WWW").unwrap()));
+    }
+}
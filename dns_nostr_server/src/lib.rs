@@ -1,6 +1,22 @@
+pub mod config;
 pub mod dns_nostr_token;
 pub mod dns_nostr_token_repository;
+pub mod dns_request_handler;
+pub mod label_policy;
+pub mod logging;
+pub mod metrics;
 pub mod name_token;
 pub mod name_token_repository;
+pub mod negative_cache;
 pub mod nostr_authority;
 pub mod nostr_events_repository;
+pub mod origin_zone;
+pub mod rate_limit;
+pub mod zone_cache;
+pub mod zone_decoder;
+pub mod zone_events_database;
+pub mod zone_lookup;
+pub mod zone_policy;
+pub mod zone_signer;
+pub mod zone_transfer;
+pub mod zone_update;
@@ -0,0 +1,42 @@
+use tracing_subscriber::EnvFilter;
+
+/// Default filter of the logs, e.g. `info` or `warn,lib::nostr_events_repository=debug`.
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Format the logs are written in to the standard error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines, with the fields of the enclosing spans.
+    #[default]
+    Text,
+
+    /// One JSON object per line, with the fields of the current span and of its parents.
+    Json,
+}
+
+/// Filter of the logs parsed from `level`, in the `RUST_LOG` syntax.
+pub fn log_filter(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(level).map_err(|e| e.to_string())
+}
+
+/// Write the logs and spans of the server to the standard error, filtered by `filter`.
+///
+/// Every event carries the fields of its spans, the DNS request, block sync or relay fetch it
+/// happened in, so a query can be followed end to end.
+pub fn init_logging(filter: EnvFilter, format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match format {
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("failed to initialize logging: {}", e);
+    }
+}
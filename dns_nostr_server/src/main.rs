@@ -1,33 +1,234 @@
+use clap::Parser;
 use hickory_server::{
     authority::{Authority, Catalog},
+    proto::{rr::Name, rustls::tls_server},
     ServerFuture,
 };
 use lib::{
+    config::{Cli, Config},
     dns_nostr_token_repository::DnsNostrTokenRepository,
-    name_token_repository::NameTokenRepository, nostr_authority::NostrAuthority,
+    dns_request_handler::DnsRequestHandler,
+    label_policy::LabelPolicy,
+    logging::init_logging,
+    metrics::serve_metrics,
+    name_token_repository::NameTokenRepository,
+    nostr_authority::NostrAuthority,
     nostr_events_repository::NostrEventsRepository,
+    zone_events_database::ZoneEventsDatabase,
+    zone_signer::ZoneSigner,
+    zone_update::ZoneUpdates,
 };
-use std::sync::Arc;
-use tokio::net::UdpSocket;
+use nostr_sdk::JsonUtil;
+use std::{sync::Arc, time::Duration};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() {
-    let name_token_repository = Arc::new(NameTokenRepository::create().await);
-    let dns_nostr_token_repository = DnsNostrTokenRepository::new(name_token_repository.clone());
+    let cli = Cli::parse();
+    let config = Config::load(&cli).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+    init_logging(config.log.filter().unwrap(), config.log.format);
 
-    let nostr_events_repository = NostrEventsRepository::new("ws://localhost:8080".to_string());
+    let zone_events_database = ZoneEventsDatabase::create(&config.database_path)
+        .await
+        .unwrap_or_else(|e| {
+            error!(error = %e, "failed to open the zone events database");
+            std::process::exit(1);
+        });
+    if let Some(pubkey) = &cli.pending_zone_events {
+        print_pending_zone_events(&zone_events_database, pubkey).await;
+        return;
+    }
+
+    let name_token_repository = NameTokenRepository::create(
+        config.bitcoin_rpc.url.clone(),
+        config.bitcoin_rpc.auth(),
+        &config.database_path,
+    )
+    .await
+    .unwrap_or_else(|e| {
+        error!(error = %e, "failed to open the Name-Tokens database");
+        std::process::exit(1);
+    });
+    let name_token_repository = Arc::new(name_token_repository);
+
+    if let Some(metrics) = &config.metrics {
+        tokio::spawn(serve_metrics(
+            TcpListener::bind(metrics.listen_addr).await.unwrap(),
+            name_token_repository.watch_sync_status(),
+        ));
+    }
+
+    let max_staleness = Duration::from_secs(config.dns.max_stale_secs);
 
     let mut handler = Catalog::new();
-    let nostr_authority = NostrAuthority::new(
-        "nostr.dns.name.".parse().unwrap(),
-        dns_nostr_token_repository,
-        nostr_events_repository,
-    );
-    handler.upsert(
-        nostr_authority.origin().clone(),
-        Box::new(Arc::new(nostr_authority)),
-    );
+    let mut tsig_signers = Vec::new();
+    for origin in &config.origins {
+        let mut nostr_events_repository = NostrEventsRepository::new(origin.relays.clone())
+            .with_zone_event_kind(origin.zone_event_kind.into())
+            .with_text_note_fallback(origin.text_note_fallback);
+        if let Some(relay_quorum) = origin.relay_quorum {
+            nostr_events_repository = nostr_events_repository.with_quorum(relay_quorum);
+        }
+        if !max_staleness.is_zero() {
+            nostr_events_repository = nostr_events_repository
+                .with_zone_events_database(zone_events_database.clone(), max_staleness);
+        }
+        tokio::spawn(follow_dns_nostr_tokens(
+            DnsNostrTokenRepository::new(name_token_repository.clone()),
+            nostr_events_repository.clone(),
+            origin.label_policy.clone(),
+        ));
+        let mut nostr_authority = NostrAuthority::new(
+            origin.name().unwrap(),
+            DnsNostrTokenRepository::new(name_token_repository.clone()),
+            nostr_events_repository,
+        )
+        .with_label_policy(origin.label_policy.clone())
+        .with_zone_policy(origin.zone_policy.clone())
+        .with_zone_cache_size(config.dns.zone_cache_size)
+        .with_negative_cache_size(config.dns.negative_cache_size)
+        .with_origin_zone(origin.origin_zone().unwrap());
+        if let Some(dnssec) = &origin.dnssec {
+            let origin_name = Name::from(origin.name().unwrap());
+            let zone_signer =
+                ZoneSigner::from_key_file(origin_name.clone(), dnssec.algorithm, &dnssec.key_path)
+                    .expect("Failed to load DNSSEC key")
+                    .with_signature_lifetime(Duration::from_secs(dnssec.signature_lifetime_secs));
+            let ds = zone_signer.ds().expect("Failed to compute DS record");
+            info!(
+                origin = %origin_name,
+                "DS record to publish: {} IN DS {}",
+                origin_name,
+                ds
+            );
+            nostr_authority = nostr_authority.with_zone_signer(zone_signer);
+        }
+        if let Some(updates) = &origin.updates {
+            let mut zone_updates = ZoneUpdates::new(zone_events_database.clone());
+            for (label, label_keys) in updates.label_keys().unwrap() {
+                zone_updates = zone_updates.with_label_keys(&label, label_keys);
+            }
+            nostr_authority = nostr_authority.with_zone_updates(zone_updates);
+        }
+        let mut refresh_interval = None;
+        if let Some(transfers) = &origin.transfers {
+            tsig_signers.extend(transfers.tsig_signers().unwrap());
+            nostr_authority =
+                nostr_authority.with_zone_transfers(transfers.zone_transfers().unwrap());
+            refresh_interval = Some(Duration::from_secs(transfers.refresh_secs));
+        }
+        let nostr_authority = Arc::new(nostr_authority);
+        if let Some(refresh_interval) = refresh_interval {
+            tokio::spawn(refresh_transfer_zone(
+                nostr_authority.clone(),
+                DnsNostrTokenRepository::new(name_token_repository.clone()),
+                refresh_interval,
+            ));
+        }
+        handler.upsert(nostr_authority.origin().clone(), Box::new(nostr_authority));
+    }
+    let mut handler = DnsRequestHandler::new(handler, config.dns.edns_max_payload)
+        .with_tsig_signers(tsig_signers);
+    if let Some(rate_limit) = &config.dns.rate_limit {
+        handler = handler.with_rate_limits(rate_limit.rate_limits());
+    }
     let mut server = ServerFuture::new(handler);
-    server.register_socket(UdpSocket::bind("0.0.0.0:1053").await.unwrap());
+    let tcp_timeout = Duration::from_secs(config.dns.tcp_timeout_secs);
+    server.register_socket(UdpSocket::bind(config.dns.listen_addr).await.unwrap());
+    server.register_listener(
+        TcpListener::bind(config.dns.listen_addr).await.unwrap(),
+        tcp_timeout,
+    );
+    if let Some(tls) = &config.tls {
+        let certificate_and_key = (
+            tls_server::read_cert(&tls.cert_path).expect("Failed to read TLS certificate"),
+            tls_server::read_key(&tls.key_path).expect("Failed to read TLS private key"),
+        );
+        if let Some(dns_over_tls) = &config.dns_over_tls {
+            server
+                .register_tls_listener(
+                    TcpListener::bind(dns_over_tls.listen_addr).await.unwrap(),
+                    tcp_timeout,
+                    certificate_and_key.clone(),
+                )
+                .expect("Failed to register DNS-over-TLS listener");
+        }
+        if let Some(dns_over_https) = &config.dns_over_https {
+            server
+                .register_https_listener(
+                    TcpListener::bind(dns_over_https.listen_addr).await.unwrap(),
+                    tcp_timeout,
+                    certificate_and_key.clone(),
+                    dns_over_https.hostname.clone(),
+                )
+                .expect("Failed to register DNS-over-HTTPS listener");
+        }
+    }
     server.block_until_done().await.unwrap();
 }
+
+/// Print the zone events queued for the wallet of `pubkey`, oldest first, for it to sign and
+/// publish them.
+async fn print_pending_zone_events(
+    zone_events_database: &ZoneEventsDatabase,
+    pubkey: &nostr_sdk::PublicKey,
+) {
+    let pending_zone_events = zone_events_database
+        .get_unsigned_events(pubkey)
+        .await
+        .unwrap_or_else(|e| {
+            error!(error = %e, "failed to read the pending zone events");
+            std::process::exit(1);
+        });
+    for pending_zone_event in pending_zone_events {
+        println!("{}", pending_zone_event.as_json());
+    }
+}
+
+/// Keep the subscriptions of `nostr_events_repository` on the zones of the served Name-Tokens,
+/// following them as blocks are indexed.
+async fn follow_dns_nostr_tokens(
+    dns_nostr_token_repository: DnsNostrTokenRepository,
+    nostr_events_repository: NostrEventsRepository,
+    label_policy: LabelPolicy,
+) {
+    let mut updates = dns_nostr_token_repository.watch_updates();
+    loop {
+        match dns_nostr_token_repository.get_tokens().await {
+            Ok(dns_nostr_tokens) => {
+                let dns_nostr_tokens: Vec<_> = dns_nostr_tokens
+                    .into_iter()
+                    .filter(|dns_nostr_token| label_policy.allows(&dns_nostr_token.label))
+                    .collect();
+                nostr_events_repository.follow(&dns_nostr_tokens).await;
+            }
+            // The subscriptions are kept until the next block is indexed.
+            Err(e) => warn!(error = %e, "failed to follow the zones of the Name-Tokens"),
+        }
+        if updates.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Keep the zone transferred to the secondaries of `nostr_authority` up to date, assembling it
+/// again as blocks are indexed and every `refresh_interval`, for the changes of the zones.
+async fn refresh_transfer_zone(
+    nostr_authority: Arc<NostrAuthority<DnsNostrTokenRepository>>,
+    dns_nostr_token_repository: DnsNostrTokenRepository,
+    refresh_interval: Duration,
+) {
+    let mut updates = dns_nostr_token_repository.watch_updates();
+    loop {
+        nostr_authority.refresh_transfer_zone().await;
+        let changed = tokio::time::timeout(refresh_interval, updates.changed()).await;
+        if let Ok(Err(_)) = changed {
+            // The indexer stopped, only the zones of the relays can still change.
+            tokio::time::sleep(refresh_interval).await;
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use crate::name_token_repository::SyncStatus;
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet, EncodeLabelValue},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tracing::{debug, error, warn};
+
+/// Longest request head accepted by the metrics endpoint.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Time a client of the metrics endpoint has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metrics of the resolver and the indexer, exposed in the Prometheus text format.
+pub struct Metrics {
+    /// Responses sent, by response code and queried type.
+    pub queries: Family<QueryLabels, Counter>,
+
+    /// Queries and responses dropped by the rate limits.
+    pub rate_limited: Family<RateLimitLabels, Counter>,
+
+    /// Time spent resolving the zones of the labels, by stage.
+    pub lookup_duration: Family<StageLabels, Histogram, fn() -> Histogram>,
+
+    /// Failed fetches and publications, by relay.
+    pub relay_errors: Family<RelayLabels, Counter>,
+
+    /// Lookups of the zone and negative caches, by outcome.
+    pub cache_lookups: Family<CacheLabels, Counter>,
+
+    /// Height of the next block to index.
+    pub next_block_height: Gauge,
+
+    /// Height of the tip of the chain, as last seen by the indexer.
+    pub chain_height: Gauge,
+
+    /// Blocks indexed since the start of the server.
+    pub blocks_processed: Counter,
+
+    /// Valid Name-Tokens indexed.
+    pub name_tokens: Gauge,
+
+    registry: Registry,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct QueryLabels {
+    pub rcode: String,
+    pub qtype: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RateLimitLabels {
+    pub kind: RateLimitKind,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum RateLimitKind {
+    Query,
+    Response,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StageLabels {
+    pub stage: LookupStage,
+}
+
+/// Stage of the resolution of the zone of a label.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum LookupStage {
+    /// Looking up the Name-Token of the label in the database of the indexer.
+    Sqlite,
+
+    /// Fetching the zone event from the relays, or from the stored events.
+    Relay,
+
+    /// Decoding, building and signing the zone.
+    Parse,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RelayLabels {
+    pub relay: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct CacheLabels {
+    pub cache: CacheKind,
+    pub result: CacheResult,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum CacheKind {
+    Zone,
+    Negative,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum CacheResult {
+    Hit,
+    Miss,
+}
+
+/// Metrics of the server, shared by all its components.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let lookup_duration: Family<StageLabels, Histogram, fn() -> Histogram> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.0001, 2.0, 16)));
+        let metrics = Self {
+            queries: Family::default(),
+            rate_limited: Family::default(),
+            lookup_duration,
+            relay_errors: Family::default(),
+            cache_lookups: Family::default(),
+            next_block_height: Gauge::default(),
+            chain_height: Gauge::default(),
+            blocks_processed: Counter::default(),
+            name_tokens: Gauge::default(),
+            registry: Registry::default(),
+        };
+        let mut registry = Registry::with_prefix("dns_nostr");
+        registry.register(
+            "queries",
+            "DNS responses sent, by response code and queried type",
+            metrics.queries.clone(),
+        );
+        registry.register(
+            "rate_limited",
+            "Queries and responses dropped by the rate limits",
+            metrics.rate_limited.clone(),
+        );
+        registry.register(
+            "lookup_duration_seconds",
+            "Time spent resolving the zones of the labels, by stage",
+            metrics.lookup_duration.clone(),
+        );
+        registry.register(
+            "relay_errors",
+            "Failed fetches and publications, by relay",
+            metrics.relay_errors.clone(),
+        );
+        registry.register(
+            "cache_lookups",
+            "Lookups of the zone and negative caches, by result",
+            metrics.cache_lookups.clone(),
+        );
+        registry.register(
+            "next_block_height",
+            "Height of the next block to index",
+            metrics.next_block_height.clone(),
+        );
+        registry.register(
+            "chain_height",
+            "Height of the tip of the chain, as last seen by the indexer",
+            metrics.chain_height.clone(),
+        );
+        registry.register(
+            "blocks_processed",
+            "Blocks indexed since the start of the server",
+            metrics.blocks_processed.clone(),
+        );
+        registry.register(
+            "name_tokens",
+            "Valid Name-Tokens indexed",
+            metrics.name_tokens.clone(),
+        );
+        Self {
+            registry,
+            ..metrics
+        }
+    }
+
+    /// Count a response of `rcode` to a query of `qtype`.
+    pub fn observe_query(&self, rcode: impl ToString, qtype: impl ToString) {
+        let labels = QueryLabels {
+            rcode: rcode.to_string(),
+            qtype: qtype.to_string(),
+        };
+        self.queries.get_or_create(&labels).inc();
+    }
+
+    pub fn observe_rate_limited(&self, kind: RateLimitKind) {
+        self.rate_limited
+            .get_or_create(&RateLimitLabels { kind })
+            .inc();
+    }
+
+    /// Record the time elapsed since `start` in the `stage` of a lookup.
+    pub fn observe_lookup(&self, stage: LookupStage, start: Instant) {
+        self.lookup_duration
+            .get_or_create(&StageLabels { stage })
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    pub fn observe_relay_error(&self, relay: &str) {
+        let labels = RelayLabels {
+            relay: relay.to_string(),
+        };
+        self.relay_errors.get_or_create(&labels).inc();
+    }
+
+    pub fn observe_cache_lookup(&self, cache: CacheKind, is_hit: bool) {
+        let result = if is_hit {
+            CacheResult::Hit
+        } else {
+            CacheResult::Miss
+        };
+        self.cache_lookups
+            .get_or_create(&CacheLabels { cache, result })
+            .inc();
+    }
+
+    /// Metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
+        if let Err(e) = encode(&mut encoded, &self.registry) {
+            error!(error = %e, "failed to encode metrics");
+        }
+        encoded
+    }
+}
+
+/// Serve the metrics on the `/metrics` path of the HTTP `listener`, and on `/health` the
+/// `sync_status` of the indexer.
+pub async fn serve_metrics(listener: TcpListener, sync_status: watch::Receiver<SyncStatus>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_metrics_request(stream, sync_status.clone()));
+            }
+            Err(e) => warn!(error = %e, "failed to accept metrics connection"),
+        }
+    }
+}
+
+/// Answer the HTTP request of `stream`, closing the connection afterwards.
+async fn serve_metrics_request(mut stream: TcpStream, sync_status: watch::Receiver<SyncStatus>) {
+    let Ok(Some(request_line)) =
+        tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream)).await
+    else {
+        return;
+    };
+    let sync_status = sync_status.borrow().clone();
+    let response = http_response(&request_line, &sync_status);
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!(error = %e, "failed to send metrics");
+    }
+}
+
+/// Status line, without the version, content type and body of an HTTP response.
+struct HttpResponse {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+/// Health of the server reported on `/health`.
+#[derive(serde::Serialize)]
+struct HealthReport<'a> {
+    is_healthy: bool,
+
+    #[serde(flatten)]
+    sync_status: &'a SyncStatus,
+}
+
+/// Response to the HTTP request starting with `request_line`.
+///
+/// `/health` answers 503 while the indexer is behind the chain or failing to sync, so load
+/// balancers stop sending queries the server would answer from stale Name-Tokens.
+fn http_response(request_line: &str, sync_status: &SyncStatus) -> HttpResponse {
+    let mut parts = request_line.split_whitespace();
+    let path = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => target.split('?').next().unwrap_or_default(),
+        _ => "",
+    };
+    match path {
+        "/metrics" => HttpResponse {
+            status: "200 OK",
+            content_type: "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            body: metrics().encode(),
+        },
+        "/health" => {
+            let is_healthy = sync_status.is_healthy();
+            let health_report = HealthReport {
+                is_healthy,
+                sync_status,
+            };
+            HttpResponse {
+                status: if is_healthy {
+                    "200 OK"
+                } else {
+                    "503 Service Unavailable"
+                },
+                content_type: "application/json",
+                body: serde_json::to_string(&health_report).unwrap_or_default(),
+            }
+        }
+        _ => HttpResponse {
+            status: "404 Not Found",
+            content_type: "text/plain",
+            body: String::new(),
+        },
+    }
+}
+
+/// First line of the HTTP request of `stream`, once its whole head is read.
+async fn read_request_line(stream: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 || head.len() + read > MAX_REQUEST_LEN {
+            return None;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    head.lines().next().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_response() {
+        let sync_status = SyncStatus::default();
+        metrics().observe_query("NoError", "A");
+        let response = http_response("GET /metrics HTTP/1.1", &sync_status);
+        assert_eq!(response.status, "200 OK");
+        assert!(response
+            .body
+            .contains("dns_nostr_queries_total{rcode=\"NoError\",qtype=\"A\"}"));
+        assert!(response.body.ends_with("# EOF\n"));
+        let response = http_response("GET /metrics?x=1 HTTP/1.1", &sync_status);
+        assert_eq!(response.status, "200 OK");
+        let response = http_response("GET / HTTP/1.1", &sync_status);
+        assert_eq!(response.status, "404 Not Found");
+        let response = http_response("POST /metrics HTTP/1.1", &sync_status);
+        assert_eq!(response.status, "404 Not Found");
+    }
+
+    #[test]
+    fn test_health() {
+        let mut sync_status = SyncStatus {
+            is_synced: true,
+            next_block_height: 100,
+            chain_height: Some(105),
+            last_synced_at: Some(1_700_000_000),
+            ..SyncStatus::default()
+        };
+        let response = http_response("GET /health HTTP/1.1", &sync_status);
+        assert_eq!(response.status, "200 OK");
+        let health_report: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(health_report["is_healthy"], true);
+        assert_eq!(health_report["next_block_height"], 100);
+        assert_eq!(health_report["chain_height"], 105);
+
+        sync_status.consecutive_failures = 1;
+        sync_status.last_error = Some("Bitcoin Core RPC failed".into());
+        let response = http_response("GET /health HTTP/1.1", &sync_status);
+        assert_eq!(response.status, "503 Service Unavailable");
+        let health_report: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(health_report["is_healthy"], false);
+        assert_eq!(health_report["last_error"], "Bitcoin Core RPC failed");
+    }
+}
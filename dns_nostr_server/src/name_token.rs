@@ -95,7 +95,7 @@ fn parse_inscription(instructions: &mut Instructions) -> Option<(Bytes, Vec<Insc
 /// Parse the inscription header.
 /// If the header is not valid, return `None`.
 /// If the header is valid, return the label and a boolean indicating if there is more to parse.
-fn parse_header(mut instructions: &mut Instructions) -> Option<(Bytes, bool)> {
+fn parse_header(instructions: &mut Instructions) -> Option<(Bytes, bool)> {
     match instructions.next()? {
         Ok(Instruction::PushBytes(push_bytes)) if push_bytes.is_empty() => {}
         _ => return None,
@@ -104,13 +104,13 @@ fn parse_header(mut instructions: &mut Instructions) -> Option<(Bytes, bool)> {
         Ok(Instruction::Op(OP_IF)) => {}
         _ => return None,
     }
-    let (header_section, has_more) = parse_section(&mut instructions)?;
+    let (header_section, has_more) = parse_section(instructions)?;
     let magic_bytes = &header_section.protocol;
     if *magic_bytes != b"name" {
         return None;
     }
-    let label = header_section.arguments.get(0)?;
-    return Some((label.clone(), has_more));
+    let label = header_section.arguments.first()?;
+    Some((label.clone(), has_more))
 }
 
 /// Parse a section from the instructions.
@@ -131,7 +131,7 @@ fn parse_section(instructions: &mut Instructions) -> Option<(InscriptionSection,
                 let has_more = op == OP_NOP;
                 return Some((
                     InscriptionSection {
-                        protocol: protocol,
+                        protocol,
                         arguments,
                     },
                     has_more,
@@ -1,82 +1,315 @@
-use crate::name_token::{Bytes, Inscription, InscriptionMetadata, NameToken};
+use crate::{
+    metrics::metrics,
+    name_token::{Bytes, Inscription, InscriptionMetadata, NameToken},
+};
 use bitcoin::{
     hex::{Case, DisplayHex, FromHex},
-    Block, OutPoint, Transaction, TxIn, TxOut, Txid,
+    Block, BlockHash, OutPoint, Transaction, TxIn, TxOut, Txid,
 };
 use bitcoincore_rpc::RpcApi;
+use rusqlite::OptionalExtension;
 use std::{
     collections::HashMap,
+    fmt::{self, Display},
+    path::Path,
     str::FromStr,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::sync::watch;
+use tracing::{debug, error, info, instrument, warn};
 
 const MIN_CONFIRMATIONS: u64 = 6;
 
+/// Indexed blocks whose hash and undo rows are kept, the deepest reorganization that can be
+/// rolled back.
+const MAX_REORG_DEPTH: u64 = 100;
+
+/// Time between two syncs of the blockchain, once the indexer caught up with it.
+const SYNC_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Wait before retrying a failed sync, doubled after every consecutive failure.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between two attempts to sync, e.g. while Bitcoin Core restarts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Failure of the indexer to read the blockchain or to access its database.
+#[derive(Debug)]
+pub enum IndexerError {
+    /// The Bitcoin Core RPC server could not be reached or failed.
+    Rpc(bitcoincore_rpc::Error),
+
+    /// The SQLite database could not be read or written, or holds invalid rows.
+    Database(rusqlite::Error),
+}
+
+impl Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexerError::Rpc(e) => write!(f, "Bitcoin Core RPC failed: {}", e),
+            IndexerError::Database(e) => write!(f, "Name-Tokens database failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for IndexerError {}
+
+impl From<bitcoincore_rpc::Error> for IndexerError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        IndexerError::Rpc(e)
+    }
+}
+
+impl From<rusqlite::Error> for IndexerError {
+    fn from(e: rusqlite::Error) -> Self {
+        IndexerError::Database(e)
+    }
+}
+
+/// Progress of the indexer, reported by the health endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct SyncStatus {
+    /// Whether the last sync reached the tip of the chain, less the required confirmations.
+    pub is_synced: bool,
+
+    /// Height of the next block to index.
+    pub next_block_height: u64,
+
+    /// Height of the tip of the chain, as last seen by the indexer.
+    pub chain_height: Option<u64>,
+
+    /// Unix time of the end of the last successful sync.
+    pub last_synced_at: Option<u64>,
+
+    /// Failed syncs since the last successful one.
+    pub consecutive_failures: u32,
+
+    /// Error of the last failed sync, cleared by a successful one.
+    pub last_error: Option<String>,
+}
+
+impl SyncStatus {
+    /// Whether the indexer caught up with the chain and its last sync succeeded.
+    pub fn is_healthy(&self) -> bool {
+        self.is_synced && self.consecutive_failures == 0
+    }
+}
+
 #[derive(Clone)]
 pub struct NameTokenRepository {
     database: NameTokensDatabase,
+    bitcoin_rpc_url: String,
+    bitcoin_rpc_auth: bitcoincore_rpc::Auth,
+    next_block_height: Arc<AtomicU64>,
+    generation: Arc<watch::Sender<u64>>,
+    sync_status: Arc<watch::Sender<SyncStatus>>,
 }
 
 impl NameTokenRepository {
-    pub async fn create() -> Self {
-        let database = NameTokensDatabase::create().await;
-        let this = Self { database };
-        let this_clone = this.clone();
-        tokio::spawn(async move {
-            this_clone.watch_blockchain().await;
-        });
-        this
+    /// Repository of the Name-Tokens indexed in the database at `database_path`, indexing the
+    /// blockchain of the Bitcoin Core RPC server in the background.
+    pub async fn create(
+        bitcoin_rpc_url: String,
+        bitcoin_rpc_auth: bitcoincore_rpc::Auth,
+        database_path: &Path,
+    ) -> Result<Self, IndexerError> {
+        let database = NameTokensDatabase::create(database_path).await?;
+        let next_block_height = database.get_next_block_height().await?;
+        let this = Self {
+            database,
+            bitcoin_rpc_url,
+            bitcoin_rpc_auth,
+            next_block_height: Arc::new(AtomicU64::new(next_block_height)),
+            generation: Arc::new(watch::Sender::new(0)),
+            sync_status: Arc::new(watch::Sender::new(SyncStatus {
+                next_block_height,
+                ..SyncStatus::default()
+            })),
+        };
+        tokio::spawn(this.clone().supervise_watcher());
+        Ok(this)
     }
 
-    fn bitcoin_client(&self) -> bitcoincore_rpc::Client {
-        bitcoincore_rpc::Client::new(
-            "http://0.0.0.0:18443",
-            bitcoincore_rpc::Auth::UserPass("rpcuser".into(), "rpcpassword".into()),
-        )
-        .unwrap()
+    fn bitcoin_client(&self) -> Result<bitcoincore_rpc::Client, IndexerError> {
+        let bitcoin_client =
+            bitcoincore_rpc::Client::new(&self.bitcoin_rpc_url, self.bitcoin_rpc_auth.clone())?;
+        Ok(bitcoin_client)
     }
 
+    /// Keep the blockchain watcher running, restarting it when it panics.
+    async fn supervise_watcher(self) {
+        loop {
+            let this = self.clone();
+            let watcher = tokio::spawn(async move { this.watch_blockchain().await });
+            if let Err(e) = watcher.await {
+                error!(error = %e, "blockchain watcher stopped, restarting");
+                self.sync_status.send_modify(|sync_status| {
+                    sync_status.consecutive_failures += 1;
+                    sync_status.last_error = Some(e.to_string());
+                });
+            }
+            let consecutive_failures = self.sync_status.borrow().consecutive_failures;
+            tokio::time::sleep(retry_delay(consecutive_failures)).await;
+        }
+    }
+
+    /// Sync the blockchain every `SYNC_INTERVAL`, retrying failed syncs with an exponential
+    /// backoff.
     async fn watch_blockchain(&self) {
         loop {
-            self.sync_blocks().await;
-            tokio::time::sleep(Duration::from_secs(
-                600, // 10 minutes
-            ))
-            .await;
+            let generation = self.generation();
+            let synced = self.sync_blocks().await;
+            self.notify_block_changes(generation);
+            let delay = match synced {
+                Ok(()) => {
+                    self.sync_status.send_modify(|sync_status| {
+                        sync_status.last_synced_at = Some(unix_time());
+                        sync_status.consecutive_failures = 0;
+                        sync_status.last_error = None;
+                    });
+                    SYNC_INTERVAL
+                }
+                Err(e) => {
+                    let mut consecutive_failures = 0;
+                    self.sync_status.send_modify(|sync_status| {
+                        consecutive_failures = sync_status.consecutive_failures;
+                        sync_status.consecutive_failures += 1;
+                        sync_status.last_error = Some(e.to_string());
+                    });
+                    let delay = retry_delay(consecutive_failures);
+                    warn!(error = %e, retry_in_secs = delay.as_secs(), "failed to sync blocks");
+                    delay
+                }
+            };
+            tokio::time::sleep(delay).await;
         }
     }
 
     // This function would typically sync the repository state with the current state of the blockchain.
-    async fn sync_blocks(&self) {
-        println!("Syncing blocks...");
+    async fn sync_blocks(&self) -> Result<(), IndexerError> {
+        debug!("syncing blocks");
+        self.rollback_stale_blocks().await?;
         loop {
-            let state_next_blockheight = self.database.get_next_block_height().await;
-            let blockchain_num_blocks = self
-                .bitcoin_client()
-                .get_blockchain_info()
-                .expect("Failed to get blockchain info")
-                .blocks;
-            if state_next_blockheight >= blockchain_num_blocks - MIN_CONFIRMATIONS {
+            let state_next_blockheight = self.database.get_next_block_height().await?;
+            let blockchain_num_blocks = self.bitcoin_client()?.get_blockchain_info()?.blocks;
+            metrics().chain_height.set(blockchain_num_blocks as i64);
+            let is_synced =
+                state_next_blockheight >= blockchain_num_blocks.saturating_sub(MIN_CONFIRMATIONS);
+            self.sync_status.send_modify(|sync_status| {
+                sync_status.is_synced = is_synced;
+                sync_status.next_block_height = state_next_blockheight;
+                sync_status.chain_height = Some(blockchain_num_blocks);
+            });
+            if is_synced {
                 break;
             }
-            self.sync_next_block(state_next_blockheight).await;
+            self.sync_next_block(state_next_blockheight).await?;
         }
+        metrics()
+            .name_tokens
+            .set(self.get_name_tokens().await?.len() as i64);
+        Ok(())
+    }
+
+    /// Record that a block was applied or rolled back, `next_block_height` being the height of
+    /// the next block to index, without waking the watchers of the generation yet.
+    fn record_block_change(&self, next_block_height: u64) {
+        self.next_block_height
+            .store(next_block_height, Ordering::Relaxed);
+        metrics().next_block_height.set(next_block_height as i64);
+        self.generation.send_if_modified(|generation| {
+            *generation += 1;
+            false
+        });
+    }
+
+    /// Wake the watchers of the generation when blocks were applied or rolled back since
+    /// `previous_generation`, once per sync rather than for every block of a long catch-up.
+    fn notify_block_changes(&self, previous_generation: u64) {
+        self.generation
+            .send_if_modified(|generation| *generation != previous_generation);
     }
 
-    async fn sync_next_block(&self, next_blockheight: u64) {
-        let block_hash = self
-            .bitcoin_client()
-            .get_block_hash(next_blockheight)
-            .expect("Failed to get block hash");
-        let block = self
-            .bitcoin_client()
-            .get_block(&block_hash)
-            .expect("Failed to get block");
-        self.sync_block(next_blockheight, &block).await;
+    /// Roll back indexed blocks until the indexed tip is part of the best chain again.
+    ///
+    /// Blocks are undone one at a time, from the tip down to the fork point, so the following
+    /// sync re-indexes the blocks of the new best chain.
+    async fn rollback_stale_blocks(&self) -> Result<(), IndexerError> {
+        self.record_tip_hash().await?;
+        while let Some((blockheight, indexed_block_hash)) = self.database.get_last_block().await? {
+            if self.is_in_best_chain(blockheight, &indexed_block_hash)? {
+                break;
+            }
+            warn!(
+                height = blockheight,
+                hash = %indexed_block_hash,
+                "block no longer in the best chain, rolling back"
+            );
+            self.database.rollback_block(blockheight).await?;
+            self.record_block_change(blockheight);
+            if self.database.get_last_block().await?.is_none() && blockheight > 0 {
+                error!(
+                    max_depth = MAX_REORG_DEPTH,
+                    "reorganization deeper than the blocks kept, reindex from scratch"
+                );
+            }
+        }
+        Ok(())
     }
 
-    async fn sync_block(&self, blockheight: u64, block: &Block) {
+    /// Record the hash of the indexed tip of a database indexed before the hashes of the blocks
+    /// were, taken from the best chain, so the reorganizations of the tip are detected from now.
+    async fn record_tip_hash(&self) -> Result<(), IndexerError> {
+        let next_block_height = self.database.get_next_block_height().await?;
+        let Some(blockheight) = next_block_height.checked_sub(1) else {
+            return Ok(());
+        };
+        if self.database.has_block_hashes().await? {
+            return Ok(());
+        }
+        let block_hash = self.bitcoin_client()?.get_block_hash(blockheight)?;
+        self.database
+            .record_block_hash(blockheight, &block_hash)
+            .await?;
+        info!(height = blockheight, hash = %block_hash, "recorded hash of the indexed tip");
+        Ok(())
+    }
+
+    fn is_in_best_chain(
+        &self,
+        blockheight: u64,
+        block_hash: &BlockHash,
+    ) -> Result<bool, IndexerError> {
+        let bitcoin_client = self.bitcoin_client()?;
+        let blockchain_num_blocks = bitcoin_client.get_blockchain_info()?.blocks;
+        if blockheight > blockchain_num_blocks {
+            return Ok(false);
+        }
+        let best_block_hash = bitcoin_client.get_block_hash(blockheight)?;
+        Ok(best_block_hash == *block_hash)
+    }
+
+    #[instrument(name = "block_sync", skip_all, fields(height = next_blockheight))]
+    async fn sync_next_block(&self, next_blockheight: u64) -> Result<(), IndexerError> {
+        let bitcoin_client = self.bitcoin_client()?;
+        let block_hash = bitcoin_client.get_block_hash(next_blockheight)?;
+        let block = bitcoin_client.get_block(&block_hash)?;
+        if let Some(previous_blockheight) = next_blockheight.checked_sub(1) {
+            let indexed_previous_block_hash =
+                self.database.get_block_hash(previous_blockheight).await?;
+            if indexed_previous_block_hash.is_some_and(|hash| hash != block.header.prev_blockhash) {
+                // The chain was reorganized while syncing, undo the stale blocks first.
+                return self.rollback_stale_blocks().await;
+            }
+        }
+        self.sync_block(next_blockheight, &block).await
+    }
+
+    async fn sync_block(&self, blockheight: u64, block: &Block) -> Result<(), IndexerError> {
         let mut pending_block_updates = HashMap::new();
         for (blockindex, transaction) in block.txdata.iter().enumerate() {
             self.sync_transaction(
@@ -85,17 +318,20 @@ impl NameTokenRepository {
                 blockheight,
                 &mut pending_block_updates,
             )
-            .await;
+            .await?;
         }
         let updates: Vec<NameToken> = pending_block_updates.values().cloned().collect();
         self.database
-            .save_block_updates(blockheight, &updates)
-            .await;
-        println!(
-            "Synced block at height {} with {} updates",
-            blockheight,
-            updates.len()
+            .save_block_updates(blockheight, &block.block_hash(), &updates)
+            .await?;
+        metrics().blocks_processed.inc();
+        self.record_block_change(blockheight + 1);
+        info!(
+            hash = %block.block_hash(),
+            updates = updates.len(),
+            "synced block"
         );
+        Ok(())
     }
 
     async fn sync_transaction(
@@ -103,8 +339,8 @@ impl NameTokenRepository {
         transaction: &Transaction,
         blockindex: usize,
         blockheight: u64,
-        mut pending_block_updates: &mut HashMap<OutPoint, NameToken>,
-    ) {
+        pending_block_updates: &mut HashMap<OutPoint, NameToken>,
+    ) -> Result<(), IndexerError> {
         let num_positional_correlation =
             usize::max(transaction.input.len(), transaction.output.len());
         for positional_correlation in 0..num_positional_correlation {
@@ -120,10 +356,11 @@ impl NameTokenRepository {
                 txin,
                 txout,
                 metadata,
-                &mut pending_block_updates,
+                pending_block_updates,
             )
-            .await;
+            .await?;
         }
+        Ok(())
     }
 
     async fn sync_txin_txout_positional_correlation(
@@ -132,12 +369,12 @@ impl NameTokenRepository {
         txout: Option<&TxOut>,
         metadata: InscriptionMetadata,
         pending_block_updates: &mut HashMap<OutPoint, NameToken>,
-    ) {
+    ) -> Result<(), IndexerError> {
         let input_name_token = match txin {
             None => None,
             Some(txin) => {
-                self.get_name_token_by_outpoint(txin.previous_output, &pending_block_updates)
-                    .await
+                self.get_name_token_by_outpoint(txin.previous_output, pending_block_updates)
+                    .await?
             }
         };
         let output_inscription = match txout {
@@ -155,23 +392,164 @@ impl NameTokenRepository {
                 updated_name_token.clone(),
             );
         }
+        Ok(())
     }
 
     async fn get_name_token_by_outpoint(
         &self,
         outpoint: OutPoint,
         pending_block_updates: &HashMap<OutPoint, NameToken>,
-    ) -> Option<NameToken> {
+    ) -> Result<Option<NameToken>, IndexerError> {
         match pending_block_updates.get(&outpoint) {
-            Some(name_token) => Some(name_token.clone()),
-            None => self.database.get_name_token_by_outpoint(outpoint).await,
+            Some(name_token) => Ok(Some(name_token.clone())),
+            None => Ok(self.database.get_name_token_by_outpoint(outpoint).await?),
         }
     }
 
-    pub async fn get_name_token(&self, label: &Bytes) -> Option<NameToken> {
-        let name_tokens_with_label = self.database.get_name_tokens_by_label(label).await;
+    pub async fn get_name_token(&self, label: &Bytes) -> Result<Option<NameToken>, IndexerError> {
+        let name_tokens_with_label = self.database.get_name_tokens_by_label(label).await?;
         let valid_name_token = NameToken::select_valid_name_token(label, &name_tokens_with_label);
-        valid_name_token.cloned()
+        Ok(valid_name_token.cloned())
+    }
+
+    /// Valid Name-Token of every indexed label.
+    pub async fn get_name_tokens(&self) -> Result<Vec<NameToken>, IndexerError> {
+        let mut name_tokens_by_label: HashMap<Bytes, Vec<NameToken>> = HashMap::new();
+        for name_token in self.database.get_name_tokens().await? {
+            name_tokens_by_label
+                .entry(name_token.label.clone())
+                .or_default()
+                .push(name_token);
+        }
+        Ok(name_tokens_by_label
+            .iter()
+            .filter_map(|(label, name_tokens)| {
+                NameToken::select_valid_name_token(label, name_tokens)
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Height of the next block to index, which grows with every indexed block.
+    pub fn next_block_height(&self) -> u64 {
+        self.next_block_height.load(Ordering::Relaxed)
+    }
+
+    /// Generation of the indexed Name-Tokens, increased by every block applied or rolled back,
+    /// so it changes even when a reorganization is re-indexed up to the same height.
+    pub fn generation(&self) -> u64 {
+        *self.generation.borrow()
+    }
+
+    /// Receiver of the generation of the indexed Name-Tokens, notified after every sync that
+    /// changed them.
+    pub fn watch_generation(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+
+    /// Receiver of the progress of the indexer, notified after every sync and indexed block.
+    pub fn watch_sync_status(&self) -> watch::Receiver<SyncStatus> {
+        self.sync_status.subscribe()
+    }
+}
+
+/// Wait before the next attempt to sync after `consecutive_failures` failed ones.
+fn retry_delay(consecutive_failures: u32) -> Duration {
+    MIN_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(consecutive_failures))
+        .min(MAX_RETRY_DELAY)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Columns selected to rebuild a [`NameToken`] with [`name_token_from_row`].
+const NAME_TOKEN_COLUMNS: &str = "label_hex,
+    first_blockheight,
+    first_blockindex,
+    first_vout,
+    first_txid,
+    last_blockheight,
+    last_blockindex,
+    last_vout,
+    last_txid,
+    inscription_json";
+
+fn name_token_from_row(row: &rusqlite::Row) -> rusqlite::Result<NameToken> {
+    name_token_from_columns(row, 0)
+}
+
+/// Rebuild a [`NameToken`] from the [`NAME_TOKEN_COLUMNS`] starting at column `first_column`.
+fn name_token_from_columns(
+    row: &rusqlite::Row,
+    first_column: usize,
+) -> rusqlite::Result<NameToken> {
+    let label_hex: String = row.get(first_column)?;
+    let first_blockheight: u64 = row.get(first_column + 1)?;
+    let first_blockindex: usize = row.get(first_column + 2)?;
+    let first_vout: u32 = row.get(first_column + 3)?;
+    let first_txid: String = row.get(first_column + 4)?;
+    let last_blockheight: u64 = row.get(first_column + 5)?;
+    let last_blockindex: usize = row.get(first_column + 6)?;
+    let last_vout: u32 = row.get(first_column + 7)?;
+    let last_txid: String = row.get(first_column + 8)?;
+    let inscription_json: String = row.get(first_column + 9)?;
+    Ok(NameToken {
+        first_inscription_metadata: InscriptionMetadata {
+            txid: parse_column(first_column + 4, Txid::from_str(&first_txid))?,
+            vout: first_vout,
+            blockheight: first_blockheight,
+            blockindex: first_blockindex,
+        },
+        last_inscription_metadata: InscriptionMetadata {
+            txid: parse_column(first_column + 8, Txid::from_str(&last_txid))?,
+            vout: last_vout,
+            blockheight: last_blockheight,
+            blockindex: last_blockindex,
+        },
+        label: parse_column(first_column, Bytes::from_hex(&label_hex))?,
+        inscription: Some(parse_column(
+            first_column + 9,
+            serde_json::from_str::<Inscription>(&inscription_json),
+        )?),
+    })
+}
+
+/// Value parsed from the text of the column `column`, failing like an invalid column type.
+fn parse_column<T, E>(column: usize, parsed: Result<T, E>) -> rusqlite::Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    parsed.map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+/// JSON of `inscription`, as stored in the `inscription_json` columns.
+fn inscription_json(inscription: &Option<Inscription>) -> rusqlite::Result<String> {
+    serde_json::to_string(inscription)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// How a block changed a Name-Token, recorded so the block can be undone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameTokenUndoAction {
+    Created,
+    Updated,
+    Revoked,
+}
+
+impl NameTokenUndoAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            NameTokenUndoAction::Created => "created",
+            NameTokenUndoAction::Updated => "updated",
+            NameTokenUndoAction::Revoked => "revoked",
+        }
     }
 }
 
@@ -181,30 +559,40 @@ struct NameTokensDatabase {
 }
 
 impl NameTokensDatabase {
-    pub async fn create() -> Self {
-        let sqlite =
-            rusqlite::Connection::open("./data/name-tokens.sqlite").expect("Failed to open SQLite database");
+    pub async fn create(database_path: &Path) -> rusqlite::Result<Self> {
+        let sqlite = rusqlite::Connection::open(database_path)?;
+        // The zone events are stored in the same database by another connection.
+        sqlite.busy_timeout(Duration::from_secs(5))?;
+        Self::from_connection(sqlite).await
+    }
+
+    async fn from_connection(sqlite: rusqlite::Connection) -> rusqlite::Result<Self> {
         let this = Self {
             connection: Arc::new(Mutex::new(sqlite)),
         };
-        this.create_tables().await;
-        this
+        this.create_tables().await?;
+        Ok(this)
     }
 
-    async fn create_tables(&self) {
-        let mut connection = self.connection.lock().unwrap();
-        let transaction = connection.transaction().unwrap();
-        transaction
-            .execute(
-                "CREATE TABLE IF NOT EXISTS state (
+    /// Connection to the database, still usable after a panic while it was held, e.g. in a
+    /// restarted watcher, as the blocks are written in transactions rolled back when dropped.
+    fn connection(&self) -> MutexGuard<'_, rusqlite::Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn create_tables(&self) -> rusqlite::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "CREATE TABLE IF NOT EXISTS state (
                 next_block_height UNSIGNED INTEGER NOT NULL
             )",
-                [],
-            )
-            .unwrap();
-        transaction
-            .execute(
-                "CREATE TABLE IF NOT EXISTS name_tokens (
+            [],
+        )?;
+        transaction.execute(
+            "CREATE TABLE IF NOT EXISTS name_tokens (
                 label_hex TEXT NOT NULL,
                 first_blockheight UNSIGNED INTEGER NOT NULL,
                 first_blockindex UNSIGNED INTEGER NOT NULL,
@@ -216,202 +604,584 @@ impl NameTokensDatabase {
                 last_txid CHAR(64) NOT NULL,
                 inscription_json TEXT NOT NULL
             )",
-                [],
-            )
-            .unwrap();
-        transaction.commit().expect("Failed to create state table");
+            [],
+        )?;
+        transaction.execute(
+            "CREATE TABLE IF NOT EXISTS blocks (
+                height UNSIGNED INTEGER PRIMARY KEY,
+                hash CHAR(64) NOT NULL
+            )",
+            [],
+        )?;
+        // One row per token touched by a block, holding the token row as it was before the
+        // block. The `previous_*` columns are NULL for tokens created by the block.
+        transaction.execute(
+            "CREATE TABLE IF NOT EXISTS name_token_undos (
+                blockheight UNSIGNED INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                first_blockheight UNSIGNED INTEGER NOT NULL,
+                first_blockindex UNSIGNED INTEGER NOT NULL,
+                first_vout UNSIGNED INTEGER NOT NULL,
+                previous_label_hex TEXT,
+                previous_first_blockheight UNSIGNED INTEGER,
+                previous_first_blockindex UNSIGNED INTEGER,
+                previous_first_vout UNSIGNED INTEGER,
+                previous_first_txid CHAR(64),
+                previous_last_blockheight UNSIGNED INTEGER,
+                previous_last_blockindex UNSIGNED INTEGER,
+                previous_last_vout UNSIGNED INTEGER,
+                previous_last_txid CHAR(64),
+                previous_inscription_json TEXT
+            )",
+            [],
+        )?;
+        transaction.commit()
     }
 
-    pub async fn get_next_block_height(&self) -> u64 {
-        let connection = self.connection.lock().unwrap();
-        let mut statement = connection
-            .prepare("SELECT next_block_height FROM state")
-            .unwrap();
-        let mut rows = statement.query([]).unwrap();
-        rows.next().unwrap().map_or(0, |row| {
-            row.get(0).expect("Failed to get next block height")
-        })
+    pub async fn get_next_block_height(&self) -> rusqlite::Result<u64> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT next_block_height FROM state")?;
+        let mut rows = statement.query([])?;
+        rows.next()?.map_or(Ok(0), |row| row.get(0))
     }
 
-    pub async fn get_name_token_by_outpoint(&self, outpoint: OutPoint) -> Option<NameToken> {
-        let connection = self.connection.lock().unwrap();
-        let mut statement = connection
-            .prepare(
-                "SELECT
-                    label_hex,
-                    first_blockheight,
-                    first_blockindex,
-                    first_vout,
-                    first_txid,
-                    last_blockheight,
-                    last_blockindex,
-                    last_vout,
-                    last_txid,
-                    inscription_json
+    pub async fn get_block_hash(&self, blockheight: u64) -> rusqlite::Result<Option<BlockHash>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT hash FROM blocks WHERE height = ?1")?;
+        let mut rows = statement.query([blockheight])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let hash: String = row.get(0)?;
+        parse_column(0, BlockHash::from_str(&hash)).map(Some)
+    }
+
+    /// Whether the hash of any indexed block is recorded.
+    pub async fn has_block_hashes(&self) -> rusqlite::Result<bool> {
+        let connection = self.connection();
+        connection.query_row("SELECT EXISTS (SELECT 1 FROM blocks)", [], |row| row.get(0))
+    }
+
+    /// Record `block_hash` as the hash of the indexed block at `blockheight`.
+    pub async fn record_block_hash(
+        &self,
+        blockheight: u64,
+        block_hash: &BlockHash,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection();
+        connection.execute(
+            "INSERT OR REPLACE INTO blocks (height, hash) VALUES (?1, ?2)",
+            rusqlite::params![blockheight, block_hash.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the height and hash of the last indexed block, if its hash was recorded.
+    pub async fn get_last_block(&self) -> rusqlite::Result<Option<(u64, BlockHash)>> {
+        let Some(last_blockheight) = self.get_next_block_height().await?.checked_sub(1) else {
+            return Ok(None);
+        };
+        let block_hash = self.get_block_hash(last_blockheight).await?;
+        Ok(block_hash.map(|block_hash| (last_blockheight, block_hash)))
+    }
+
+    pub async fn get_name_token_by_outpoint(
+        &self,
+        outpoint: OutPoint,
+    ) -> rusqlite::Result<Option<NameToken>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {NAME_TOKEN_COLUMNS}
                 FROM name_tokens
-                WHERE last_txid = ?1 AND last_vout = ?2",
-            )
-            .unwrap();
+                WHERE last_txid = ?1 AND last_vout = ?2"
+        ))?;
         let params = rusqlite::params![outpoint.txid.to_string(), outpoint.vout];
-        let mut rows = statement.query(params).unwrap();
-        let first_row = rows.next().unwrap();
-        match first_row {
-            None => None,
-            Some(first_row) => {
-                let label_hex: String = first_row.get(0).unwrap();
-                let first_blockheight: u64 = first_row.get(1).unwrap();
-                let first_blockindex: usize = first_row.get(2).unwrap();
-                let first_vout: u32 = first_row.get(3).unwrap();
-                let first_txid: String = first_row.get(4).unwrap();
-                let last_blockheight: u64 = first_row.get(5).unwrap();
-                let last_blockindex: usize = first_row.get(6).unwrap();
-                let last_vout: u32 = first_row.get(7).unwrap();
-                let last_txid: String = first_row.get(8).unwrap();
-                let inscription_json: String = first_row.get(9).unwrap();
-                Some(NameToken {
-                    first_inscription_metadata: InscriptionMetadata {
-                        txid: Txid::from_str(&first_txid).expect("Invalid Txid"),
-                        vout: first_vout,
-                        blockheight: first_blockheight,
-                        blockindex: first_blockindex,
-                    },
-                    last_inscription_metadata: InscriptionMetadata {
-                        txid: bitcoin::Txid::from_str(&last_txid).expect("Invalid Txid"),
-                        vout: last_vout,
-                        blockheight: last_blockheight,
-                        blockindex: last_blockindex,
-                    },
-                    label: Bytes::from_hex(&label_hex).expect("Invalid label hex"),
-                    inscription: Some(
-                        serde_json::from_str::<Inscription>(&inscription_json)
-                            .expect("Failed to parse inscription JSON"),
-                    ),
-                })
-            }
-        }
+        let mut rows = statement.query(params)?;
+        let first_row = rows.next()?;
+        first_row.map(name_token_from_row).transpose()
     }
 
     pub async fn save_block_updates<'a>(
         &self,
         blockheight: u64,
+        block_hash: &BlockHash,
         updated_name_tokens: impl IntoIterator<Item = &'a NameToken>,
-    ) {
+    ) -> rusqlite::Result<()> {
         let next_block_height = blockheight + 1;
-        let mut connection = self.connection.lock().unwrap();
-        let transaction = connection.transaction().unwrap();
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
         // remove old block height
-        transaction
-            .execute("DELETE FROM state", [])
-            .expect("Failed to delete old state");
+        transaction.execute("DELETE FROM state", [])?;
         // insert new block height
-        transaction
-            .execute(
-                "INSERT INTO state (next_block_height) VALUES (?1)",
-                &[&next_block_height],
-            )
-            .expect("Failed to insert new block height");
+        transaction.execute(
+            "INSERT INTO state (next_block_height) VALUES (?1)",
+            [&next_block_height],
+        )?;
+        transaction.execute(
+            "INSERT OR REPLACE INTO blocks (height, hash) VALUES (?1, ?2)",
+            rusqlite::params![blockheight, block_hash.to_string()],
+        )?;
         for updated_token in updated_name_tokens.into_iter() {
-            transaction
-                .execute(
-                    "DELETE FROM name_tokens
-                    WHERE first_blockheight = ?1 
-                        AND first_blockindex = ?2
-                        AND first_vout = ?3",
+            let previous_token = transaction
+                .query_row(
+                    &format!(
+                        "SELECT {NAME_TOKEN_COLUMNS}
+                        FROM name_tokens
+                        WHERE first_blockheight = ?1
+                            AND first_blockindex = ?2
+                            AND first_vout = ?3"
+                    ),
                     rusqlite::params![
                         &updated_token.first_inscription_metadata.blockheight,
                         &updated_token.first_inscription_metadata.blockindex,
                         &updated_token.first_inscription_metadata.vout,
                     ],
+                    name_token_from_row,
                 )
-                .expect("Failed to delete old name token");
+                .optional()?;
+            let action = match (&previous_token, updated_token.is_revoked()) {
+                (_, true) => NameTokenUndoAction::Revoked,
+                (None, false) => NameTokenUndoAction::Created,
+                (Some(_), false) => NameTokenUndoAction::Updated,
+            };
+            Self::insert_name_token_undo(
+                &transaction,
+                blockheight,
+                action,
+                updated_token,
+                previous_token.as_ref(),
+            )?;
+            transaction.execute(
+                "DELETE FROM name_tokens
+                    WHERE first_blockheight = ?1
+                        AND first_blockindex = ?2
+                        AND first_vout = ?3",
+                rusqlite::params![
+                    &updated_token.first_inscription_metadata.blockheight,
+                    &updated_token.first_inscription_metadata.blockindex,
+                    &updated_token.first_inscription_metadata.vout,
+                ],
+            )?;
             if updated_token.is_revoked() {
                 continue; // Just remove revoked name tokens
             }
-            transaction
-                .execute(
-                    "INSERT INTO name_tokens (
-                        label_hex,
+            Self::insert_name_token(&transaction, updated_token)?;
+        }
+        // Blocks deeper than the supported reorganizations are never rolled back.
+        if let Some(pruned_height) = next_block_height.checked_sub(MAX_REORG_DEPTH) {
+            transaction.execute(
+                "DELETE FROM name_token_undos WHERE blockheight < ?1",
+                [pruned_height],
+            )?;
+            transaction.execute("DELETE FROM blocks WHERE height < ?1", [pruned_height])?;
+        }
+        transaction.commit()
+    }
+
+    /// Undo the updates saved for the block at `blockheight`, which must be the last indexed
+    /// block, and make it the next block to be synced.
+    pub async fn rollback_block(&self, blockheight: u64) -> rusqlite::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let undos = {
+            let mut statement = transaction.prepare(
+                "SELECT
                         first_blockheight,
                         first_blockindex,
                         first_vout,
-                        first_txid,
-                        last_blockheight,
-                        last_blockindex,
-                        last_vout,
-                        last_txid,
-                        inscription_json
-                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-                    rusqlite::params![
-                        updated_token.label.to_hex_string(Case::Lower),
-                        updated_token.first_inscription_metadata.blockheight,
-                        updated_token.first_inscription_metadata.blockindex,
-                        updated_token.first_inscription_metadata.vout,
-                        updated_token.first_inscription_metadata.txid.to_string(),
-                        updated_token.last_inscription_metadata.blockheight,
-                        updated_token.last_inscription_metadata.blockindex,
-                        updated_token.last_inscription_metadata.vout,
-                        updated_token.last_inscription_metadata.txid.to_string(),
-                        serde_json::to_string(&updated_token.inscription)
-                            .expect("Failed to serialize inscription"),
-                    ],
-                )
-                .expect("Failed to insert new name token");
+                        previous_label_hex,
+                        previous_first_blockheight,
+                        previous_first_blockindex,
+                        previous_first_vout,
+                        previous_first_txid,
+                        previous_last_blockheight,
+                        previous_last_blockindex,
+                        previous_last_vout,
+                        previous_last_txid,
+                        previous_inscription_json
+                    FROM name_token_undos
+                    WHERE blockheight = ?1
+                    ORDER BY rowid DESC",
+            )?;
+            let undos = statement
+                .query_map([blockheight], |row| {
+                    let first_blockheight: u64 = row.get(0)?;
+                    let first_blockindex: usize = row.get(1)?;
+                    let first_vout: u32 = row.get(2)?;
+                    let previous_label_hex: Option<String> = row.get(3)?;
+                    let previous_token = match previous_label_hex {
+                        None => None,
+                        Some(_) => Some(name_token_from_columns(row, 3)?),
+                    };
+                    Ok((
+                        (first_blockheight, first_blockindex, first_vout),
+                        previous_token,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            undos
+        };
+        for ((first_blockheight, first_blockindex, first_vout), previous_token) in undos {
+            transaction.execute(
+                "DELETE FROM name_tokens
+                    WHERE first_blockheight = ?1
+                        AND first_blockindex = ?2
+                        AND first_vout = ?3",
+                rusqlite::params![first_blockheight, first_blockindex, first_vout],
+            )?;
+            if let Some(previous_token) = previous_token {
+                Self::insert_name_token(&transaction, &previous_token)?;
+            }
         }
-        transaction.commit().expect("Failed to commit transaction");
+        transaction.execute(
+            "DELETE FROM name_token_undos WHERE blockheight >= ?1",
+            [blockheight],
+        )?;
+        transaction.execute("DELETE FROM blocks WHERE height >= ?1", [blockheight])?;
+        transaction.execute("DELETE FROM state", [])?;
+        transaction.execute(
+            "INSERT INTO state (next_block_height) VALUES (?1)",
+            [blockheight],
+        )?;
+        transaction.commit()
     }
 
-    pub async fn get_name_tokens_by_label(&self, _label: &Bytes) -> Vec<NameToken> {
-        let connection = self.connection.lock().unwrap();
-        let mut statement = connection
-            .prepare(
-                "SELECT
-                label_hex,
-                first_blockheight,
-                first_blockindex,
-                first_vout,
-                first_txid,
-                last_blockheight,
-                last_blockindex,
-                last_vout,
-                last_txid,
-                inscription_json
-            FROM name_tokens WHERE label_hex = ?1",
+    fn insert_name_token(
+        transaction: &rusqlite::Transaction,
+        name_token: &NameToken,
+    ) -> rusqlite::Result<()> {
+        transaction.execute(
+            "INSERT INTO name_tokens (
+                    label_hex,
+                    first_blockheight,
+                    first_blockindex,
+                    first_vout,
+                    first_txid,
+                    last_blockheight,
+                    last_blockindex,
+                    last_vout,
+                    last_txid,
+                    inscription_json
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            rusqlite::params![
+                name_token.label.to_hex_string(Case::Lower),
+                name_token.first_inscription_metadata.blockheight,
+                name_token.first_inscription_metadata.blockindex,
+                name_token.first_inscription_metadata.vout,
+                name_token.first_inscription_metadata.txid.to_string(),
+                name_token.last_inscription_metadata.blockheight,
+                name_token.last_inscription_metadata.blockindex,
+                name_token.last_inscription_metadata.vout,
+                name_token.last_inscription_metadata.txid.to_string(),
+                inscription_json(&name_token.inscription)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn insert_name_token_undo(
+        transaction: &rusqlite::Transaction,
+        blockheight: u64,
+        action: NameTokenUndoAction,
+        updated_token: &NameToken,
+        previous_token: Option<&NameToken>,
+    ) -> rusqlite::Result<()> {
+        let previous_inscription_json = previous_token
+            .map(|token| inscription_json(&token.inscription))
+            .transpose()?;
+        transaction.execute(
+            "INSERT INTO name_token_undos (
+                    blockheight,
+                    action,
+                    first_blockheight,
+                    first_blockindex,
+                    first_vout,
+                    previous_label_hex,
+                    previous_first_blockheight,
+                    previous_first_blockindex,
+                    previous_first_vout,
+                    previous_first_txid,
+                    previous_last_blockheight,
+                    previous_last_blockindex,
+                    previous_last_vout,
+                    previous_last_txid,
+                    previous_inscription_json
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            rusqlite::params![
+                blockheight,
+                action.as_str(),
+                updated_token.first_inscription_metadata.blockheight,
+                updated_token.first_inscription_metadata.blockindex,
+                updated_token.first_inscription_metadata.vout,
+                previous_token.map(|token| token.label.to_hex_string(Case::Lower)),
+                previous_token.map(|token| token.first_inscription_metadata.blockheight),
+                previous_token.map(|token| token.first_inscription_metadata.blockindex),
+                previous_token.map(|token| token.first_inscription_metadata.vout),
+                previous_token.map(|token| token.first_inscription_metadata.txid.to_string()),
+                previous_token.map(|token| token.last_inscription_metadata.blockheight),
+                previous_token.map(|token| token.last_inscription_metadata.blockindex),
+                previous_token.map(|token| token.last_inscription_metadata.vout),
+                previous_token.map(|token| token.last_inscription_metadata.txid.to_string()),
+                previous_inscription_json,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub async fn get_name_tokens_by_label(
+        &self,
+        label: &Bytes,
+    ) -> rusqlite::Result<Vec<NameToken>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {NAME_TOKEN_COLUMNS}
+                FROM name_tokens WHERE label_hex = ?1"
+        ))?;
+        let params = rusqlite::params![&label.to_hex_string(Case::Lower)];
+        let name_tokens = statement.query_map(params, name_token_from_row)?;
+        name_tokens.collect()
+    }
+
+    pub async fn get_name_tokens(&self) -> rusqlite::Result<Vec<NameToken>> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare(&format!("SELECT {NAME_TOKEN_COLUMNS} FROM name_tokens"))?;
+        let name_tokens = statement.query_map([], name_token_from_row)?;
+        name_tokens.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::name_token::InscriptionSection;
+    use bitcoin::hashes::Hash;
+
+    fn create_inscription(label: &Bytes, argument: &[u8]) -> Inscription {
+        Inscription {
+            label: label.clone(),
+            sections: vec![InscriptionSection {
+                protocol: b"section-0".into(),
+                arguments: vec![argument.into()],
+            }],
+        }
+    }
+
+    fn create_metadata(blockheight: u64) -> InscriptionMetadata {
+        InscriptionMetadata {
+            blockheight,
+            blockindex: 0,
+            vout: 0,
+            txid: Txid::from_byte_array([blockheight as u8; 32]),
+        }
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), MIN_RETRY_DELAY);
+        assert_eq!(retry_delay(1), MIN_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(3), MIN_RETRY_DELAY * 8);
+        assert_eq!(retry_delay(20), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    async fn create_database() -> NameTokensDatabase {
+        let sqlite = rusqlite::Connection::open_in_memory().unwrap();
+        NameTokensDatabase::from_connection(sqlite).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_generation() {
+        let repository = NameTokenRepository {
+            database: create_database().await,
+            bitcoin_rpc_url: String::new(),
+            bitcoin_rpc_auth: bitcoincore_rpc::Auth::None,
+            next_block_height: Arc::new(AtomicU64::new(10)),
+            generation: Arc::new(watch::Sender::new(0)),
+            sync_status: Arc::new(watch::Sender::new(SyncStatus::default())),
+        };
+        let mut updates = repository.watch_generation();
+
+        // A reorganization rolls back a block and indexes another one at the same height.
+        let generation = repository.generation();
+        repository.record_block_change(9);
+        repository.record_block_change(10);
+        assert_eq!(repository.next_block_height(), 10);
+        assert_eq!(repository.generation(), 2);
+        assert!(!updates.has_changed().unwrap());
+        repository.notify_block_changes(generation);
+        assert!(updates.has_changed().unwrap());
+        assert_eq!(*updates.borrow_and_update(), 2);
+
+        // A sync that changed nothing does not wake the watchers.
+        repository.notify_block_changes(repository.generation());
+        assert!(!updates.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_connection() {
+        let database = create_database().await;
+        let connection = database.connection.clone();
+        let panicked = std::thread::spawn(move || {
+            let _connection = connection.lock().unwrap();
+            panic!("watcher panicked");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(database.connection.is_poisoned());
+        assert_eq!(database.get_next_block_height().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_row() {
+        let database = create_database().await;
+        let label = Bytes::from(b"label");
+        // A row whose txids do not parse, e.g. written by another version of the indexer.
+        database
+            .connection()
+            .execute(
+                "INSERT INTO name_tokens VALUES
+                (?1, 1, 0, 0, 'not a txid', 1, 0, 0, 'not a txid', 'null')",
+                [label.to_hex_string(Case::Lower)],
             )
             .unwrap();
-        let params = rusqlite::params![&_label.to_hex_string(Case::Lower)];
-        let name_tokens = statement
-            .query_map(params, |row| {
-                let label_hex: String = row.get(0).unwrap();
-                let first_blockheight: u64 = row.get(1).unwrap();
-                let first_blockindex: usize = row.get(2).unwrap();
-                let first_vout: u32 = row.get(3).unwrap();
-                let first_txid: String = row.get(4).unwrap();
-                let last_blockheight: u64 = row.get(5).unwrap();
-                let last_blockindex: usize = row.get(6).unwrap();
-                let last_vout: u32 = row.get(7).unwrap();
-                let last_txid: String = row.get(8).unwrap();
-                let inscription_json: String = row.get(9).unwrap();
-                Ok(NameToken {
-                    first_inscription_metadata: InscriptionMetadata {
-                        txid: Txid::from_str(&first_txid).expect("Invalid Txid"),
-                        vout: first_vout,
-                        blockheight: first_blockheight,
-                        blockindex: first_blockindex,
-                    },
-                    last_inscription_metadata: InscriptionMetadata {
-                        txid: bitcoin::Txid::from_str(&last_txid).expect("Invalid Txid"),
-                        vout: last_vout,
-                        blockheight: last_blockheight,
-                        blockindex: last_blockindex,
-                    },
-                    label: Bytes::from_hex(&label_hex).expect("Invalid label hex"),
-                    inscription: Some(
-                        serde_json::from_str::<Inscription>(&inscription_json)
-                            .expect("Failed to parse inscription JSON"),
-                    ),
-                })
-            })
-            .expect("Failed to query name tokens");
-        name_tokens.filter_map(Result::ok).collect()
+        assert!(database.get_name_tokens().await.is_err());
+        assert!(database.get_name_tokens_by_label(&label).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rollback_block() {
+        let database = create_database().await;
+        let label = Bytes::from(b"label");
+        let block_hash_0 = BlockHash::from_byte_array([0; 32]);
+        let block_hash_1 = BlockHash::from_byte_array([1; 32]);
+
+        let created_token =
+            NameToken::create(create_inscription(&label, b"arg1"), create_metadata(0));
+        database
+            .save_block_updates(0, &block_hash_0, [&created_token])
+            .await
+            .unwrap();
+        let updated_token = created_token
+            .update(create_inscription(&label, b"arg2"), create_metadata(1))
+            .unwrap();
+        database
+            .save_block_updates(1, &block_hash_1, [&updated_token])
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_name_tokens_by_label(&label).await.unwrap(),
+            vec![updated_token]
+        );
+        assert_eq!(
+            database.get_last_block().await.unwrap(),
+            Some((1, block_hash_1))
+        );
+
+        database.rollback_block(1).await.unwrap();
+        assert_eq!(
+            database.get_name_tokens_by_label(&label).await.unwrap(),
+            vec![created_token.clone()]
+        );
+        assert_eq!(database.get_next_block_height().await.unwrap(), 1);
+        assert_eq!(
+            database.get_last_block().await.unwrap(),
+            Some((0, block_hash_0))
+        );
+
+        database.rollback_block(0).await.unwrap();
+        assert_eq!(
+            database.get_name_tokens_by_label(&label).await.unwrap(),
+            vec![]
+        );
+        assert_eq!(database.get_next_block_height().await.unwrap(), 0);
+        assert_eq!(database.get_last_block().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_block_hashes() {
+        let database = create_database().await;
+        let block_hash = |height: u64| BlockHash::from_byte_array([height as u8; 32]);
+        assert!(!database.has_block_hashes().await.unwrap());
+
+        database.record_block_hash(0, &block_hash(0)).await.unwrap();
+        assert!(database.has_block_hashes().await.unwrap());
+
+        // Only the hashes and undos of the last `MAX_REORG_DEPTH` blocks are kept.
+        for height in 0..MAX_REORG_DEPTH + 2 {
+            database
+                .save_block_updates(height, &block_hash(height), [])
+                .await
+                .unwrap();
+        }
+        assert_eq!(database.get_block_hash(1).await.unwrap(), None);
+        assert_eq!(
+            database.get_block_hash(2).await.unwrap(),
+            Some(block_hash(2))
+        );
+        assert_eq!(
+            database.get_last_block().await.unwrap(),
+            Some((MAX_REORG_DEPTH + 1, block_hash(MAX_REORG_DEPTH + 1)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rollback_revoked_name_token() {
+        let database = create_database().await;
+        let label = Bytes::from(b"label");
+
+        let created_token =
+            NameToken::create(create_inscription(&label, b"arg1"), create_metadata(0));
+        database
+            .save_block_updates(0, &BlockHash::from_byte_array([0; 32]), [&created_token])
+            .await
+            .unwrap();
+        database
+            .save_block_updates(
+                1,
+                &BlockHash::from_byte_array([1; 32]),
+                [&created_token.revoke()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            database.get_name_tokens_by_label(&label).await.unwrap(),
+            vec![]
+        );
+
+        database.rollback_block(1).await.unwrap();
+        assert_eq!(
+            database.get_name_tokens_by_label(&label).await.unwrap(),
+            vec![created_token.clone()]
+        );
+        assert_eq!(
+            database
+                .get_name_token_by_outpoint(created_token.last_outpoint())
+                .await
+                .unwrap(),
+            Some(created_token)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_name_tokens() {
+        let database = create_database().await;
+        let label_1 = Bytes::from(b"label-1");
+        let label_2 = Bytes::from(b"label-2");
+
+        let token_1 = NameToken::create(create_inscription(&label_1, b"arg1"), create_metadata(0));
+        let token_2 = NameToken::create(create_inscription(&label_2, b"arg2"), create_metadata(1));
+        database
+            .save_block_updates(0, &BlockHash::from_byte_array([0; 32]), [&token_1])
+            .await
+            .unwrap();
+        database
+            .save_block_updates(1, &BlockHash::from_byte_array([1; 32]), [&token_2])
+            .await
+            .unwrap();
+
+        let mut name_tokens = database.get_name_tokens().await.unwrap();
+        name_tokens.sort_by(|a, b| a.label.cmp(&b.label));
+        assert_eq!(name_tokens, vec![token_1, token_2]);
     }
 }
@@ -0,0 +1,81 @@
+use hickory_server::proto::rr::domain::Label;
+use std::{collections::HashSet, sync::Mutex};
+
+/// Default number of labels without a Name-Token remembered per origin.
+pub const DEFAULT_NEGATIVE_CACHE_SIZE: usize = 100_000;
+
+/// Labels no valid Name-Token holds, so the queries for them are answered without looking them
+/// up in the database of the indexer again.
+///
+/// The labels are forgotten as soon as the generation of the indexed Name-Tokens changes, as the
+/// blocks indexed or rolled back may create their Name-Tokens. When the cache is full, it is
+/// emptied.
+pub struct NegativeCache {
+    max_entries: usize,
+    entries: Mutex<NegativeCacheEntries>,
+}
+
+struct NegativeCacheEntries {
+    generation: u64,
+    labels: HashSet<Label>,
+}
+
+impl NegativeCache {
+    /// Cache holding at most `max_entries` labels. A zero size disables the cache.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: Mutex::new(NegativeCacheEntries {
+                generation: 0,
+                labels: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Whether `label` was found without a Name-Token in the `generation` of the indexed
+    /// Name-Tokens.
+    pub fn contains(&self, label: &Label, generation: u64) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.generation == generation && entries.labels.contains(label)
+    }
+
+    /// Remember that no Name-Token holds `label`, as of `generation`.
+    pub fn insert(&self, label: &Label, generation: u64) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation != generation {
+            entries.generation = generation;
+            entries.labels.clear();
+        }
+        if entries.labels.len() >= self.max_entries {
+            entries.labels.clear();
+        }
+        entries.labels.insert(label.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negative_cache() {
+        let negative_cache = NegativeCache::new(2);
+        let label = |label| Label::from_ascii(label).unwrap();
+        negative_cache.insert(&label("alice"), 42);
+        negative_cache.insert(&label("bob"), 42);
+        assert!(negative_cache.contains(&label("alice"), 42));
+        assert!(!negative_cache.contains(&label("carol"), 42));
+        // An indexed or rolled back block may have created the Name-Tokens.
+        assert!(!negative_cache.contains(&label("alice"), 43));
+        negative_cache.insert(&label("carol"), 42);
+        assert!(negative_cache.contains(&label("carol"), 42));
+        assert!(!negative_cache.contains(&label("alice"), 42));
+
+        let negative_cache = NegativeCache::new(0);
+        negative_cache.insert(&label("alice"), 42);
+        assert!(!negative_cache.contains(&label("alice"), 42));
+    }
+}
@@ -1,21 +1,72 @@
 use crate::{
-    dns_nostr_token_repository::GetDnsNostrToken, nostr_events_repository::NostrEventsRepository,
+    dns_nostr_token::DnsNostrToken,
+    dns_nostr_token_repository::GetDnsNostrToken,
+    dns_request_handler::{report_server_failure, request_tsig_key_name},
+    label_policy::LabelPolicy,
+    metrics::{metrics, CacheKind, LookupStage},
+    negative_cache::{NegativeCache, DEFAULT_NEGATIVE_CACHE_SIZE},
+    nostr_events_repository::{zone_event_identifier, NostrEventsRepository},
+    origin_zone::OriginZone,
+    zone_cache::{zone_ttl, ZoneCache, DEFAULT_ZONE_CACHE_SIZE},
+    zone_decoder::{decode_wire_record_tags, insert_record, wire_record_tags, ZoneDecoders},
+    zone_lookup::{self, ZoneLookup},
+    zone_policy::ZonePolicy,
+    zone_signer::ZoneSigner,
+    zone_transfer::{TransferZone, ZoneTransfers},
+    zone_update::{self, ZoneUpdates},
 };
 use hickory_server::{
-    authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType},
+    authority::{
+        AuthLookup, Authority, LookupError, LookupOptions, LookupRecords, MessageRequest,
+        UpdateRequest, UpdateResult, ZoneType,
+    },
     proto::{
         op::ResponseCode,
-        rr::{domain::Label, LowerName, Name, RecordType},
-        serialize::txt::Parser,
+        rr::{
+            dnssec::rdata::DNSSECRData, domain::Label, rdata::NS, LowerName, Name, RData, Record,
+            RecordType,
+        },
     },
-    server::RequestInfo,
+    server::{Protocol, RequestInfo},
     store::in_memory::InMemoryAuthority,
 };
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::Instant,
+};
+use tracing::{debug, error, warn};
+
+/// Most CNAME and DNAME links of the chain followed to answer a query.
+const MAX_ALIASES: usize = 8;
+
+tokio::task_local! {
+    /// Zone signed by its owner that answered the request being handled, if any.
+    static OWNER_SIGNED_ZONE: RefCell<Option<Arc<InMemoryAuthority>>>;
+}
+
+/// Run `request`, the handling of a request by the authorities, keeping the zone signed by its
+/// owner that answers it, so negative answers carry the SOA the owner signed.
+pub async fn scope_request<F: Future>(request: F) -> F::Output {
+    OWNER_SIGNED_ZONE.scope(RefCell::new(None), request).await
+}
 
 pub struct NostrAuthority<GetTokenT: GetDnsNostrToken> {
     zone: LowerName,
     dns_nostr_token_repository: GetTokenT,
     nostr_events_repository: NostrEventsRepository,
+    label_policy: LabelPolicy,
+    zone_policy: ZonePolicy,
+    zone_decoders: ZoneDecoders,
+    zone_cache: ZoneCache,
+    negative_cache: NegativeCache,
+    origin_zone: OriginZone,
+    zone_signer: Option<ZoneSigner>,
+    zone_updates: Option<ZoneUpdates>,
+    zone_transfers: Option<ZoneTransfers>,
 }
 
 #[async_trait::async_trait]
@@ -27,12 +78,126 @@ impl<GetTokenT: GetDnsNostrToken> Authority for NostrAuthority<GetTokenT> {
         ZoneType::Primary
     }
 
+    /// Transfers are allowed when set up, each one being checked against the `ZoneTransfers`.
     fn is_axfr_allowed(&self) -> bool {
-        false
+        self.zone_transfers.is_some()
     }
 
-    async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
-        Err(ResponseCode::NotImp)
+    /// Apply an RFC 2136 update to the zone of a Name-Token label, publishing the updated zone
+    /// as a new zone event, see `ZoneUpdates`.
+    async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+        let Some(zone_updates) = &self.zone_updates else {
+            return Err(ResponseCode::NotImp);
+        };
+        let zone_name = update.zone().name();
+        // Only the zones of the labels can be updated, not the origin nor their subdomains.
+        let (Some(token_label), Some(label_zone_name)) = (
+            self.extract_token_label(zone_name),
+            self.extract_zone_name(zone_name),
+        ) else {
+            return Err(ResponseCode::NotAuth);
+        };
+        if *zone_name != LowerName::new(&label_zone_name) || !self.label_policy.allows(&token_label)
+        {
+            return Err(ResponseCode::NotAuth);
+        }
+        let dns_nostr_token = self
+            .get_token(&token_label)
+            .await
+            .map_err(|_| ResponseCode::ServFail)?
+            .ok_or(ResponseCode::NotAuth)?;
+        if !dns_nostr_token.ds_records.is_empty() {
+            warn!(zone = %zone_name, "refused update of a zone signed by its owner");
+            return Err(ResponseCode::Refused);
+        }
+        let zone_event = self
+            .nostr_events_repository
+            .get_zone_event(dns_nostr_token.nostr_pubkey, &token_label)
+            .await
+            .map_err(|e| {
+                warn!(zone = %zone_name, error = %e, "failed to fetch zone");
+                ResponseCode::ServFail
+            })?;
+        let pending_zone_event = zone_updates
+            .pending_zone_event(
+                &dns_nostr_token.nostr_pubkey,
+                self.nostr_events_repository.zone_event_kind(),
+                &zone_event_identifier(&token_label),
+            )
+            .await
+            .map_err(|e| {
+                error!(zone = %zone_name, error = %e, "failed to read queued zone");
+                ResponseCode::ServFail
+            })?
+            // A zone published since it was queued supersedes it.
+            .filter(|pending_zone_event| {
+                zone_event
+                    .as_ref()
+                    .is_none_or(|zone_event| pending_zone_event.created_at >= zone_event.created_at)
+            });
+        let mut records = match (pending_zone_event, zone_event) {
+            (Some(pending_zone_event), _) => {
+                let records = decode_wire_record_tags(&pending_zone_event.tags).map_err(|e| {
+                    error!(zone = %zone_name, error = %e, "failed to decode queued zone");
+                    ResponseCode::ServFail
+                })?;
+                self.zone_policy.scope(&label_zone_name, records)
+            }
+            (None, Some(zone_event)) => {
+                let records = self
+                    .zone_decoders
+                    .decode(&zone_event, &label_zone_name)
+                    .map_err(|e| {
+                        warn!(event = %zone_event.id, error = %e, "failed to decode zone");
+                        ResponseCode::ServFail
+                    })?;
+                self.zone_policy.scope(&label_zone_name, records)
+            }
+            (None, None) => zone_update::new_zone_records(&label_zone_name),
+        };
+
+        let label_keys = zone_updates.label_keys(&token_label);
+        zone_update::authorize(update, &records, &label_keys.tsig_signers)?;
+        if !zone_update::update_zone(update, &label_zone_name, &mut records)? {
+            return Ok(false);
+        }
+        let records = self.zone_policy.scope(&label_zone_name, records);
+        let tags = wire_record_tags(&records).map_err(|e| {
+            error!(zone = %zone_name, error = %e, "failed to encode zone");
+            ResponseCode::ServFail
+        })?;
+        let zone_event_builder = self
+            .nostr_events_repository
+            .zone_event_builder(&token_label, tags);
+        let nostr_keys = label_keys
+            .nostr_keys
+            .filter(|nostr_keys| nostr_keys.public_key() == dns_nostr_token.nostr_pubkey);
+        let Some(nostr_keys) = nostr_keys else {
+            let zone_event = zone_event_builder.build(dns_nostr_token.nostr_pubkey);
+            zone_updates
+                .queue_zone_event(&zone_event)
+                .await
+                .map_err(|e| {
+                    error!(zone = %zone_name, error = %e, "failed to queue zone");
+                    ResponseCode::ServFail
+                })?;
+            return Ok(true);
+        };
+        let zone_event = zone_event_builder
+            .sign_with_keys(&nostr_keys)
+            .map_err(|e| {
+                error!(zone = %zone_name, error = %e, "failed to sign zone");
+                ResponseCode::ServFail
+            })?;
+        self.nostr_events_repository
+            .publish_zone_event(&zone_event)
+            .await
+            .map_err(|e| {
+                warn!(zone = %zone_name, error = %e, "failed to publish zone");
+                ResponseCode::ServFail
+            })?;
+        self.zone_cache.invalidate(&token_label);
+        Ok(true)
     }
 
     fn origin(&self) -> &LowerName {
@@ -45,11 +210,11 @@ impl<GetTokenT: GetDnsNostrToken> Authority for NostrAuthority<GetTokenT> {
         rtype: RecordType,
         lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
-        let authority = self
-            .create_in_memory_zone_authority(name)
-            .await
-            .ok_or_else(|| LookupError::from(ResponseCode::NXDomain))?;
-        authority.lookup(name, rtype, lookup_options).await
+        if matches!(rtype, RecordType::ANY | RecordType::AXFR | RecordType::IXFR) {
+            let authority = self.get_authority_for(name, rtype).await?;
+            return authority.lookup(name, rtype, lookup_options).await;
+        }
+        self.resolve(name, rtype, lookup_options).await
     }
 
     /// Using the specified query, perform a lookup against this zone.
@@ -68,11 +233,16 @@ impl<GetTokenT: GetDnsNostrToken> Authority for NostrAuthority<GetTokenT> {
         request: RequestInfo<'_>,
         lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
-        let authority = self
-            .create_in_memory_zone_authority(request.query.name())
-            .await
-            .ok_or_else(|| LookupError::from(ResponseCode::NXDomain))?;
-        authority.search(request, lookup_options).await
+        let name = request.query.name();
+        let rtype = request.query.query_type();
+        if matches!(rtype, RecordType::AXFR | RecordType::IXFR) {
+            return self.transfer(&request, lookup_options).await;
+        }
+        if rtype == RecordType::SOA {
+            let authority = self.get_authority_for(name, rtype).await?;
+            return authority.search(request, lookup_options).await;
+        }
+        self.lookup(name, rtype, lookup_options).await
     }
 
     /// Get the NS, NameServer, record for the zone
@@ -90,10 +260,19 @@ impl<GetTokenT: GetDnsNostrToken> Authority for NostrAuthority<GetTokenT> {
     /// * `is_secure` - if true then it will return RRSIG records as well
     async fn get_nsec_records(
         &self,
-        _: &LowerName,
-        _: LookupOptions,
+        name: &LowerName,
+        lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
-        Result::Err(LookupError::ResponseCode(ResponseCode::NotImp))
+        if self.zone_signer.is_none() {
+            return Err(LookupError::ResponseCode(ResponseCode::NotImp));
+        }
+        // The names under labels without a zone are denied by the NSEC chain of the origin.
+        let authority = match self.get_authority(name).await {
+            Ok(authority) => authority,
+            Err(e) if e.is_nx_domain() || e.is_name_exists() => self.get_origin_authority()?,
+            Err(e) => return Err(e),
+        };
+        authority.get_nsec_records(name, lookup_options).await
     }
 
     /// Returns the SOA of the authority.
@@ -108,6 +287,14 @@ impl<GetTokenT: GetDnsNostrToken> Authority for NostrAuthority<GetTokenT> {
 
     /// Returns the SOA record for the zone
     async fn soa_secure(&self, lookup_options: LookupOptions) -> Result<Self::Lookup, LookupError> {
+        // Negative answers of a zone signed by its owner are proven by its own SOA.
+        let owner_signed_zone = OWNER_SIGNED_ZONE
+            .try_with(|owner_signed_zone| owner_signed_zone.borrow().clone())
+            .ok()
+            .flatten();
+        if let Some(owner_signed_zone) = owner_signed_zone {
+            return owner_signed_zone.soa_secure(lookup_options).await;
+        }
         self.lookup(self.origin(), RecordType::SOA, lookup_options)
             .await
     }
@@ -120,45 +307,590 @@ impl<GetTokenT: GetDnsNostrToken> NostrAuthority<GetTokenT> {
         nostr_client: NostrEventsRepository,
     ) -> Self {
         Self {
-            zone,
             dns_nostr_token_repository,
             nostr_events_repository: nostr_client,
+            label_policy: LabelPolicy::default(),
+            zone_policy: ZonePolicy::default(),
+            zone_decoders: ZoneDecoders::default(),
+            zone_cache: ZoneCache::new(DEFAULT_ZONE_CACHE_SIZE),
+            negative_cache: NegativeCache::new(DEFAULT_NEGATIVE_CACHE_SIZE),
+            origin_zone: OriginZone::new(Name::from(&zone)),
+            zone_signer: None,
+            zone_updates: None,
+            zone_transfers: None,
+            zone,
         }
     }
 
-    async fn create_in_memory_zone_authority(&self, name: &LowerName) -> Option<InMemoryAuthority> {
-        let zone_file = self.get_zone_file(name).await?;
-        let zone_name = self.extract_zone_name(name)?;
-        let (_, records) = Parser::new(&zone_file, None, Some(zone_name.clone()))
-            .parse()
-            .inspect_err(|e| {
-                eprintln!("failed to parse zone file ({:?}):\n: {}", e, &zone_file);
-            })
-            .ok()?;
-        let authority =
-            InMemoryAuthority::new(zone_name.clone(), records, ZoneType::Primary, false)
-                .inspect_err(|e| {
-                    format!(
-                        "failed to create authority for {}: {:?}",
-                        zone_name.to_string(),
-                        e
+    /// Only serve the Name-Token labels allowed by `label_policy`.
+    pub fn with_label_policy(mut self, label_policy: LabelPolicy) -> Self {
+        self.label_policy = label_policy;
+        self
+    }
+
+    /// Only serve the records of the zones allowed by `zone_policy`.
+    pub fn with_zone_policy(mut self, zone_policy: ZonePolicy) -> Self {
+        self.zone_policy = zone_policy;
+        self
+    }
+
+    /// Decode the zones published in the Nostr events with `zone_decoders` instead of the
+    /// default formats.
+    pub fn with_zone_decoders(mut self, zone_decoders: ZoneDecoders) -> Self {
+        self.zone_decoders = zone_decoders;
+        self
+    }
+
+    /// Cache at most `zone_cache_size` parsed zones, none when zero.
+    pub fn with_zone_cache_size(mut self, zone_cache_size: usize) -> Self {
+        let max_age = self.zone_cache.max_age();
+        self.zone_cache = ZoneCache::new(zone_cache_size);
+        self.zone_cache.set_max_age(max_age);
+        self
+    }
+
+    /// Remember at most `negative_cache_size` labels without a Name-Token, none when zero.
+    pub fn with_negative_cache_size(mut self, negative_cache_size: usize) -> Self {
+        self.negative_cache = NegativeCache::new(negative_cache_size);
+        self
+    }
+
+    /// Serve the SOA, name servers and glue records of `origin_zone` for the origin.
+    pub fn with_origin_zone(mut self, origin_zone: OriginZone) -> Self {
+        self.origin_zone = origin_zone;
+        self
+    }
+
+    /// Sign the origin zone and the zones of the labels with `zone_signer`, signing the cached
+    /// zones again before their signatures expire.
+    pub fn with_zone_signer(mut self, zone_signer: ZoneSigner) -> Self {
+        self.zone_cache.set_max_age(zone_signer.resign_interval());
+        self.zone_signer = Some(zone_signer);
+        self
+    }
+
+    /// Accept RFC 2136 updates of the zones of the labels, as set up by `zone_updates`.
+    pub fn with_zone_updates(mut self, zone_updates: ZoneUpdates) -> Self {
+        self.zone_updates = Some(zone_updates);
+        self
+    }
+
+    /// Serve AXFR and IXFR of the whole origin zone, as set up by `zone_transfers`, see
+    /// `refresh_transfer_zone`.
+    pub fn with_zone_transfers(mut self, zone_transfers: ZoneTransfers) -> Self {
+        self.zone_transfers = Some(zone_transfers);
+        self
+    }
+
+    /// Assemble the origin zone transferred to the secondaries from the zones of every indexed
+    /// Name-Token, notifying them when it changes.
+    ///
+    /// The zones signed by the operator are flattened into the origin zone, while the zones
+    /// signed by their owners are delegated to the name servers of the origin with their DS
+    /// records. The serial is the height of the next block to index plus the timestamp of the
+    /// latest zone event, incremented when that would not make it grow.
+    ///
+    /// The zone is kept as it is while the Name-Tokens cannot be read or a zone is unavailable,
+    /// e.g. as no quorum of relays answered, so an outage does not remove names from the
+    /// secondaries. Labels whose zone is invalid, or confirmed missing by a quorum of relays, are
+    /// left out.
+    pub async fn refresh_transfer_zone(&self) {
+        let Some(zone_transfers) = &self.zone_transfers else {
+            return;
+        };
+        let mut dns_nostr_tokens = match self.dns_nostr_token_repository.get_tokens().await {
+            Ok(dns_nostr_tokens) => dns_nostr_tokens,
+            Err(e) => {
+                warn!(
+                    origin = %self.zone,
+                    error = %e,
+                    "kept transfer zone, Name-Tokens unavailable"
+                );
+                return;
+            }
+        };
+        dns_nostr_tokens.retain(|dns_nostr_token| self.label_policy.allows(&dns_nostr_token.label));
+        dns_nostr_tokens.sort_by(|a, b| a.label.cmp(&b.label));
+
+        let mut hasher = DefaultHasher::new();
+        let mut latest = 0;
+        let mut label_records = Vec::new();
+        for dns_nostr_token in &dns_nostr_tokens {
+            let Some(zone_name) = Name::from_labels([dns_nostr_token.label.clone()])
+                .ok()
+                .and_then(|label| label.append_domain(&self.origin_zone.origin).ok())
+            else {
+                continue;
+            };
+            (&dns_nostr_token.label, &dns_nostr_token.outpoint).hash(&mut hasher);
+            if !dns_nostr_token.ds_records.is_empty() {
+                label_records.extend(self.delegation_records(dns_nostr_token, &zone_name));
+                continue;
+            }
+            let zone = match self.get_token_zone(dns_nostr_token, &zone_name).await {
+                Ok(Some(zone)) => zone,
+                // A quorum of relays confirmed that the owner has not published a zone.
+                Ok(None) => continue,
+                // A zone its owner broke is left out, as it would be when queried.
+                Err(TokenZoneError::Invalid) => {
+                    warn!(
+                        origin = %self.zone,
+                        zone = %zone_name,
+                        "left invalid zone out of transfer zone"
                     );
-                })
-                .ok()?;
-        Some(authority)
+                    continue;
+                }
+                Err(TokenZoneError::Unavailable) => {
+                    warn!(
+                        origin = %self.zone,
+                        zone = %zone_name,
+                        "kept transfer zone, zone unavailable"
+                    );
+                    return;
+                }
+            };
+            let (authority, zone_event) = zone;
+            zone_event.id.hash(&mut hasher);
+            latest = latest.max(zone_event.created_at.as_u64());
+            let zone_name = LowerName::new(&zone_name);
+            for record_set in authority.records().await.values() {
+                let rtype = record_set.record_type();
+                let is_apex = *record_set.name() == Name::from(&zone_name);
+                if zone_update::is_dnssec_type(rtype)
+                    || rtype == RecordType::SOA
+                    || (is_apex && rtype == RecordType::NS)
+                {
+                    continue;
+                }
+                label_records.extend(record_set.records_without_rrsigs().cloned());
+            }
+        }
+        let fingerprint = hasher.finish();
+
+        let is_resign_due = |zone: &TransferZone| {
+            self.zone_signer.as_ref().is_some_and(|zone_signer| {
+                zone.assembled_at.elapsed() >= zone_signer.resign_interval()
+            })
+        };
+        if let Some(zone) = zone_transfers.zone() {
+            if zone.fingerprint == fingerprint && !is_resign_due(&zone) {
+                return;
+            }
+        }
+        let next_block_height = self.dns_nostr_token_repository.next_block_height() as u32;
+        let serial = zone_transfers.next_serial(next_block_height.wrapping_add(latest as u32));
+        let authority = match self.create_transfer_authority(serial, label_records) {
+            Ok(authority) => authority,
+            Err(e) => {
+                error!(origin = %self.zone, error = %e, "failed to assemble transfer zone");
+                return;
+            }
+        };
+        let soa = authority
+            .records()
+            .await
+            .values()
+            .find(|record_set| record_set.record_type() == RecordType::SOA)
+            .and_then(|record_set| record_set.records_without_rrsigs().next().cloned());
+        zone_transfers.set_zone(TransferZone {
+            serial,
+            fingerprint,
+            authority,
+            assembled_at: Instant::now(),
+        });
+        if let Some(soa) = soa {
+            zone_transfers.notify(&soa).await;
+        }
+    }
+
+    /// NS records delegating the zone `zone_name`, signed by the owner of `dns_nostr_token`, to
+    /// the name servers of the origin, with the DS records of the owner's keys.
+    fn delegation_records(&self, dns_nostr_token: &DnsNostrToken, zone_name: &Name) -> Vec<Record> {
+        let ttl = self.origin_zone.soa.ttl;
+        let ns_records = self.origin_zone.name_servers.iter().map(|name_server| {
+            let rdata = RData::NS(NS(name_server.name.clone()));
+            Record::from_rdata(zone_name.clone(), ttl, rdata)
+        });
+        let ds_records = dns_nostr_token.ds_records.iter().map(|ds| {
+            let rdata = RData::DNSSEC(DNSSECRData::DS(ds.clone()));
+            Record::from_rdata(zone_name.clone(), ttl, rdata)
+        });
+        ns_records.chain(ds_records).collect()
+    }
+
+    /// Origin zone with the SOA `serial` holding `label_records`, signed when DNSSEC is enabled.
+    fn create_transfer_authority(
+        &self,
+        serial: u32,
+        label_records: Vec<Record>,
+    ) -> Result<Arc<InMemoryAuthority>, String> {
+        // Signing increments the serial of the zone.
+        let records_serial = match self.zone_signer {
+            Some(_) => serial.wrapping_sub(1),
+            None => serial,
+        };
+        let mut records = self.origin_zone.records(records_serial);
+        for record in label_records {
+            insert_record(&mut records, record);
+        }
+        let mut authority = InMemoryAuthority::new(
+            self.origin_zone.origin.clone(),
+            records,
+            ZoneType::Primary,
+            true,
+        )?;
+        if let Some(zone_signer) = &self.zone_signer {
+            zone_signer
+                .sign(&mut authority)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(Arc::new(authority))
     }
 
-    async fn get_zone_file(&self, name: &LowerName) -> Option<String> {
-        let token_label = self.extract_token_label(name)?;
+    /// Answer of the AXFR or IXFR `request` with the whole transfer zone, when allowed.
+    ///
+    /// IXFR is answered with a full transfer, as RFC 1995 allows, and only with the SOA over UDP,
+    /// for the client to retry over TCP. AXFR is refused over UDP.
+    async fn transfer(
+        &self,
+        request: &RequestInfo<'_>,
+        lookup_options: LookupOptions,
+    ) -> Result<AuthLookup, LookupError> {
+        let refused = || LookupError::from(ResponseCode::Refused);
+        let Some(zone_transfers) = &self.zone_transfers else {
+            return Err(refused());
+        };
+        let name = request.query.name();
+        let rtype = request.query.query_type();
+        if name != self.origin() {
+            return Err(refused());
+        }
+        let tsig_key_name = request_tsig_key_name();
+        if !zone_transfers.allows(request.src.ip(), tsig_key_name.as_ref()) {
+            warn!(%rtype, src = %request.src, "refused zone transfer");
+            return Err(refused());
+        }
+        let is_udp = matches!(request.protocol, Protocol::Udp);
+        if is_udp && rtype == RecordType::AXFR {
+            return Err(refused());
+        }
+        if zone_transfers.zone().is_none() {
+            self.refresh_transfer_zone().await;
+        }
+        let Some(zone) = zone_transfers.zone() else {
+            return Err(server_failure());
+        };
+        if is_udp {
+            return zone.authority.soa_secure(lookup_options).await;
+        }
+        let start_soa = zone.authority.soa_secure(lookup_options).await?;
+        let records = zone
+            .authority
+            .lookup(name, RecordType::AXFR, lookup_options)
+            .await?;
+        let end_soa = zone.authority.soa().await?;
+        Ok(AuthLookup::AXFR {
+            start_soa: start_soa.unwrap_records(),
+            records: records.unwrap_records(),
+            end_soa: end_soa.unwrap_records(),
+        })
+    }
+
+    /// Name-Token of `label`, remembering the labels without one until a block is indexed or
+    /// rolled back, so floods of random labels do not reach the database.
+    ///
+    /// Fails with SERVFAIL when the database of the indexer cannot be read.
+    async fn get_token(&self, label: &Label) -> Result<Option<DnsNostrToken>, LookupError> {
+        let generation = self.dns_nostr_token_repository.generation();
+        let is_cached = self.negative_cache.contains(label, generation);
+        metrics().observe_cache_lookup(CacheKind::Negative, is_cached);
+        if is_cached {
+            debug!(%label, "no Name-Token holds the label, cached");
+            return Ok(None);
+        }
+        let start = Instant::now();
         let dns_nostr_token = self
             .dns_nostr_token_repository
-            .get_token(&token_label)
-            .await?;
-        let last_text_note = self
+            .get_token(label)
+            .await
+            .map_err(|e| {
+                error!(%label, error = %e, "failed to look up Name-Token");
+                server_failure()
+            })?;
+        metrics().observe_lookup(LookupStage::Sqlite, start);
+        if dns_nostr_token.is_none() {
+            debug!(%label, "no Name-Token holds the label");
+            self.negative_cache.insert(label, generation);
+        }
+        Ok(dns_nostr_token)
+    }
+
+    /// Answer of the `rtype` records of `name`, with the wildcards, CNAME and DNAME of the zones.
+    ///
+    /// The aliases to names of the origin are followed through the zones of their labels, so the
+    /// answer holds the whole chain, up to `MAX_ALIASES` links. The chain ends at names outside of
+    /// the origin, or without records, for the resolver to follow it from there.
+    async fn resolve(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        lookup_options: LookupOptions,
+    ) -> Result<AuthLookup, LookupError> {
+        let mut answers = Vec::new();
+        let mut names = HashSet::new();
+        let mut name = name.clone();
+        while names.len() <= MAX_ALIASES && names.insert(name.clone()) {
+            if !answers.is_empty() && !self.origin().zone_of(&name) {
+                break;
+            }
+            let authority = match self.get_authority_for(&name, rtype).await {
+                Ok(authority) => authority,
+                Err(e) if answers.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            let records = authority.records().await;
+            match zone_lookup::lookup(&records, authority.origin(), &name, rtype) {
+                ZoneLookup::Answer(record_set) => {
+                    // The records of the targets of MX, NS and SRV records go along as usual.
+                    let additionals = match authority.lookup(&name, rtype, lookup_options).await {
+                        Ok(mut lookup) if record_set.name() == &Name::from(&name) => {
+                            lookup.take_additionals()
+                        }
+                        _ => None,
+                    };
+                    answers.push(record_set);
+                    let answers = LookupRecords::many(lookup_options, answers);
+                    return Ok(AuthLookup::answers(answers, additionals));
+                }
+                ZoneLookup::Alias { records, target } => {
+                    answers.extend(records);
+                    name = target;
+                }
+                ZoneLookup::NoData if answers.is_empty() => return Err(LookupError::NameExists),
+                ZoneLookup::NxDomain if answers.is_empty() => {
+                    return Err(LookupError::from(ResponseCode::NXDomain))
+                }
+                ZoneLookup::NoData | ZoneLookup::NxDomain => break,
+            }
+        }
+        Ok(AuthLookup::answers(
+            LookupRecords::many(lookup_options, answers),
+            None,
+        ))
+    }
+
+    /// Authority answering the `rtype` records of `name`.
+    ///
+    /// The DS records of the labels whose owners sign their zones are answered by the origin, the
+    /// parent of those zones.
+    async fn get_authority_for(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+    ) -> Result<Arc<InMemoryAuthority>, LookupError> {
+        if rtype == RecordType::DS && !self.origin_zone.owns(name) {
+            if let Some(authority) = self.get_delegation_authority(name).await? {
+                return Ok(authority);
+            }
+        }
+        self.get_authority(name).await
+    }
+
+    /// Origin zone with the DS records committed to by the Name-Token of the label of `name`,
+    /// when `name` is the apex of a zone signed by its owner.
+    async fn get_delegation_authority(
+        &self,
+        name: &LowerName,
+    ) -> Result<Option<Arc<InMemoryAuthority>>, LookupError> {
+        let (Some(token_label), Some(zone_name)) =
+            (self.extract_token_label(name), self.extract_zone_name(name))
+        else {
+            return Ok(None);
+        };
+        if *name != LowerName::new(&zone_name) || !self.label_policy.allows(&token_label) {
+            return Ok(None);
+        }
+        let Some(dns_nostr_token) = self.get_token(&token_label).await? else {
+            return Ok(None);
+        };
+        if dns_nostr_token.ds_records.is_empty() {
+            return Ok(None);
+        }
+        let ds_records = dns_nostr_token.ds_records.into_iter().map(|ds| {
+            let rdata = RData::DNSSEC(DNSSECRData::DS(ds));
+            Record::from_rdata(zone_name.clone(), self.origin_zone.soa.ttl, rdata)
+        });
+        self.create_origin_authority(ds_records).map(Some)
+    }
+
+    /// Authority serving `name`, either the origin zone or the zone of a Name-Token label.
+    ///
+    /// Fails with NXDOMAIN when no valid Name-Token holds the label, and with SERVFAIL when the
+    /// zone cannot be fetched from the relays or decoded, so resolvers do not cache transient
+    /// failures as the absence of the name.
+    async fn get_authority(&self, name: &LowerName) -> Result<Arc<InMemoryAuthority>, LookupError> {
+        if self.origin_zone.owns(name) {
+            return self.get_origin_authority();
+        }
+        self.get_zone_authority(name).await
+    }
+
+    /// Origin zone, its SOA serial being the height of the next block to index, or the serial of
+    /// the zone transferred to the secondaries.
+    fn get_origin_authority(&self) -> Result<Arc<InMemoryAuthority>, LookupError> {
+        self.create_origin_authority([])
+    }
+
+    /// Origin zone holding `extra_records` too.
+    fn create_origin_authority(
+        &self,
+        extra_records: impl IntoIterator<Item = Record>,
+    ) -> Result<Arc<InMemoryAuthority>, LookupError> {
+        // The SOA follows the zone transferred to the secondaries, when there is one.
+        let transfer_serial = self.zone_transfers.as_ref().and_then(ZoneTransfers::serial);
+        let mut serial = transfer_serial.unwrap_or_else(|| {
+            u32::try_from(self.dns_nostr_token_repository.next_block_height()).unwrap_or(u32::MAX)
+        });
+        if self.zone_signer.is_some() {
+            // Signing increments the serial of the zone.
+            serial = serial.wrapping_sub(1);
+        }
+        let mut records = self.origin_zone.records(serial);
+        for record in extra_records {
+            insert_record(&mut records, record);
+        }
+        let mut authority = InMemoryAuthority::new(
+            self.origin_zone.origin.clone(),
+            records,
+            ZoneType::Primary,
+            false,
+        )
+        .map_err(|e| {
+            error!(zone = %self.zone, error = %e, "failed to create authority");
+            server_failure()
+        })?;
+        self.sign(&mut authority)?;
+        Ok(Arc::new(authority))
+    }
+
+    /// Sign `authority` when DNSSEC is enabled.
+    fn sign(&self, authority: &mut InMemoryAuthority) -> Result<(), LookupError> {
+        let Some(zone_signer) = &self.zone_signer else {
+            return Ok(());
+        };
+        zone_signer.sign(authority).map_err(|e| {
+            error!(zone = %authority.origin(), error = %e, "failed to sign zone");
+            server_failure()
+        })
+    }
+
+    /// Zone of the Name-Token label of `name`, from the cache while the Name-Token stays in the
+    /// same output and its zone event is the latest one.
+    async fn get_zone_authority(
+        &self,
+        name: &LowerName,
+    ) -> Result<Arc<InMemoryAuthority>, LookupError> {
+        let nx_domain = || LookupError::from(ResponseCode::NXDomain);
+        let token_label = self.extract_token_label(name).ok_or_else(nx_domain)?;
+        let zone_name = self.extract_zone_name(name).ok_or_else(nx_domain)?;
+        if !self.label_policy.allows(&token_label) {
+            return Err(nx_domain());
+        }
+        let dns_nostr_token = self.get_token(&token_label).await?.ok_or_else(nx_domain)?;
+        let Some((authority, _)) = self.get_token_zone(&dns_nostr_token, &zone_name).await? else {
+            // The label exists as a Name-Token, but its owner has not published a zone yet.
+            return Err(if *name == LowerName::new(&zone_name) {
+                LookupError::NameExists
+            } else {
+                nx_domain()
+            });
+        };
+        // The zones of owners committing to their keys are served with the owner's signatures.
+        let is_owner_signed = !dns_nostr_token.ds_records.is_empty();
+        Ok(remember_owner_signed_zone(authority, is_owner_signed))
+    }
+
+    /// Zone `zone_name` published by the owner of `dns_nostr_token`, with the zone event it was
+    /// parsed from, or `None` when a quorum of relays confirmed that the owner has not published
+    /// a zone yet. The cached zone is kept when the relays cannot tell.
+    async fn get_token_zone(
+        &self,
+        dns_nostr_token: &DnsNostrToken,
+        zone_name: &Name,
+    ) -> Result<Option<(Arc<InMemoryAuthority>, nostr_sdk::Event)>, TokenZoneError> {
+        let token_label = &dns_nostr_token.label;
+        let latest_event_id = self
             .nostr_events_repository
-            .get_last_text_note_from_pubkey(dns_nostr_token.nostr_pubkey)
-            .await?;
-        Some(last_text_note.content)
+            .latest_zone_event_id(dns_nostr_token.nostr_pubkey, token_label);
+        let fresh_zone = self.zone_cache.get(
+            token_label,
+            &dns_nostr_token.outpoint,
+            latest_event_id.as_ref(),
+        );
+        if let Some((authority, zone_event)) = fresh_zone {
+            metrics().observe_cache_lookup(CacheKind::Zone, true);
+            debug!(zone = %zone_name, event = %zone_event.id, "served cached zone");
+            return Ok(Some((authority, zone_event)));
+        }
+        let start = Instant::now();
+        let zone_event = self
+            .nostr_events_repository
+            .get_zone_event(dns_nostr_token.nostr_pubkey, token_label)
+            .await
+            .map_err(|e| {
+                warn!(zone = %zone_name, error = %e, "failed to fetch zone");
+                TokenZoneError::Unavailable
+            })?;
+        metrics().observe_lookup(LookupStage::Relay, start);
+        let Some(zone_event) = zone_event else {
+            self.zone_cache.invalidate(token_label);
+            return Ok(None);
+        };
+        let is_owner_signed = !dns_nostr_token.ds_records.is_empty();
+        let cached_authority =
+            self.zone_cache
+                .revalidate(token_label, &dns_nostr_token.outpoint, &zone_event.id);
+        metrics().observe_cache_lookup(CacheKind::Zone, cached_authority.is_some());
+        debug!(
+            zone = %zone_name,
+            event = %zone_event.id,
+            is_cached = cached_authority.is_some(),
+            "found zone event"
+        );
+        if let Some(authority) = cached_authority {
+            return Ok(Some((authority, zone_event)));
+        }
+        let start = Instant::now();
+        let records = self
+            .zone_decoders
+            .decode(&zone_event, zone_name)
+            .map_err(|e| {
+                warn!(event = %zone_event.id, error = %e, "failed to decode zone");
+                TokenZoneError::Invalid
+            })?;
+        let records = self.zone_policy.scope(zone_name, records);
+        let ttl = zone_ttl(&records);
+        let mut authority =
+            InMemoryAuthority::new(zone_name.clone(), records, ZoneType::Primary, false).map_err(
+                |e| {
+                    warn!(zone = %zone_name, error = %e, "failed to create authority");
+                    TokenZoneError::Invalid
+                },
+            )?;
+        if !is_owner_signed {
+            self.sign(&mut authority)
+                .map_err(|_| TokenZoneError::Unavailable)?;
+        }
+        metrics().observe_lookup(LookupStage::Parse, start);
+        let authority = Arc::new(authority);
+        self.zone_cache.insert(
+            token_label,
+            dns_nostr_token.outpoint,
+            zone_event.clone(),
+            authority.clone(),
+            ttl,
+        );
+        Ok(Some((authority, zone_event)))
     }
 
     /// Check if the domain name has the shape "[<subdomain>.]<label>.<oringin>."
@@ -179,8 +911,7 @@ impl<GetTokenT: GetDnsNostrToken> NostrAuthority<GetTokenT> {
         let query_name = Name::from(name);
         let raw_label = query_name
             .iter()
-            .skip((query_name.num_labels() - self.origin().num_labels() - 1).into())
-            .next()?;
+            .nth((query_name.num_labels() - self.origin().num_labels() - 1).into())?;
         Label::from_raw_bytes(raw_label).ok()
     }
 
@@ -205,25 +936,672 @@ impl<GetTokenT: GetDnsNostrToken> NostrAuthority<GetTokenT> {
     }
 }
 
+/// Keep `authority` as the zone answering the request being handled when its owner signs it.
+fn remember_owner_signed_zone(
+    authority: Arc<InMemoryAuthority>,
+    is_owner_signed: bool,
+) -> Arc<InMemoryAuthority> {
+    if is_owner_signed {
+        let _ = OWNER_SIGNED_ZONE.try_with(|owner_signed_zone| {
+            *owner_signed_zone.borrow_mut() = Some(authority.clone());
+        });
+    }
+    authority
+}
+
+/// Why the zone of a Name-Token label cannot be served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenZoneError {
+    /// The relays cannot be reached or the zone cannot be signed, so it may be served later.
+    Unavailable,
+
+    /// The zone event of the label does not hold a zone that can be served.
+    Invalid,
+}
+
+impl From<TokenZoneError> for LookupError {
+    fn from(_: TokenZoneError) -> Self {
+        server_failure()
+    }
+}
+
+/// Lookup error answered with SERVFAIL, see `report_server_failure`.
+fn server_failure() -> LookupError {
+    report_server_failure();
+    LookupError::from(ResponseCode::ServFail)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::dns_nostr_token::DnsNostrToken;
+    use crate::{
+        name_token_repository::IndexerError, origin_zone::NameServer,
+        zone_events_database::ZoneEventsDatabase, zone_signer::SigningAlgorithm,
+    };
+    use hickory_server::proto::rr::{
+        dnssec::{rdata::DS, Algorithm, DigestType, KeyPair, SupportedAlgorithms},
+        RData,
+    };
 
-    struct GetDnsNostrTokenStub {}
+    /// Name-Tokens of the labels of `keys`, owned by their Nostr keys and committing to the
+    /// `ds_records`.
+    #[derive(Default)]
+    struct GetDnsNostrTokenStub {
+        keys: std::collections::HashMap<String, nostr_sdk::Keys>,
+        ds_records: Vec<DS>,
+    }
+
+    impl GetDnsNostrTokenStub {
+        fn new(labels: &[&str]) -> Self {
+            let keys = labels
+                .iter()
+                .map(|label| (label.to_string(), nostr_sdk::Keys::generate()))
+                .collect();
+            Self {
+                keys,
+                ds_records: vec![],
+            }
+        }
+
+        fn with_ds_records(mut self, ds_records: Vec<DS>) -> Self {
+            self.ds_records = ds_records;
+            self
+        }
+    }
 
     impl GetDnsNostrToken for GetDnsNostrTokenStub {
-        async fn get_token(&self, _label: &Label) -> Option<DnsNostrToken> {
-            None
+        async fn get_token(&self, label: &Label) -> Result<Option<DnsNostrToken>, IndexerError> {
+            let Some(keys) = self.keys.get(&label.to_ascii()) else {
+                return Ok(None);
+            };
+            Ok(Some(DnsNostrToken {
+                label: label.clone(),
+                nostr_pubkey: keys.public_key(),
+                outpoint: bitcoin::OutPoint::null(),
+                ds_records: self.ds_records.clone(),
+            }))
+        }
+
+        async fn get_tokens(&self) -> Result<Vec<DnsNostrToken>, IndexerError> {
+            let mut dns_nostr_tokens = vec![];
+            for label in self.keys.keys() {
+                let label = Label::from_ascii(label).unwrap();
+                dns_nostr_tokens.extend(self.get_token(&label).await?);
+            }
+            Ok(dns_nostr_tokens)
+        }
+
+        fn next_block_height(&self) -> u64 {
+            42
+        }
+
+        fn generation(&self) -> u64 {
+            0
+        }
+    }
+
+    /// SOA of the zones published by the labels, cached for 300 seconds.
+    const LABEL_SOA: &str = "@ 3600 IN SOA ns hostmaster 1 3600 600 86400 300\n";
+
+    /// Zone event of `label` publishing `zone_file`, signed by `keys` at `created_at`.
+    fn label_zone_event(
+        label: &str,
+        zone_file: &str,
+        created_at: u64,
+        keys: &nostr_sdk::Keys,
+    ) -> nostr_sdk::Event {
+        use hickory_server::proto::serialize::txt::Parser;
+
+        let zone_name = Name::from_ascii(format!("{}.nostr.dns.name.", label)).unwrap();
+        let (_, records) = Parser::new(zone_file, None, Some(zone_name))
+            .parse()
+            .unwrap();
+        NostrEventsRepository::new(vec![])
+            .zone_event_builder(
+                &Label::from_ascii(label).unwrap(),
+                wire_record_tags(&records).unwrap(),
+            )
+            .custom_created_at(nostr_sdk::Timestamp::from(created_at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    /// Authority of the origin "nostr.dns.name." whose labels publish the `zones` files after
+    /// [`LABEL_SOA`], with the database the zone events are served from.
+    async fn label_zones_authority(
+        zones: &[(&str, &str)],
+    ) -> (NostrAuthority<GetDnsNostrTokenStub>, ZoneEventsDatabase) {
+        let labels: Vec<_> = zones.iter().map(|(label, _)| *label).collect();
+        let dns_nostr_token_repository = GetDnsNostrTokenStub::new(&labels);
+        // Without relays, the zones are served from the database.
+        let zone_events_database = ZoneEventsDatabase::create(":memory:".as_ref())
+            .await
+            .unwrap();
+        for (label, zone_file) in zones {
+            let zone_file = format!("{}{}", LABEL_SOA, zone_file);
+            let keys = &dns_nostr_token_repository.keys[*label];
+            let zone_event = label_zone_event(label, &zone_file, 1_000, keys);
+            zone_events_database.save_event(&zone_event).await.unwrap();
+        }
+        let nostr_events_repository = NostrEventsRepository::new(vec![]).with_zone_events_database(
+            zone_events_database.clone(),
+            std::time::Duration::from_secs(3600),
+        );
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            dns_nostr_token_repository,
+            nostr_events_repository,
+        );
+        (authority, zone_events_database)
+    }
+
+    /// Address of the A record of `name` served by `authority`.
+    async fn lookup_address(
+        authority: &NostrAuthority<GetDnsNostrTokenStub>,
+        name: &str,
+    ) -> String {
+        let name = name.parse().unwrap();
+        let lookup = authority
+            .lookup(&name, RecordType::A, LookupOptions::default())
+            .await
+            .unwrap();
+        let address = lookup
+            .iter()
+            .find_map(|record| record.data()?.as_a().copied());
+        address.unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_aliases() {
+        let (authority, _) = label_zones_authority(&[
+            (
+                "alice",
+                "www 300 IN CNAME bob.nostr.dns.name.\n* 300 IN CNAME www\n",
+            ),
+            ("bob", "@ 300 IN A 192.0.2.2\n"),
+        ])
+        .await;
+
+        let name = "a.b.alice.nostr.dns.name.".parse().unwrap();
+        let lookup = authority
+            .lookup(&name, RecordType::A, LookupOptions::default())
+            .await
+            .unwrap();
+        let answers = lookup
+            .iter()
+            .map(|record| (record.name().to_string(), record.record_type()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            answers,
+            vec![
+                ("a.b.alice.nostr.dns.name.".into(), RecordType::CNAME),
+                ("www.alice.nostr.dns.name.".into(), RecordType::CNAME),
+                ("bob.nostr.dns.name.".into(), RecordType::A),
+            ]
+        );
+
+        let name = "bob.nostr.dns.name.".parse().unwrap();
+        let lookup = authority
+            .lookup(&name, RecordType::TXT, LookupOptions::default())
+            .await;
+        assert!(lookup.is_err_and(|e| e.is_name_exists()));
+    }
+
+    #[tokio::test]
+    async fn test_zone_cache_ttl() {
+        let (authority, zone_events_database) = label_zones_authority(&[
+            ("alice", "@ 300 IN A 192.0.2.1\n"),
+            ("bob", "@ 300 IN A 192.0.2.1\n"),
+        ])
+        .await;
+        let keys = &authority.dns_nostr_token_repository.keys;
+        // The zone of alice expires right away, the one of bob after 300 seconds.
+        let alice_soa = "@ 3600 IN SOA ns hostmaster 1 3600 600 86400 0\n";
+        let zone_event = label_zone_event(
+            "alice",
+            &format!("{}@ 300 IN A 192.0.2.1\n", alice_soa),
+            1_500,
+            &keys["alice"],
+        );
+        zone_events_database.save_event(&zone_event).await.unwrap();
+        assert_eq!(
+            lookup_address(&authority, "alice.nostr.dns.name.").await,
+            "192.0.2.1"
+        );
+        assert_eq!(
+            lookup_address(&authority, "bob.nostr.dns.name.").await,
+            "192.0.2.1"
+        );
+
+        for (label, soa) in [("alice", alice_soa), ("bob", LABEL_SOA)] {
+            let zone_file = format!("{}@ 300 IN A 192.0.2.2\n", soa);
+            let zone_event = label_zone_event(label, &zone_file, 2_000, &keys[label]);
+            zone_events_database.save_event(&zone_event).await.unwrap();
         }
+        // The expired zone is fetched again, the fresh one is served without a fetch.
+        assert_eq!(
+            lookup_address(&authority, "alice.nostr.dns.name.").await,
+            "192.0.2.2"
+        );
+        assert_eq!(
+            lookup_address(&authority, "bob.nostr.dns.name.").await,
+            "192.0.2.1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_zone_event() {
+        // The zone of alice is cached for 300 seconds.
+        let (authority, _) = label_zones_authority(&[("alice", "@ 300 IN A 192.0.2.1\n")]).await;
+        assert_eq!(
+            lookup_address(&authority, "alice.nostr.dns.name.").await,
+            "192.0.2.1"
+        );
+
+        let dns_nostr_tokens = authority.dns_nostr_token_repository.get_tokens().await;
+        authority
+            .nostr_events_repository
+            .follow(&dns_nostr_tokens.unwrap())
+            .await;
+        let zone_file = format!("{}@ 300 IN A 192.0.2.2\n", LABEL_SOA);
+        let keys = &authority.dns_nostr_token_repository.keys["alice"];
+        let zone_event = label_zone_event("alice", &zone_file, 2_000, keys);
+        authority
+            .nostr_events_repository
+            .receive_event(&zone_event)
+            .await;
+        // The streamed zone is served before the cached one expires.
+        assert_eq!(
+            lookup_address(&authority, "alice.nostr.dns.name.").await,
+            "192.0.2.2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        use hickory_server::proto::op::{Header, LowerQuery, Query};
+
+        let zone_transfers =
+            ZoneTransfers::new().with_allowed_networks(vec!["192.0.2.0/24".parse().unwrap()]);
+        let (authority, _) = label_zones_authority(&[
+            (
+                "alice",
+                "@ 300 IN NS ns.example.com.\nwww 300 IN A 192.0.2.1\n",
+            ),
+            ("bob", "@ 300 IN A 192.0.2.2\n"),
+        ])
+        .await;
+        let authority = authority.with_zone_transfers(zone_transfers);
+        assert!(authority.is_axfr_allowed());
+
+        let header = Header::new();
+        let query = LowerQuery::from(Query::query(
+            "nostr.dns.name.".parse().unwrap(),
+            RecordType::AXFR,
+        ));
+        let transfer = |src: &str, protocol| {
+            let request = RequestInfo::new(src.parse().unwrap(), protocol, &header, &query);
+            authority.search(request, LookupOptions::default())
+        };
+        let refused = |lookup: Result<AuthLookup, LookupError>| {
+            matches!(
+                lookup,
+                Err(LookupError::ResponseCode(ResponseCode::Refused))
+            )
+        };
+        assert!(refused(transfer("198.51.100.1:53", Protocol::Tcp).await));
+        assert!(refused(transfer("192.0.2.53:53", Protocol::Udp).await));
+
+        let lookup = transfer("192.0.2.53:53", Protocol::Tcp).await.unwrap();
+        let records = lookup
+            .iter()
+            .map(|record| (record.name().to_string(), record.record_type()))
+            .collect::<Vec<_>>();
+        let origin = "nostr.dns.name.".to_string();
+        assert_eq!(records.first(), Some(&(origin.clone(), RecordType::SOA)));
+        assert_eq!(records.last(), Some(&(origin, RecordType::SOA)));
+        assert!(records.contains(&("www.alice.nostr.dns.name.".into(), RecordType::A)));
+        assert!(records.contains(&("bob.nostr.dns.name.".into(), RecordType::A)));
+        // The SOA and NS records of the labels do not cut the origin zone.
+        assert_eq!(
+            records
+                .iter()
+                .filter(|(_, rtype)| matches!(rtype, RecordType::SOA | RecordType::NS))
+                .count(),
+            2
+        );
+
+        // The origin SOA follows the serial of the transferred zone.
+        let serial = authority.zone_transfers.as_ref().unwrap().serial();
+        let soa = authority.soa().await.unwrap();
+        let soa_serial = soa
+            .iter()
+            .find_map(|record| record.data()?.as_soa())
+            .map(|soa| soa.serial());
+        assert_eq!(soa_serial, serial);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_invalid_zone() {
+        use hickory_server::proto::op::{Header, LowerQuery, Query};
+
+        let (authority, zone_events_database) = label_zones_authority(&[
+            ("alice", "www 300 IN A 192.0.2.1\n"),
+            ("bob", "www 300 IN A 192.0.2.2\n"),
+        ])
+        .await;
+        let invalid_record = nostr_sdk::Tag::custom(nostr_sdk::TagKind::custom("rr"), ["zz"]);
+        let invalid_zone_event = authority
+            .nostr_events_repository
+            .zone_event_builder(&Label::from_ascii("bob").unwrap(), vec![invalid_record])
+            .custom_created_at(nostr_sdk::Timestamp::from(2_000))
+            .sign_with_keys(&authority.dns_nostr_token_repository.keys["bob"])
+            .unwrap();
+        zone_events_database
+            .save_event(&invalid_zone_event)
+            .await
+            .unwrap();
+        let zone_transfers =
+            ZoneTransfers::new().with_allowed_networks(vec!["192.0.2.0/24".parse().unwrap()]);
+        let authority = authority.with_zone_transfers(zone_transfers);
+
+        // The zone of bob cannot be decoded, the one of alice is transferred anyway.
+        authority.refresh_transfer_zone().await;
+        let header = Header::new();
+        let query = LowerQuery::from(Query::query(
+            "nostr.dns.name.".parse().unwrap(),
+            RecordType::AXFR,
+        ));
+        let request = RequestInfo::new(
+            "192.0.2.53:53".parse().unwrap(),
+            Protocol::Tcp,
+            &header,
+            &query,
+        );
+        let lookup = authority
+            .search(request, LookupOptions::default())
+            .await
+            .unwrap();
+        let names = lookup
+            .iter()
+            .map(|record| record.name().to_string())
+            .collect::<HashSet<_>>();
+        assert!(names.contains("www.alice.nostr.dns.name."));
+        assert!(!names
+            .iter()
+            .any(|name| name.ends_with("bob.nostr.dns.name.")));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_unavailable_zone() {
+        let dns_nostr_token_repository = GetDnsNostrTokenStub::new(&["alice", "bob"]);
+        let zone_events_database = ZoneEventsDatabase::create(":memory:".as_ref())
+            .await
+            .unwrap();
+        let zone_file = format!("{}www 300 IN A 192.0.2.1\n", LABEL_SOA);
+        let keys = &dns_nostr_token_repository.keys["alice"];
+        let zone_event = label_zone_event("alice", &zone_file, 1_000, keys);
+        zone_events_database.save_event(&zone_event).await.unwrap();
+        let nostr_events_repository =
+            NostrEventsRepository::new(vec!["ws://127.0.0.1:1".to_string()])
+                .with_zone_events_database(
+                    zone_events_database,
+                    std::time::Duration::from_secs(3600),
+                );
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            dns_nostr_token_repository,
+            nostr_events_repository,
+        )
+        .with_zone_transfers(ZoneTransfers::new());
+
+        // The relay is down and bob has no stored zone, so bob is not left out of a new zone.
+        authority.refresh_transfer_zone().await;
+        assert!(authority.zone_transfers.as_ref().unwrap().zone().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_owner_ds() {
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            GetDnsNostrTokenStub::new(&["alice"]).with_ds_records(vec![DS::new(
+                12345,
+                Algorithm::ED25519,
+                DigestType::SHA256,
+                vec![0xab; 32],
+            )]),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
+        );
+
+        let name = "alice.nostr.dns.name.".parse().unwrap();
+        let ds = authority
+            .lookup(&name, RecordType::DS, LookupOptions::default())
+            .await
+            .unwrap();
+        let ds = ds
+            .iter()
+            .find_map(|record| record.data()?.as_dnssec()?.as_ds())
+            .unwrap();
+        assert_eq!(ds.key_tag(), 12345);
+    }
+
+    #[tokio::test]
+    async fn test_origin_soa() {
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
+        );
+
+        let soa = authority.soa().await.unwrap();
+        let soa = soa.iter().next().unwrap();
+        assert_eq!(soa.name(), &"nostr.dns.name.".parse::<Name>().unwrap());
+        assert_eq!(soa.data().and_then(RData::as_soa).unwrap().serial(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_signed_origin_soa() {
+        let pkcs8_key = KeyPair::generate_pkcs8(Algorithm::ED25519).unwrap();
+        let zone_signer = ZoneSigner::new(
+            "nostr.dns.name.".parse().unwrap(),
+            SigningAlgorithm::Ed25519,
+            pkcs8_key,
+        )
+        .unwrap();
+        let mut origin_zone = OriginZone::new("nostr.dns.name.".parse().unwrap());
+        origin_zone.name_servers.push(NameServer {
+            name: "ns1.nostr.dns.name.".parse().unwrap(),
+            addresses: vec!["192.0.2.1".parse().unwrap()],
+        });
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
+        )
+        .with_origin_zone(origin_zone)
+        .with_zone_signer(zone_signer);
+
+        let lookup_options = LookupOptions::for_dnssec(true, SupportedAlgorithms::all());
+        let soa = authority.soa_secure(lookup_options).await.unwrap();
+        let soa_serial = soa
+            .iter()
+            .find_map(|record| record.data()?.as_soa())
+            .map(|soa| soa.serial());
+        assert_eq!(soa_serial, Some(42));
+        assert!(soa
+            .iter()
+            .any(|record| record.record_type() == RecordType::RRSIG));
+
+        let name = "token.nostr.dns.name.".parse().unwrap();
+        let nsec = authority.get_nsec_records(&name, lookup_options).await;
+        assert!(nsec.unwrap().iter().next().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_negative_responses() {
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
+        );
+
+        let name = "token.nostr.dns.name.".parse().unwrap();
+        let lookup = authority
+            .lookup(&name, RecordType::A, LookupOptions::default())
+            .await;
+        assert!(lookup.is_err_and(|e| e.is_nx_domain()));
+
+        let lookup = authority
+            .lookup(authority.origin(), RecordType::A, LookupOptions::default())
+            .await;
+        assert!(lookup.is_err_and(|e| e.is_name_exists()));
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_zone() {
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            GetDnsNostrTokenStub::new(&["alice"]),
+            NostrEventsRepository::new(vec!["ws://127.0.0.1:1".to_string()]),
+        );
+
+        // The relay is down, the zone of alice is unknown rather than missing.
+        let name = "alice.nostr.dns.name.".parse().unwrap();
+        let lookup = authority
+            .lookup(&name, RecordType::A, LookupOptions::default())
+            .await;
+        assert!(matches!(
+            lookup,
+            Err(LookupError::ResponseCode(ResponseCode::ServFail))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_zone_names() {
+        use crate::zone_update::ZoneUpdates;
+        use hickory_server::proto::{
+            op::update_message,
+            rr::RecordSet,
+            serialize::binary::{BinDecodable, BinEncodable},
+        };
+
+        let update = |zone_name: &str| {
+            let zone_name: Name = zone_name.parse().unwrap();
+            let name = Name::from_ascii("www").unwrap().append_domain(&zone_name);
+            let record =
+                Record::from_rdata(name.unwrap(), 300, RData::A("1.2.3.4".parse().unwrap()));
+            let mut record_set = RecordSet::new(record.name(), RecordType::A, 0);
+            record_set.insert(record, 0);
+            let message = update_message::create(record_set, zone_name, false);
+            MessageRequest::from_bytes(&message.to_bytes().unwrap()).unwrap()
+        };
+        let authority = NostrAuthority::new(
+            "nostr.dns.name.".parse().unwrap(),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
+        );
+        let result = authority.update(&update("token.nostr.dns.name.")).await;
+        assert_eq!(result, Err(ResponseCode::NotImp));
+
+        let zone_events_database = ZoneEventsDatabase::create(":memory:".as_ref())
+            .await
+            .unwrap();
+        let authority = authority.with_zone_updates(ZoneUpdates::new(zone_events_database));
+        let result = authority.update(&update("nostr.dns.name.")).await;
+        assert_eq!(result, Err(ResponseCode::NotAuth));
+        let result = authority.update(&update("sub.token.nostr.dns.name.")).await;
+        assert_eq!(result, Err(ResponseCode::NotAuth));
+        // No Name-Token holds the label.
+        let result = authority.update(&update("token.nostr.dns.name.")).await;
+        assert_eq!(result, Err(ResponseCode::NotAuth));
+    }
+
+    #[tokio::test]
+    async fn test_queued_updates() {
+        use crate::zone_update::{LabelUpdateKeys, ZoneUpdates, MAX_CLOCK_SKEW};
+        use hickory_server::proto::{
+            op::update_message,
+            rr::{
+                dnssec::{rdata::tsig::TsigAlgorithm, tsig::TSigner},
+                RecordSet,
+            },
+            serialize::binary::{BinDecodable, BinEncodable},
+        };
+
+        let zone_name: Name = "alice.nostr.dns.name.".parse().unwrap();
+        let label = Label::from_ascii("alice").unwrap();
+        let tsig_signer = TSigner::new(
+            b"owner secret".to_vec(),
+            TsigAlgorithm::HmacSha256,
+            "alice-key.".parse().unwrap(),
+            MAX_CLOCK_SKEW,
+        )
+        .unwrap();
+        let update = |name: &str, address: &str| {
+            let name = Name::from_ascii(name).unwrap();
+            let record = Record::from_rdata(name, 300, RData::A(address.parse().unwrap()));
+            let mut record_set = RecordSet::new(record.name(), RecordType::A, 0);
+            record_set.insert(record, 0);
+            let mut message = update_message::create(record_set, zone_name.clone(), false);
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap();
+            message
+                .finalize(&tsig_signer, now.as_secs() as u32)
+                .unwrap();
+            MessageRequest::from_bytes(&message.to_bytes().unwrap()).unwrap()
+        };
+
+        let (authority, zone_events_database) =
+            label_zones_authority(&[("alice", "www 300 IN A 192.0.2.1\n")]).await;
+        let label_keys = authority.dns_nostr_token_repository.keys["alice"].clone();
+        // The operator holds a TSIG key of the label, but not its Nostr key.
+        let zone_updates = ZoneUpdates::new(zone_events_database.clone()).with_label_keys(
+            &label,
+            LabelUpdateKeys {
+                tsig_signers: vec![tsig_signer.clone()],
+                nostr_keys: None,
+            },
+        );
+        let authority = authority.with_zone_updates(zone_updates);
+
+        let result = authority
+            .update(&update("mail.alice.nostr.dns.name.", "192.0.2.2"))
+            .await;
+        assert_eq!(result, Ok(true));
+        let result = authority
+            .update(&update("ftp.alice.nostr.dns.name.", "192.0.2.3"))
+            .await;
+        assert_eq!(result, Ok(true));
+
+        // The second update applies to the zone queued by the first one.
+        let pending_zone_events = zone_events_database
+            .get_unsigned_events(&label_keys.public_key())
+            .await
+            .unwrap();
+        assert_eq!(pending_zone_events.len(), 1);
+        let records = decode_wire_record_tags(&pending_zone_events[0].tags).unwrap();
+        let names: HashSet<_> = records
+            .values()
+            .filter(|record_set| record_set.record_type() == RecordType::A)
+            .map(|record_set| record_set.name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            HashSet::from([
+                "www.alice.nostr.dns.name.".to_string(),
+                "mail.alice.nostr.dns.name.".to_string(),
+                "ftp.alice.nostr.dns.name.".to_string(),
+            ])
+        );
     }
 
     #[test]
     fn test_is_valid_dns_nostr_name() {
         let authority = NostrAuthority::new(
             "nostr.dns.name.".parse().unwrap(),
-            GetDnsNostrTokenStub {},
-            NostrEventsRepository::new("ws://localhost:8080".to_string()),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
         );
 
         let name = "token.nostr.dns.name.".parse().unwrap();
@@ -243,8 +1621,8 @@ mod tests {
     fn test_is_dns_nostr_name() {
         let authority = NostrAuthority::new(
             "nostr.dns.name.".parse().unwrap(),
-            GetDnsNostrTokenStub {},
-            NostrEventsRepository::new("ws://localhost:8080".to_string()),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
         );
 
         let name = "token.nostr.dns.name.".parse().unwrap();
@@ -264,8 +1642,8 @@ mod tests {
     fn test_extract_token_label() {
         let authority = NostrAuthority::new(
             "nostr.dns.name.".parse().unwrap(),
-            GetDnsNostrTokenStub {},
-            NostrEventsRepository::new("ws://localhost:8080".to_string()),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
         );
 
         let name = "token.nostr.dns.name.".parse().unwrap();
@@ -289,8 +1667,8 @@ mod tests {
     fn test_extract_zone_name() {
         let authority = NostrAuthority::new(
             "nostr.dns.name.".parse().unwrap(),
-            GetDnsNostrTokenStub {},
-            NostrEventsRepository::new("ws://localhost:8080".to_string()),
+            GetDnsNostrTokenStub::default(),
+            NostrEventsRepository::new(vec!["ws://localhost:8080".to_string()]),
         );
 
         let name = "token.nostr.dns.name.".parse().unwrap();
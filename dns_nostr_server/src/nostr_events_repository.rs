@@ -1,31 +1,857 @@
-use std::time::Duration;
+use crate::{
+    dns_nostr_token::DnsNostrToken, metrics::metrics, zone_events_database::ZoneEventsDatabase,
+};
+use hickory_server::proto::rr::domain::Label;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, OnceCell},
+    task::JoinSet,
+};
+use tracing::{debug, error, info_span, warn, Instrument};
 
+/// Default kind of the events publishing a zone, an addressable event whose `d` tag is the label.
+pub const DEFAULT_ZONE_EVENT_KIND: u16 = 30053;
+
+/// Time to wait for the first connection to the relays before the first fetch.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Initial wait between reconnection attempts to a relay, grown by the client on repeated failures.
+const RECONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time to wait for a quorum of the relays to answer a fetch.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Authors followed by each subscription, as relays limit the size of the filters.
+const AUTHORS_PER_SUBSCRIPTION: usize = 256;
+
+/// Source of the Nostr events of the zones, backed by a long-lived connection to a set of relays.
+///
+/// The websockets are opened on the first fetch and shared by all the following ones. Dropped
+/// connections are reopened in the background, backing off while a relay keeps failing.
+///
+/// Every fetch queries the relays concurrently and merges their answers, so a single relay that
+/// is down or lagging behind does not break nor roll back the resolution of the zones.
+///
+/// The zones of the followed Name-Tokens are kept up to date by standing subscriptions, so they
+/// are served from memory and a new zone takes effect as soon as the relays relay it. Zones of
+/// the other owners are fetched on demand.
+///
+/// With a zone events database, the last zone event of every owner is also persisted and served
+/// while none of the relays can be reached, as long as it is not older than the max staleness.
+#[derive(Clone)]
 pub struct NostrEventsRepository {
-    nostr_relay_url: String,
+    nostr_relay_urls: Vec<String>,
+    quorum: usize,
+    zone_event_kind: nostr_sdk::Kind,
+    text_note_fallback: bool,
+    nostr_client: nostr_sdk::Client,
+    connected: Arc<OnceCell<()>>,
+    zone_event_store: Arc<Mutex<ZoneEventStore>>,
+    zone_events_database: Option<ZoneEventsDatabase>,
+    max_staleness: Duration,
 }
 
 impl NostrEventsRepository {
-    pub fn new(nostr_relay_url: String) -> Self {
-        NostrEventsRepository { nostr_relay_url }
+    /// Repository fetching from `nostr_relay_urls`, waiting for a majority of them on each fetch.
+    pub fn new(nostr_relay_urls: Vec<String>) -> Self {
+        NostrEventsRepository {
+            quorum: nostr_relay_urls.len() / 2 + 1,
+            nostr_relay_urls,
+            zone_event_kind: nostr_sdk::Kind::from(DEFAULT_ZONE_EVENT_KIND),
+            text_note_fallback: false,
+            nostr_client: nostr_sdk::Client::default(),
+            connected: Arc::new(OnceCell::new()),
+            zone_event_store: Arc::new(Mutex::new(ZoneEventStore::default())),
+            zone_events_database: None,
+            max_staleness: Duration::ZERO,
+        }
+    }
+
+    /// Return the merged answers as soon as `quorum` relays have answered a fetch.
+    pub fn with_quorum(mut self, quorum: usize) -> Self {
+        self.quorum = quorum.clamp(1, self.nostr_relay_urls.len().max(1));
+        self
+    }
+
+    /// Read the zones from addressable events of `zone_event_kind` instead of the default kind.
+    pub fn with_zone_event_kind(mut self, zone_event_kind: nostr_sdk::Kind) -> Self {
+        self.zone_event_kind = zone_event_kind;
+        self
     }
 
-    pub async fn get_last_text_note_from_pubkey(
+    /// Fall back to the last text note of the owner when it has not published a zone event,
+    /// as zones were published before the dedicated kind.
+    pub fn with_text_note_fallback(mut self, text_note_fallback: bool) -> Self {
+        self.text_note_fallback = text_note_fallback;
+        self
+    }
+
+    /// Persist the zone events in `zone_events_database`, serving the ones confirmed by the
+    /// relays at most `max_staleness` ago while the relays are unreachable.
+    pub fn with_zone_events_database(
+        mut self,
+        zone_events_database: ZoneEventsDatabase,
+        max_staleness: Duration,
+    ) -> Self {
+        self.zone_events_database = Some(zone_events_database);
+        self.max_staleness = max_staleness;
+        self
+    }
+
+    /// Latest event publishing the zone of `label`, signed by `pubkey`, or `None` when a quorum
+    /// of relays confirmed that the owner has not published it.
+    pub async fn get_zone_event(
         &self,
         pubkey: nostr_sdk::PublicKey,
-    ) -> Option<nostr_sdk::Event> {
-        let nostr_client = nostr_sdk::Client::default();
-        nostr_client.add_relay(&self.nostr_relay_url).await.unwrap();
-        nostr_client.connect().await;
+        label: &Label,
+    ) -> Result<Option<nostr_sdk::Event>, RelaysUnreachable> {
+        let identifier = zone_event_identifier(label);
+        let stored_zone_event = self
+            .zone_event_store
+            .lock()
+            .unwrap()
+            .zone_event(&pubkey, &identifier);
+        if stored_zone_event.is_some() {
+            return Ok(stored_zone_event);
+        }
 
         let filter = nostr_sdk::Filter::new()
             .author(pubkey)
-            .kind(nostr_sdk::Kind::TextNote);
-        let text_notes = nostr_client
-            .fetch_events(filter, Duration::from_secs(10))
+            .kind(self.zone_event_kind)
+            .identifier(identifier.clone())
+            .limit(1);
+        let relay_answers = self.fetch_events(filter).await;
+        let zone_event = relay_answers.as_ref().and_then(|relay_answers| {
+            let zone_events = relay_answers.events.values().filter(|event| {
+                event.kind == self.zone_event_kind && event.tags.identifier() == Some(&identifier)
+            });
+            latest_authentic_event(pubkey, zone_events.cloned())
+        });
+        let zone_event = match zone_event {
+            Some(zone_event) => {
+                self.store_fetched_event(&zone_event).await;
+                Ok(Some(zone_event))
+            }
+            None if relay_answers.is_some_and(|relay_answers| relay_answers.is_quorum) => Ok(None),
+            None => self
+                .get_stale_event(&pubkey, self.zone_event_kind, &identifier)
+                .await
+                .map(Some)
+                .ok_or(RelaysUnreachable),
+        };
+        if !self.text_note_fallback {
+            return zone_event;
+        }
+        match zone_event {
+            Ok(Some(zone_event)) => Ok(Some(zone_event)),
+            Ok(None) => self.get_last_text_note_from_pubkey(pubkey).await,
+            Err(e) => match self.get_last_text_note_from_pubkey(pubkey).await {
+                Ok(Some(text_note)) => Ok(Some(text_note)),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Id of the latest event publishing the zone of `label`, signed by `pubkey`, streamed by the
+    /// subscriptions, or `None` when the owner is not followed or has not published it.
+    pub fn latest_zone_event_id(
+        &self,
+        pubkey: nostr_sdk::PublicKey,
+        label: &Label,
+    ) -> Option<nostr_sdk::EventId> {
+        let zone_event_store = self.zone_event_store.lock().unwrap();
+        let zone_event = zone_event_store.zone_event(&pubkey, &zone_event_identifier(label));
+        let zone_event = match zone_event {
+            None if self.text_note_fallback => zone_event_store.text_note(&pubkey),
+            zone_event => zone_event,
+        };
+        zone_event.map(|zone_event| zone_event.id)
+    }
+
+    async fn get_last_text_note_from_pubkey(
+        &self,
+        pubkey: nostr_sdk::PublicKey,
+    ) -> Result<Option<nostr_sdk::Event>, RelaysUnreachable> {
+        let stored_text_note = self.zone_event_store.lock().unwrap().text_note(&pubkey);
+        if stored_text_note.is_some() {
+            return Ok(stored_text_note);
+        }
+
+        let filter = nostr_sdk::Filter::new()
+            .author(pubkey)
+            .kind(nostr_sdk::Kind::TextNote)
+            .limit(1);
+        let relay_answers = self.fetch_events(filter).await;
+        let text_note = relay_answers.as_ref().and_then(|relay_answers| {
+            let text_notes = relay_answers
+                .events
+                .values()
+                .filter(|event| event.kind == nostr_sdk::Kind::TextNote);
+            latest_authentic_event(pubkey, text_notes.cloned())
+        });
+        match text_note {
+            Some(text_note) => {
+                self.store_fetched_event(&text_note).await;
+                Ok(Some(text_note))
+            }
+            None if relay_answers.is_some_and(|relay_answers| relay_answers.is_quorum) => Ok(None),
+            None => self
+                .get_stale_event(&pubkey, nostr_sdk::Kind::TextNote, "")
+                .await
+                .map(Some)
+                .ok_or(RelaysUnreachable),
+        }
+    }
+
+    /// Kind of the events the zones are read from.
+    pub fn zone_event_kind(&self) -> nostr_sdk::Kind {
+        self.zone_event_kind
+    }
+
+    /// Builder of the zone event of `label` holding `tags`, of the kind the zones are read from.
+    pub fn zone_event_builder(
+        &self,
+        label: &Label,
+        tags: Vec<nostr_sdk::Tag>,
+    ) -> nostr_sdk::EventBuilder {
+        nostr_sdk::EventBuilder::new(self.zone_event_kind, "")
+            .tag(nostr_sdk::Tag::identifier(zone_event_identifier(label)))
+            .tags(tags)
+    }
+
+    /// Publish `zone_event` to the relays, serving it right away. Fails when no relay accepted it.
+    pub async fn publish_zone_event(
+        &self,
+        zone_event: &nostr_sdk::Event,
+    ) -> Result<(), RelaysUnreachable> {
+        let nostr_client = self.connected_client().await;
+        let output = nostr_client.send_event(zone_event).await.map_err(|e| {
+            warn!(event = %zone_event.id, error = %e, "failed to publish event");
+            RelaysUnreachable
+        })?;
+        for (nostr_relay_url, e) in &output.failed {
+            metrics().observe_relay_error(nostr_relay_url.as_str());
+            warn!(
+                relay = %nostr_relay_url,
+                event = %zone_event.id,
+                error = %e,
+                "relay rejected event"
+            );
+        }
+        if output.success.is_empty() {
+            return Err(RelaysUnreachable);
+        }
+        self.store_fetched_event(zone_event).await;
+        Ok(())
+    }
+
+    /// Keep a fetched authentic event, in memory for a followed owner, which the subscriptions
+    /// keep up to date, and in the zone events database for any owner.
+    async fn store_fetched_event(&self, event: &nostr_sdk::Event) {
+        {
+            let mut zone_event_store = self.zone_event_store.lock().unwrap();
+            if zone_event_store.follows(&event.pubkey) {
+                zone_event_store.insert(event.clone(), self.zone_event_kind);
+            }
+        }
+        if let Some(zone_events_database) = &self.zone_events_database {
+            if let Err(e) = zone_events_database.save_event(event).await {
+                warn!(event = %event.id, error = %e, "failed to save event");
+            }
+        }
+    }
+
+    /// Persisted event of `pubkey`, `kind` and `identifier`, if it is not too stale and the
+    /// database can be read.
+    async fn get_stale_event(
+        &self,
+        pubkey: &nostr_sdk::PublicKey,
+        kind: nostr_sdk::Kind,
+        identifier: &str,
+    ) -> Option<nostr_sdk::Event> {
+        let zone_events_database = self.zone_events_database.as_ref()?;
+        zone_events_database
+            .get_event(pubkey, kind, identifier, self.max_staleness)
+            .await
+            .unwrap_or_else(|e| {
+                warn!(pubkey = %pubkey, error = %e, "failed to read stored event");
+                None
+            })
+    }
+
+    /// Subscribe to the zones of the owners of `dns_nostr_tokens`, replacing the previous
+    /// subscriptions and dropping the zones of the owners no longer followed.
+    pub async fn follow(&self, dns_nostr_tokens: &[DnsNostrToken]) {
+        let nostr_client = self.connected_client().await;
+        let pubkeys: HashSet<_> = dns_nostr_tokens
+            .iter()
+            .map(|dns_nostr_token| dns_nostr_token.nostr_pubkey)
+            .collect();
+        let previous_num_subscriptions = self
+            .zone_event_store
+            .lock()
+            .unwrap()
+            .follow(pubkeys.clone());
+
+        let pubkeys: Vec<_> = pubkeys.into_iter().collect();
+        let chunks: Vec<_> = pubkeys.chunks(AUTHORS_PER_SUBSCRIPTION).collect();
+        for (i, authors) in chunks.iter().enumerate() {
+            let zone_filter = nostr_sdk::Filter::new()
+                .authors(authors.iter().copied())
+                .kind(self.zone_event_kind);
+            subscribe(nostr_client, zone_subscription_id(i), zone_filter).await;
+            if self.text_note_fallback {
+                // Only new text notes are streamed, the last one is fetched on demand.
+                let text_note_filter = nostr_sdk::Filter::new()
+                    .authors(authors.iter().copied())
+                    .kind(nostr_sdk::Kind::TextNote)
+                    .since(nostr_sdk::Timestamp::now());
+                subscribe(nostr_client, text_note_subscription_id(i), text_note_filter).await;
+            }
+        }
+        for i in chunks.len()..previous_num_subscriptions {
+            nostr_client.unsubscribe(&zone_subscription_id(i)).await;
+            nostr_client
+                .unsubscribe(&text_note_subscription_id(i))
+                .await;
+        }
+        self.zone_event_store
+            .lock()
+            .unwrap()
+            .set_num_subscriptions(chunks.len());
+    }
+
+    /// Events matching `filter` on the relays, or `None` when no relay answered.
+    ///
+    /// The relays are queried concurrently and the answers collected until `quorum` of them have
+    /// answered or the fetch times out, whatever comes first. A relay answers by sending all its
+    /// stored events up to EOSE, one that is down or times out does not count.
+    async fn fetch_events(&self, filter: nostr_sdk::Filter) -> Option<RelayAnswers> {
+        let nostr_client = self.connected_client().await;
+
+        let mut fetches = JoinSet::new();
+        for nostr_relay_url in &self.nostr_relay_urls {
+            let nostr_client = nostr_client.clone();
+            let nostr_relay_url = nostr_relay_url.clone();
+            let filter = filter.clone();
+            let span = info_span!("relay_fetch", relay = %nostr_relay_url);
+            let fetch = async move {
+                let relay_events = match nostr_client.relay(&nostr_relay_url).await {
+                    Ok(relay) => fetch_relay_events(&relay, filter).await,
+                    Err(e) => Err(e.to_string()),
+                };
+                match &relay_events {
+                    Ok(relay_events) => debug!(events = relay_events.len(), "fetched events"),
+                    Err(e) => warn!(error = %e, "failed to fetch events"),
+                }
+                (nostr_relay_url, relay_events)
+            };
+            fetches.spawn(fetch.instrument(span));
+        }
+
+        let mut events = HashMap::new();
+        let mut answers = 0;
+        let _ = tokio::time::timeout(FETCH_TIMEOUT, async {
+            while let Some(fetch) = fetches.join_next().await {
+                let Ok((nostr_relay_url, relay_events)) = fetch else {
+                    continue;
+                };
+                let Ok(relay_events) = relay_events else {
+                    metrics().observe_relay_error(&nostr_relay_url);
+                    continue;
+                };
+                for event in relay_events {
+                    events.insert(event.id, event);
+                }
+                answers += 1;
+                if answers >= self.quorum {
+                    break;
+                }
+            }
+        })
+        .await;
+        (answers > 0).then_some(RelayAnswers {
+            events,
+            is_quorum: answers >= self.quorum,
+        })
+    }
+
+    /// Client connected to the relays, adding them and opening the connections on the first call.
+    async fn connected_client(&self) -> &nostr_sdk::Client {
+        self.connected
+            .get_or_init(|| async {
+                let relay_options = nostr_sdk::RelayOptions::new()
+                    .reconnect(true)
+                    .retry_interval(RECONNECT_RETRY_INTERVAL)
+                    .adjust_retry_interval(true);
+                for nostr_relay_url in &self.nostr_relay_urls {
+                    if let Err(e) = self
+                        .nostr_client
+                        .pool()
+                        .add_relay(nostr_relay_url, relay_options.clone())
+                        .await
+                    {
+                        error!(relay = %nostr_relay_url, error = %e, "failed to add relay");
+                    }
+                }
+                self.spawn_notifications_handler();
+                self.nostr_client.connect().await;
+                self.nostr_client
+                    .wait_for_connection(CONNECTION_TIMEOUT)
+                    .await;
+            })
+            .await;
+        &self.nostr_client
+    }
+
+    /// Store the events streamed by the subscriptions as they arrive.
+    fn spawn_notifications_handler(&self) {
+        let mut notifications = self.nostr_client.notifications();
+        let nostr_events_repository = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match notifications.recv().await {
+                    Ok(nostr_sdk::RelayPoolNotification::Event { event, .. }) => {
+                        nostr_events_repository.receive_event(&event).await;
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Keep `event`, streamed by a subscription, when it is the latest zone event or text note of
+    /// a followed owner. The cached zone it replaces is no longer served from then on.
+    pub async fn receive_event(&self, event: &nostr_sdk::Event) {
+        let is_latest_event = {
+            let mut zone_event_store = self.zone_event_store.lock().unwrap();
+            zone_event_store.follows(&event.pubkey)
+                && zone_event_store.insert(event.clone(), self.zone_event_kind)
+        };
+        if let (true, Some(zone_events_database)) = (is_latest_event, &self.zone_events_database) {
+            if let Err(e) = zone_events_database.save_event(event).await {
+                warn!(event = %event.id, error = %e, "failed to save event");
+            }
+        }
+    }
+}
+
+/// Events answered by the relays to a fetch, deduplicated by id.
+struct RelayAnswers {
+    events: HashMap<nostr_sdk::EventId, nostr_sdk::Event>,
+    /// Whether a quorum of relays answered, confirming that the events missing were not published.
+    is_quorum: bool,
+}
+
+/// None of the relays answered a fetch and no stored event could stand in for their answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelaysUnreachable;
+
+impl Display for RelaysUnreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no relay answered")
+    }
+}
+
+impl std::error::Error for RelaysUnreachable {}
+
+async fn subscribe(
+    nostr_client: &nostr_sdk::Client,
+    subscription_id: nostr_sdk::SubscriptionId,
+    filter: nostr_sdk::Filter,
+) {
+    if let Err(e) = nostr_client
+        .subscribe_with_id(subscription_id.clone(), filter, None)
+        .await
+    {
+        warn!(subscription = %subscription_id, error = %e, "failed to subscribe");
+    }
+}
+
+/// Events matching `filter` on `relay`, failing unless it is connected and sends EOSE before the
+/// fetch times out.
+///
+/// Unlike [`nostr_sdk::Relay::fetch_events`], which answers with the events received so far when
+/// the relay is unreachable or times out, an answer here means the relay sent all its stored
+/// events.
+async fn fetch_relay_events(
+    relay: &nostr_sdk::Relay,
+    filter: nostr_sdk::Filter,
+) -> Result<Vec<nostr_sdk::Event>, String> {
+    if !relay.is_connected() {
+        return Err(format!("relay not connected ({})", relay.status()));
+    }
+    let mut notifications = relay.notifications();
+    let subscription_id = nostr_sdk::SubscriptionId::generate();
+    let auto_close = nostr_sdk::SubscribeAutoCloseOptions::default()
+        .exit_policy(nostr_sdk::pool::relay::ReqExitPolicy::ExitOnEOSE)
+        .timeout(Some(FETCH_TIMEOUT));
+    relay
+        .subscribe_with_id(
+            subscription_id.clone(),
+            filter,
+            nostr_sdk::SubscribeOptions::default().close_on(Some(auto_close)),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut events = Vec::new();
+    let answer = tokio::time::timeout(FETCH_TIMEOUT, async {
+        loop {
+            let message = match notifications.recv().await {
+                Ok(nostr_sdk::pool::RelayNotification::Message { message }) => message,
+                Ok(nostr_sdk::pool::RelayNotification::RelayStatus {
+                    status:
+                        nostr_sdk::RelayStatus::Disconnected
+                        | nostr_sdk::RelayStatus::Terminated
+                        | nostr_sdk::RelayStatus::Banned,
+                }) => return Err("relay disconnected".to_string()),
+                Ok(nostr_sdk::pool::RelayNotification::Shutdown) | Err(RecvError::Closed) => {
+                    return Err("relay shut down".to_string());
+                }
+                Err(RecvError::Lagged(_)) => return Err("missed relay messages".to_string()),
+                Ok(_) => continue,
+            };
+            match message {
+                nostr_sdk::RelayMessage::Event {
+                    subscription_id: id,
+                    event,
+                } if *id == subscription_id => events.push(event.into_owned()),
+                nostr_sdk::RelayMessage::EndOfStoredEvents(id) if *id == subscription_id => {
+                    return Ok(());
+                }
+                nostr_sdk::RelayMessage::Closed {
+                    subscription_id: id,
+                    message,
+                } if *id == subscription_id => {
+                    return Err(format!("relay closed the subscription: {}", message));
+                }
+                _ => continue,
+            }
+        }
+    })
+    .await;
+    match answer {
+        Ok(Ok(())) => Ok(events),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err("timed out before EOSE".to_string()),
+    }
+}
+
+fn zone_subscription_id(i: usize) -> nostr_sdk::SubscriptionId {
+    nostr_sdk::SubscriptionId::new(format!("dns-nostr-zones-{}", i))
+}
+
+fn text_note_subscription_id(i: usize) -> nostr_sdk::SubscriptionId {
+    nostr_sdk::SubscriptionId::new(format!("dns-nostr-text-notes-{}", i))
+}
+
+/// Latest zone events of the followed owners, kept up to date by the subscriptions.
+#[derive(Default)]
+struct ZoneEventStore {
+    followed_pubkeys: HashSet<nostr_sdk::PublicKey>,
+    num_subscriptions: usize,
+    zone_events: HashMap<(nostr_sdk::PublicKey, String), nostr_sdk::Event>,
+    text_notes: HashMap<nostr_sdk::PublicKey, nostr_sdk::Event>,
+}
+
+impl ZoneEventStore {
+    fn follows(&self, pubkey: &nostr_sdk::PublicKey) -> bool {
+        self.followed_pubkeys.contains(pubkey)
+    }
+
+    /// Follow `pubkeys` instead of the current owners, returning the number of subscriptions
+    /// of the current ones.
+    fn follow(&mut self, pubkeys: HashSet<nostr_sdk::PublicKey>) -> usize {
+        self.zone_events
+            .retain(|(pubkey, _), _| pubkeys.contains(pubkey));
+        self.text_notes.retain(|pubkey, _| pubkeys.contains(pubkey));
+        self.followed_pubkeys = pubkeys;
+        self.num_subscriptions
+    }
+
+    fn set_num_subscriptions(&mut self, num_subscriptions: usize) {
+        self.num_subscriptions = num_subscriptions;
+    }
+
+    fn zone_event(
+        &self,
+        pubkey: &nostr_sdk::PublicKey,
+        identifier: &str,
+    ) -> Option<nostr_sdk::Event> {
+        self.zone_events
+            .get(&(*pubkey, identifier.to_string()))
+            .cloned()
+    }
+
+    fn text_note(&self, pubkey: &nostr_sdk::PublicKey) -> Option<nostr_sdk::Event> {
+        self.text_notes.get(pubkey).cloned()
+    }
+
+    /// Keep `event` if it is authentic and newer than the stored one, returning whether it is
+    /// the latest one, either kept or already stored.
+    fn insert(&mut self, event: nostr_sdk::Event, zone_event_kind: nostr_sdk::Kind) -> bool {
+        if event.kind == zone_event_kind {
+            let Some(identifier) = event.tags.identifier() else {
+                return false;
+            };
+            let key = (event.pubkey, identifier.to_string());
+            insert_latest_event(&mut self.zone_events, key, event)
+        } else if event.kind == nostr_sdk::Kind::TextNote {
+            insert_latest_event(&mut self.text_notes, event.pubkey, event)
+        } else {
+            false
+        }
+    }
+}
+
+fn insert_latest_event<K: Eq + Hash>(
+    events: &mut HashMap<K, nostr_sdk::Event>,
+    key: K,
+    event: nostr_sdk::Event,
+) -> bool {
+    let (pubkey, event_id) = (event.pubkey, event.id);
+    let stored_event = events.get(&key).cloned();
+    match latest_authentic_event(pubkey, stored_event.into_iter().chain([event])) {
+        Some(latest_event) if latest_event.id == event_id => {
+            events.insert(key, latest_event);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Value of the `d` tag of the zone event of `label`, the label in lowercase ASCII.
+pub fn zone_event_identifier(label: &Label) -> String {
+    label.to_lowercase().to_ascii()
+}
+
+/// Newest of the `events` signed by `pubkey`.
+///
+/// Events whose author is not `pubkey` or whose id or signature do not verify are discarded, so a
+/// relay cannot serve a forged event. Events created at the same second are ordered by id, the
+/// lowest one winning as for replaceable events in NIP-01, so every relay set gives the same
+/// answer.
+fn latest_authentic_event(
+    pubkey: nostr_sdk::PublicKey,
+    events: impl IntoIterator<Item = nostr_sdk::Event>,
+) -> Option<nostr_sdk::Event> {
+    events
+        .into_iter()
+        .filter(|event| event.pubkey == pubkey && event.verify().is_ok())
+        .max_by(|a, b| {
+            a.created_at
+                .cmp(&b.created_at)
+                .then_with(|| b.id.cmp(&a.id))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag, Timestamp};
+
+    fn text_note(keys: &Keys, content: &str, created_at: u64) -> nostr_sdk::Event {
+        EventBuilder::text_note(content)
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    fn zone_event(keys: &Keys, label: &str, created_at: u64) -> nostr_sdk::Event {
+        EventBuilder::new(Kind::from(DEFAULT_ZONE_EVENT_KIND), "")
+            .tag(Tag::identifier(label))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_zone_event_identifier() {
+        assert_eq!(
+            zone_event_identifier(&Label::from_utf8("Alice").unwrap()),
+            "alice"
+        );
+        assert_eq!(
+            zone_event_identifier(&Label::from_utf8("café").unwrap()),
+            "xn--caf-dma"
+        );
+    }
+
+    #[test]
+    fn test_latest_authentic_event_selects_newest() {
+        let keys = Keys::generate();
+        let old_note = text_note(&keys, "old", 1_000);
+        let new_note = text_note(&keys, "new", 2_000);
+
+        let latest = latest_authentic_event(keys.public_key(), [new_note.clone(), old_note]);
+        assert_eq!(latest, Some(new_note));
+    }
+
+    #[test]
+    fn test_latest_authentic_event_breaks_ties_by_id() {
+        let keys = Keys::generate();
+        let note_a = text_note(&keys, "a", 1_000);
+        let note_b = text_note(&keys, "b", 1_000);
+        let lowest_id_note = if note_a.id < note_b.id {
+            note_a.clone()
+        } else {
+            note_b.clone()
+        };
+
+        let latest = latest_authentic_event(keys.public_key(), [note_a.clone(), note_b.clone()]);
+        assert_eq!(latest, Some(lowest_id_note.clone()));
+        let latest = latest_authentic_event(keys.public_key(), [note_b, note_a]);
+        assert_eq!(latest, Some(lowest_id_note));
+    }
+
+    #[test]
+    fn test_latest_authentic_event_rejects_forged_events() {
+        let keys = Keys::generate();
+        let note = text_note(&keys, "authentic", 1_000);
+        let mut forged_note = text_note(&keys, "authentic", 2_000);
+        forged_note.content = "forged".into();
+        let other_author_note = text_note(&Keys::generate(), "other", 3_000);
+
+        let latest = latest_authentic_event(
+            keys.public_key(),
+            [note.clone(), forged_note, other_author_note],
+        );
+        assert_eq!(latest, Some(note));
+    }
+
+    #[test]
+    fn test_zone_event_store_keeps_latest_event() {
+        let keys = Keys::generate();
+        let kind = Kind::from(DEFAULT_ZONE_EVENT_KIND);
+        let mut store = ZoneEventStore::default();
+        store.follow(HashSet::from([keys.public_key()]));
+
+        let new_zone_event = zone_event(&keys, "alice", 2_000);
+        assert!(store.insert(new_zone_event.clone(), kind));
+        assert!(!store.insert(zone_event(&keys, "alice", 1_000), kind));
+        assert!(store.insert(zone_event(&keys, "bob", 1_000), kind));
+        assert_eq!(
+            store.zone_event(&keys.public_key(), "alice"),
+            Some(new_zone_event)
+        );
+
+        let mut forged_zone_event = zone_event(&keys, "alice", 3_000);
+        forged_zone_event.content = "forged".into();
+        assert!(!store.insert(forged_zone_event, kind));
+
+        let note = text_note(&keys, "zone", 1_000);
+        assert!(store.insert(note.clone(), kind));
+        assert_eq!(store.text_note(&keys.public_key()), Some(note));
+    }
+
+    #[test]
+    fn test_zone_event_store_drops_unfollowed_owners() {
+        let alice_keys = Keys::generate();
+        let bob_keys = Keys::generate();
+        let kind = Kind::from(DEFAULT_ZONE_EVENT_KIND);
+        let mut store = ZoneEventStore::default();
+        store.follow(HashSet::from([
+            alice_keys.public_key(),
+            bob_keys.public_key(),
+        ]));
+        store.insert(zone_event(&alice_keys, "alice", 1_000), kind);
+        store.insert(zone_event(&bob_keys, "bob", 1_000), kind);
+
+        store.follow(HashSet::from([alice_keys.public_key()]));
+        assert!(store.follows(&alice_keys.public_key()));
+        assert!(!store.follows(&bob_keys.public_key()));
+        assert!(store
+            .zone_event(&alice_keys.public_key(), "alice")
+            .is_some());
+        assert!(store.zone_event(&bob_keys.public_key(), "bob").is_none());
+    }
+
+    /// Url of a relay holding no event, answering every subscription with EOSE.
+    async fn empty_relay() -> String {
+        use futures_util::{SinkExt, StreamExt};
+        use nostr_sdk::JsonUtil;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut websocket = tokio_tungstenite::accept_async(stream).await?;
+                    while let Some(message) = websocket.next().await {
+                        let Ok(nostr_sdk::ClientMessage::Req {
+                            subscription_id, ..
+                        }) = nostr_sdk::ClientMessage::from_json(message?.into_data())
+                        else {
+                            continue;
+                        };
+                        let eose = nostr_sdk::RelayMessage::eose(subscription_id.into_owned());
+                        websocket.send(Message::text(eose.as_json())).await?;
+                    }
+                    Ok::<_, tokio_tungstenite::tungstenite::Error>(())
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_quorum() {
+        let relay_urls = vec![empty_relay().await, "ws://127.0.0.1:1".into()];
+        let keys = Keys::generate();
+        let label = Label::from_utf8("alice").unwrap();
+
+        // Only one of the two relays answers, too few to confirm that alice has no zone.
+        let nostr_events_repository = NostrEventsRepository::new(relay_urls.clone());
+        let zone_event = nostr_events_repository
+            .get_zone_event(keys.public_key(), &label)
+            .await;
+        assert_eq!(zone_event, Err(RelaysUnreachable));
+
+        let nostr_events_repository = NostrEventsRepository::new(relay_urls).with_quorum(1);
+        let zone_event = nostr_events_repository
+            .get_zone_event(keys.public_key(), &label)
+            .await;
+        assert_eq!(zone_event, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_relays_do_not_answer() {
+        let nostr_events_repository = NostrEventsRepository::new(vec!["ws://127.0.0.1:1".into()]);
+        let events = nostr_events_repository
+            .fetch_events(nostr_sdk::Filter::new().kind(Kind::TextNote))
+            .await;
+        assert!(events.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stale_zone_event_during_outage() {
+        let zone_events_database = ZoneEventsDatabase::create(":memory:".as_ref())
             .await
-            .ok()?;
-        let last_text_note = text_notes.first()?;
+            .unwrap();
+        let nostr_events_repository = NostrEventsRepository::new(vec!["ws://127.0.0.1:1".into()])
+            .with_zone_events_database(zone_events_database.clone(), Duration::from_secs(3600));
+        let keys = Keys::generate();
+        let label = Label::from_utf8("alice").unwrap();
+        let zone_event = zone_event(&keys, "alice", 1_000);
+        zone_events_database.save_event(&zone_event).await.unwrap();
 
-        Some(last_text_note.clone())
+        // The relay is down, the persisted zone event is served in its place.
+        let stale_zone_event = nostr_events_repository
+            .get_zone_event(keys.public_key(), &label)
+            .await;
+        assert_eq!(stale_zone_event, Ok(Some(zone_event)));
+        let bob_zone_event = nostr_events_repository
+            .get_zone_event(keys.public_key(), &Label::from_utf8("bob").unwrap())
+            .await;
+        assert_eq!(bob_zone_event, Err(RelaysUnreachable));
     }
 }
@@ -0,0 +1,170 @@
+use crate::zone_decoder::{insert_record, ZoneRecords};
+use hickory_server::proto::rr::{
+    rdata::{A, AAAA, NS, SOA},
+    LowerName, Name, RData, Record,
+};
+use std::net::IpAddr;
+
+/// Records served by the operator for the origin itself, outside of any Name-Token zone.
+///
+/// The SOA makes the server authoritative for the origin, so resolvers can cache negative
+/// answers, and the NS records with their glue let the parent zone delegate the origin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginZone {
+    pub origin: Name,
+    pub name_servers: Vec<NameServer>,
+    pub soa: OriginSoa,
+}
+
+/// Name server of the origin, with the glue addresses served when it is inside the origin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameServer {
+    pub name: Name,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Fields of the origin SOA. The serial is the indexed block height and the primary name
+/// server the first of the origin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginSoa {
+    /// Mailbox of the operator, e.g. "hostmaster.nostr.dns.name.".
+    pub rname: Name,
+    pub ttl: u32,
+    pub refresh: i32,
+    pub retry: i32,
+    pub expire: i32,
+    pub minimum: u32,
+}
+
+impl OriginZone {
+    /// Origin zone with the default SOA and without name servers.
+    pub fn new(origin: Name) -> Self {
+        let rname = Name::from_ascii("hostmaster")
+            .and_then(|hostmaster| hostmaster.append_domain(&origin))
+            .unwrap_or_else(|_| origin.clone());
+        Self {
+            name_servers: vec![],
+            soa: OriginSoa {
+                rname,
+                ttl: 3600,
+                refresh: 3600,
+                retry: 600,
+                expire: 604800,
+                minimum: 300,
+            },
+            origin,
+        }
+    }
+
+    /// Whether the records of `name` are served by the origin zone instead of a Name-Token zone.
+    pub fn owns(&self, name: &LowerName) -> bool {
+        *name == LowerName::new(&self.origin)
+            || self
+                .name_servers
+                .iter()
+                .any(|name_server| *name == LowerName::new(&name_server.name))
+    }
+
+    /// SOA, NS and glue records of the origin, the SOA having `serial`.
+    pub fn records(&self, serial: u32) -> ZoneRecords {
+        let mname = self
+            .name_servers
+            .first()
+            .map(|name_server| name_server.name.clone())
+            .unwrap_or_else(|| self.origin.clone());
+        let soa = SOA::new(
+            mname,
+            self.soa.rname.clone(),
+            serial,
+            self.soa.refresh,
+            self.soa.retry,
+            self.soa.expire,
+            self.soa.minimum,
+        );
+        let mut records = vec![Record::from_rdata(
+            self.origin.clone(),
+            self.soa.ttl,
+            RData::SOA(soa),
+        )];
+        for name_server in &self.name_servers {
+            records.push(Record::from_rdata(
+                self.origin.clone(),
+                self.soa.ttl,
+                RData::NS(NS(name_server.name.clone())),
+            ));
+            if !self.origin.zone_of(&name_server.name) {
+                continue;
+            }
+            for address in &name_server.addresses {
+                let rdata = match address {
+                    IpAddr::V4(address) => RData::A(A(*address)),
+                    IpAddr::V6(address) => RData::AAAA(AAAA(*address)),
+                };
+                records.push(Record::from_rdata(
+                    name_server.name.clone(),
+                    self.soa.ttl,
+                    rdata,
+                ));
+            }
+        }
+
+        let mut zone_records = ZoneRecords::new();
+        for record in records {
+            insert_record(&mut zone_records, record);
+        }
+        zone_records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::rr::{RecordSet, RecordType, RrKey};
+
+    fn origin_zone() -> OriginZone {
+        let mut origin_zone = OriginZone::new("nostr.dns.name.".parse().unwrap());
+        origin_zone.name_servers = vec![
+            NameServer {
+                name: "ns1.nostr.dns.name.".parse().unwrap(),
+                addresses: vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+            },
+            NameServer {
+                name: "ns.example.com.".parse().unwrap(),
+                addresses: vec!["192.0.2.2".parse().unwrap()],
+            },
+        ];
+        origin_zone
+    }
+
+    fn record_set<'a>(records: &'a ZoneRecords, name: &str, rtype: RecordType) -> &'a RecordSet {
+        let name = LowerName::new(&name.parse().unwrap());
+        &records[&RrKey::new(name, rtype)]
+    }
+
+    #[test]
+    fn test_records() {
+        let records = origin_zone().records(42);
+
+        let soa = record_set(&records, "nostr.dns.name.", RecordType::SOA);
+        let soa = soa.records_without_rrsigs().next().unwrap();
+        let soa = soa.data().and_then(RData::as_soa).unwrap();
+        assert_eq!(soa.serial(), 42);
+        assert_eq!(soa.mname(), &"ns1.nostr.dns.name.".parse().unwrap());
+        assert_eq!(soa.rname(), &"hostmaster.nostr.dns.name.".parse().unwrap());
+
+        let ns = record_set(&records, "nostr.dns.name.", RecordType::NS);
+        assert_eq!(ns.records_without_rrsigs().count(), 2);
+        record_set(&records, "ns1.nostr.dns.name.", RecordType::A);
+        record_set(&records, "ns1.nostr.dns.name.", RecordType::AAAA);
+        assert_eq!(records.len(), 4);
+    }
+
+    #[test]
+    fn test_owns() {
+        let origin_zone = origin_zone();
+        let owns = |name: &str| origin_zone.owns(&LowerName::new(&name.parse().unwrap()));
+        assert!(owns("nostr.dns.name."));
+        assert!(owns("NS1.nostr.dns.name."));
+        assert!(!owns("alice.nostr.dns.name."));
+    }
+}
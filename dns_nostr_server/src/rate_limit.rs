@@ -0,0 +1,315 @@
+use hickory_server::proto::{
+    op::{LowerQuery, ResponseCode},
+    rr::{LowerName, RecordType},
+};
+use ipnet::IpNet;
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+/// Most clients or responses tracked by a rate limiter, so a flood of spoofed sources cannot
+/// exhaust the memory of the server.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Most buckets swept for a new key once a rate limiter is full, so the lock is held briefly.
+const SWEEP_BATCH: usize = 64;
+
+/// Default length of the IPv4 prefixes the clients are grouped by.
+pub const DEFAULT_IPV4_PREFIX_LEN: u8 = 24;
+
+/// Default length of the IPv6 prefixes the clients are grouped by.
+pub const DEFAULT_IPV6_PREFIX_LEN: u8 = 56;
+
+/// Default share of the limited responses sent truncated, one in `slip`.
+pub const DEFAULT_SLIP: u32 = 2;
+
+/// Rate limits of the queries of each source prefix and, as in response-rate-limiting (RRL), of
+/// the identical UDP responses sent to each source prefix.
+///
+/// Queries over the limit are dropped, so random-subdomain floods do not reach the indexer nor
+/// the relays. Responses over the limit are dropped too, but one in `slip` is sent truncated and
+/// empty, so clients whose address is spoofed by an attacker can still retry over TCP.
+pub struct RateLimits {
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+    queries: Option<RateLimiter<IpNet>>,
+    responses: Option<RateLimiter<ResponseKey>>,
+    slip: u32,
+    limited_responses: AtomicU32,
+}
+
+/// Source prefix, response code, type and name an identical response is accounted to.
+type ResponseKey = (IpNet, ResponseCode, RecordType, LowerName);
+
+/// Outcome of the response-rate-limiting of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseLimit {
+    Send,
+    Drop,
+
+    /// Send the response truncated and without records.
+    Slip,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimits {
+    /// No limits, the clients being grouped by the default prefix lengths.
+    pub fn new() -> Self {
+        Self {
+            ipv4_prefix_len: DEFAULT_IPV4_PREFIX_LEN,
+            ipv6_prefix_len: DEFAULT_IPV6_PREFIX_LEN,
+            queries: None,
+            responses: None,
+            slip: DEFAULT_SLIP,
+            limited_responses: AtomicU32::new(0),
+        }
+    }
+
+    /// Group the clients by their IPv4 and IPv6 prefixes of these lengths.
+    pub fn with_prefix_lens(mut self, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> Self {
+        self.ipv4_prefix_len = ipv4_prefix_len;
+        self.ipv6_prefix_len = ipv6_prefix_len;
+        self
+    }
+
+    /// Accept at most `queries_per_second` queries of each source prefix, without a limit when
+    /// zero.
+    pub fn with_query_rate(mut self, queries_per_second: u32) -> Self {
+        self.queries = (queries_per_second > 0).then(|| RateLimiter::new(queries_per_second));
+        self
+    }
+
+    /// Send at most `responses_per_second` identical UDP responses to each source prefix,
+    /// without a limit when zero.
+    pub fn with_response_rate(mut self, responses_per_second: u32) -> Self {
+        self.responses = (responses_per_second > 0).then(|| RateLimiter::new(responses_per_second));
+        self
+    }
+
+    /// Send one in `slip` limited responses truncated, none when zero.
+    pub fn with_slip(mut self, slip: u32) -> Self {
+        self.slip = slip;
+        self
+    }
+
+    /// Whether a query from `src` is within the rate of its source prefix.
+    pub fn allows_query(&self, src: IpAddr) -> bool {
+        let Some(queries) = &self.queries else {
+            return true;
+        };
+        queries.check(self.prefix(src), Instant::now())
+    }
+
+    /// Whether a UDP response of `response_code` to `query` from `src` is sent.
+    ///
+    /// NXDOMAIN responses are accounted to the parent of the queried name, so a flood of random
+    /// subdomains is limited as a whole.
+    pub fn limit_response(
+        &self,
+        src: IpAddr,
+        response_code: ResponseCode,
+        query: &LowerQuery,
+    ) -> ResponseLimit {
+        let Some(responses) = &self.responses else {
+            return ResponseLimit::Send;
+        };
+        let name = match response_code {
+            ResponseCode::NXDomain => query.name().base_name(),
+            _ => query.name().clone(),
+        };
+        let key = (self.prefix(src), response_code, query.query_type(), name);
+        if responses.check(key, Instant::now()) {
+            return ResponseLimit::Send;
+        }
+        let limited_responses = self.limited_responses.fetch_add(1, Ordering::Relaxed);
+        if self.slip > 0 && limited_responses.is_multiple_of(self.slip) {
+            ResponseLimit::Slip
+        } else {
+            ResponseLimit::Drop
+        }
+    }
+
+    /// Prefix of the clients `src` is grouped with.
+    fn prefix(&self, src: IpAddr) -> IpNet {
+        let src = src.to_canonical();
+        let prefix_len = match src {
+            IpAddr::V4(_) => self.ipv4_prefix_len,
+            IpAddr::V6(_) => self.ipv6_prefix_len,
+        };
+        IpNet::new(src, prefix_len)
+            .map(|prefix| prefix.trunc())
+            .unwrap_or_else(|_| IpNet::from(src))
+    }
+}
+
+/// Token buckets of the keys, refilled at `rate` tokens per second up to `rate` tokens.
+///
+/// Once `max_buckets` keys are tracked, a new key sweeps a batch of the oldest ones, forgetting
+/// those whose bucket is full again and moving the others to the back, and evicts the oldest key
+/// when all of them are still limited. The new key is always tracked, so a full table does not
+/// lift the limits.
+struct RateLimiter<K> {
+    rate: u32,
+    max_buckets: usize,
+    buckets: Mutex<Buckets<K>>,
+}
+
+struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+
+    /// Keys of `buckets`, from the oldest one inserted or swept.
+    keys: VecDeque<K>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            max_buckets: MAX_BUCKETS,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                keys: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Take a token of `key` at `now`, whether one was left.
+    fn check(&self, key: K, now: Instant) -> bool {
+        let rate = f64::from(self.rate);
+        let refill = |bucket: &mut Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated_at);
+            bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+            bucket.updated_at = now;
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { buckets, keys } = &mut *buckets;
+        if !buckets.contains_key(&key) {
+            if buckets.len() >= self.max_buckets {
+                // The keys whose bucket is full again are not limited, they can be forgotten.
+                for _ in 0..SWEEP_BATCH.min(keys.len()) {
+                    let Some(swept_key) = keys.pop_front() else {
+                        break;
+                    };
+                    let Some(bucket) = buckets.get_mut(&swept_key) else {
+                        continue;
+                    };
+                    refill(bucket);
+                    if bucket.tokens < rate {
+                        keys.push_back(swept_key);
+                    } else {
+                        buckets.remove(&swept_key);
+                    }
+                }
+                while buckets.len() >= self.max_buckets {
+                    let Some(oldest_key) = keys.pop_front() else {
+                        break;
+                    };
+                    buckets.remove(&oldest_key);
+                }
+            }
+            keys.push_back(key.clone());
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: rate,
+            updated_at: now,
+        });
+        refill(bucket);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::op::Query;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limiter() {
+        let rate_limiter = RateLimiter::new(2);
+        let now = Instant::now();
+        assert!(rate_limiter.check("a", now));
+        assert!(rate_limiter.check("a", now));
+        assert!(!rate_limiter.check("a", now));
+        assert!(rate_limiter.check("b", now));
+        let now = now + Duration::from_millis(500);
+        assert!(rate_limiter.check("a", now));
+        assert!(!rate_limiter.check("a", now));
+    }
+
+    #[test]
+    fn test_full_rate_limiter() {
+        let rate_limiter = RateLimiter {
+            max_buckets: 2,
+            ..RateLimiter::new(1)
+        };
+        let now = Instant::now();
+        assert!(rate_limiter.check("a", now));
+        assert!(rate_limiter.check("b", now));
+        // Every key is limited, the oldest one is evicted and the new one still limited.
+        assert!(rate_limiter.check("c", now));
+        assert!(!rate_limiter.check("c", now));
+        assert!(!rate_limiter.check("b", now));
+        assert_eq!(rate_limiter.buckets.lock().unwrap().buckets.len(), 2);
+
+        // The keys whose bucket is full again are forgotten first.
+        let now = now + Duration::from_secs(1);
+        assert!(rate_limiter.check("b", now));
+        assert!(rate_limiter.check("d", now));
+        assert!(!rate_limiter.check("b", now));
+        assert!(!rate_limiter.check("d", now));
+        let buckets = rate_limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), 2);
+        assert_eq!(buckets.keys.len(), 2);
+    }
+
+    #[test]
+    fn test_query_prefixes() {
+        let rate_limits = RateLimits::new().with_query_rate(1);
+        assert!(rate_limits.allows_query("192.0.2.1".parse().unwrap()));
+        assert!(!rate_limits.allows_query("192.0.2.2".parse().unwrap()));
+        assert!(!rate_limits.allows_query("::ffff:192.0.2.3".parse().unwrap()));
+        assert!(rate_limits.allows_query("198.51.100.1".parse().unwrap()));
+        assert!(rate_limits.allows_query("2001:db8:0:1::1".parse().unwrap()));
+        assert!(!rate_limits.allows_query("2001:db8:0:2::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_response_limits() {
+        let rate_limits = RateLimits::new().with_response_rate(1).with_slip(2);
+        let src = "192.0.2.1".parse().unwrap();
+        let query =
+            |name: &str| LowerQuery::from(Query::query(name.parse().unwrap(), RecordType::A));
+        let nx_domain =
+            |name| rate_limits.limit_response(src, ResponseCode::NXDomain, &query(name));
+        assert_eq!(nx_domain("a.nostr.dns.name."), ResponseLimit::Send);
+        // Random subdomains share the limit of their parent.
+        assert_eq!(nx_domain("b.nostr.dns.name."), ResponseLimit::Slip);
+        assert_eq!(nx_domain("c.nostr.dns.name."), ResponseLimit::Drop);
+        assert_eq!(nx_domain("d.nostr.dns.name."), ResponseLimit::Slip);
+        let response =
+            rate_limits.limit_response(src, ResponseCode::NoError, &query("alice.nostr.dns.name."));
+        assert_eq!(response, ResponseLimit::Send);
+    }
+}
@@ -0,0 +1,298 @@
+use bitcoin::OutPoint;
+use hickory_server::{
+    proto::rr::{domain::Label, RData, RecordType},
+    store::in_memory::InMemoryAuthority,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::zone_decoder::ZoneRecords;
+
+/// Default number of zones cached per origin.
+pub const DEFAULT_ZONE_CACHE_SIZE: usize = 10_000;
+
+/// Parsed zones of the Name-Token labels of an origin.
+///
+/// A zone is served from the cache without asking the relays for as long as its TTL, while the
+/// Name-Token stays in the same output and the subscriptions have not streamed a newer zone event.
+/// Once expired, it is served again for another TTL if the latest zone event of the label is still
+/// the one it was parsed from. When the cache is full,
+/// the zones unused for longer than the negative-caching TTL of their SOA are evicted first.
+pub struct ZoneCache {
+    max_entries: usize,
+    max_age: Duration,
+    entries: Mutex<HashMap<Label, ZoneCacheEntry>>,
+}
+
+struct ZoneCacheEntry {
+    outpoint: OutPoint,
+    zone_event: nostr_sdk::Event,
+    authority: Arc<InMemoryAuthority>,
+    ttl: Duration,
+    fresh_until: Instant,
+    expires_at: Instant,
+    inserted_at: Instant,
+}
+
+impl ZoneCache {
+    /// Cache holding at most `max_entries` zones. A zero size disables the cache.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            max_age: Duration::MAX,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stop serving the zones parsed more than `max_age` ago, e.g. when their signatures are
+    /// about to expire.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
+
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Zone of `label` and the event it was parsed from, while the Name-Token is held by
+    /// `outpoint` and the zone has not outlived its TTL, nor been replaced by `latest_event_id`,
+    /// the latest zone event of the label when known without asking the relays.
+    pub fn get(
+        &self,
+        label: &Label,
+        outpoint: &OutPoint,
+        latest_event_id: Option<&nostr_sdk::EventId>,
+    ) -> Option<(Arc<InMemoryAuthority>, nostr_sdk::Event)> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&label.to_lowercase())?;
+        let now = Instant::now();
+        if entry.outpoint != *outpoint
+            || latest_event_id.is_some_and(|event_id| *event_id != entry.zone_event.id)
+            || entry.fresh_until <= now
+            || entry.inserted_at.elapsed() >= self.max_age
+        {
+            return None;
+        }
+        entry.expires_at = now + entry.ttl;
+        Some((entry.authority.clone(), entry.zone_event.clone()))
+    }
+
+    /// Zone of `label` parsed from `event_id` while the Name-Token is held by `outpoint`,
+    /// served again for its TTL as the latest zone event of the label is still `event_id`.
+    pub fn revalidate(
+        &self,
+        label: &Label,
+        outpoint: &OutPoint,
+        event_id: &nostr_sdk::EventId,
+    ) -> Option<Arc<InMemoryAuthority>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&label.to_lowercase())?;
+        if entry.outpoint != *outpoint
+            || entry.zone_event.id != *event_id
+            || entry.inserted_at.elapsed() >= self.max_age
+        {
+            return None;
+        }
+        entry.fresh_until = Instant::now() + entry.ttl;
+        entry.expires_at = entry.fresh_until;
+        Some(entry.authority.clone())
+    }
+
+    pub fn insert(
+        &self,
+        label: &Label,
+        outpoint: OutPoint,
+        zone_event: nostr_sdk::Event,
+        authority: Arc<InMemoryAuthority>,
+        ttl: Duration,
+    ) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let label = label.to_lowercase();
+        if !entries.contains_key(&label) && entries.len() >= self.max_entries {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if !entries.contains_key(&label) && entries.len() >= self.max_entries {
+            let first_to_expire = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(label, _)| label.clone());
+            if let Some(first_to_expire) = first_to_expire {
+                entries.remove(&first_to_expire);
+            }
+        }
+        entries.insert(
+            label,
+            ZoneCacheEntry {
+                outpoint,
+                zone_event,
+                authority,
+                ttl,
+                fresh_until: Instant::now() + ttl,
+                expires_at: Instant::now() + ttl,
+                inserted_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Drop the zone of `label`, e.g. when a newer zone event is published.
+    pub fn invalidate(&self, label: &Label) {
+        self.entries.lock().unwrap().remove(&label.to_lowercase());
+    }
+}
+
+/// Time a zone can be cached, the smallest of the TTL and the minimum field of its SOA.
+pub fn zone_ttl(records: &ZoneRecords) -> Duration {
+    let soa = records
+        .iter()
+        .filter(|(key, _)| key.record_type == RecordType::SOA)
+        .flat_map(|(_, record_set)| record_set.records_without_rrsigs())
+        .find_map(|record| match record.data() {
+            Some(RData::SOA(soa)) => Some((record.ttl(), soa.minimum())),
+            _ => None,
+        });
+    match soa {
+        Some((ttl, minimum)) => Duration::from_secs(ttl.min(minimum).into()),
+        None => Duration::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{hashes::Hash, Txid};
+    use hickory_server::{
+        authority::ZoneType,
+        proto::{rr::Name, serialize::txt::Parser},
+    };
+
+    fn zone_records() -> ZoneRecords {
+        let zone_file = "@ 3600 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+                         1 3600 600 86400 300\n\
+                         www 300 IN A 1.2.3.4\n";
+        let zone_name = Name::from_ascii("alice.nostr.dns.name.").unwrap();
+        let (_, records) = Parser::new(zone_file, None, Some(zone_name))
+            .parse()
+            .unwrap();
+        records
+    }
+
+    fn authority() -> Arc<InMemoryAuthority> {
+        let zone_name = Name::from_ascii("alice.nostr.dns.name.").unwrap();
+        Arc::new(
+            InMemoryAuthority::new(zone_name, zone_records(), ZoneType::Primary, false).unwrap(),
+        )
+    }
+
+    fn outpoint(vout: u32) -> OutPoint {
+        OutPoint::new(Txid::all_zeros(), vout)
+    }
+
+    fn zone_event(content: &str) -> nostr_sdk::Event {
+        nostr_sdk::EventBuilder::text_note(content)
+            .sign_with_keys(&nostr_sdk::Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_zone_ttl() {
+        assert_eq!(zone_ttl(&zone_records()), Duration::from_secs(300));
+        assert_eq!(zone_ttl(&ZoneRecords::new()), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_get() {
+        let cache = ZoneCache::new(10);
+        let label = Label::from_utf8("alice").unwrap();
+        let event = zone_event("alice");
+        let ttl = Duration::from_secs(60);
+        cache.insert(&label, outpoint(0), event.clone(), authority(), ttl);
+
+        assert_eq!(
+            cache.get(&label, &outpoint(0), None).map(|(_, e)| e),
+            Some(event.clone())
+        );
+        assert!(cache
+            .get(&Label::from_utf8("ALICE").unwrap(), &outpoint(0), None)
+            .is_some());
+        assert!(cache.get(&label, &outpoint(1), None).is_none());
+        // A newer zone event replaces the cached zone before its TTL runs out.
+        assert!(cache.get(&label, &outpoint(0), Some(&event.id)).is_some());
+        let newer_event = zone_event("alice");
+        assert!(cache
+            .get(&label, &outpoint(0), Some(&newer_event.id))
+            .is_none());
+
+        cache.invalidate(&label);
+        assert!(cache.get(&label, &outpoint(0), None).is_none());
+    }
+
+    #[test]
+    fn test_revalidate() {
+        let cache = ZoneCache::new(10);
+        let label = Label::from_utf8("alice").unwrap();
+        let event = zone_event("alice");
+        cache.insert(
+            &label,
+            outpoint(0),
+            event.clone(),
+            authority(),
+            Duration::ZERO,
+        );
+
+        // Expired right away, the zone is served again only for the same event.
+        assert!(cache.get(&label, &outpoint(0), None).is_none());
+        assert!(cache.revalidate(&label, &outpoint(0), &event.id).is_some());
+        assert!(cache
+            .revalidate(&label, &outpoint(0), &zone_event("bob").id)
+            .is_none());
+        assert!(cache.revalidate(&label, &outpoint(1), &event.id).is_none());
+
+        let ttl = Duration::from_secs(60);
+        cache.insert(&label, outpoint(0), event.clone(), authority(), ttl);
+        assert!(cache.revalidate(&label, &outpoint(0), &event.id).is_some());
+        assert!(cache.get(&label, &outpoint(0), None).is_some());
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut cache = ZoneCache::new(10);
+        cache.set_max_age(Duration::ZERO);
+        let label = Label::from_utf8("alice").unwrap();
+        let event = zone_event("alice");
+        let ttl = Duration::from_secs(60);
+        cache.insert(&label, outpoint(0), event.clone(), authority(), ttl);
+        assert!(cache.get(&label, &outpoint(0), None).is_none());
+        assert!(cache.revalidate(&label, &outpoint(0), &event.id).is_none());
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let cache = ZoneCache::new(2);
+        let labels = ["alice", "bob", "carol"].map(|label| Label::from_utf8(label).unwrap());
+        for (i, label) in labels.iter().enumerate() {
+            let ttl = Duration::from_secs(60 * (i as u64 + 1));
+            cache.insert(label, outpoint(0), zone_event("zone"), authority(), ttl);
+        }
+
+        assert!(cache.get(&labels[0], &outpoint(0), None).is_none());
+        assert!(cache.get(&labels[1], &outpoint(0), None).is_some());
+        assert!(cache.get(&labels[2], &outpoint(0), None).is_some());
+
+        let cache = ZoneCache::new(0);
+        cache.insert(
+            &labels[0],
+            outpoint(0),
+            zone_event("zone"),
+            authority(),
+            Duration::from_secs(60),
+        );
+        assert!(cache.get(&labels[0], &outpoint(0), None).is_none());
+    }
+}
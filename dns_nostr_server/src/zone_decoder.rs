@@ -0,0 +1,500 @@
+use bitcoin::hex::{DisplayHex, FromHex};
+use hickory_server::proto::{
+    error::ProtoError,
+    rr::{dnssec::SupportedAlgorithms, LowerName, Name, Record, RecordSet, RecordType, RrKey},
+    serialize::{
+        binary::{BinDecodable, BinEncodable},
+        txt::{ParseError, Parser},
+    },
+};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+/// Records of a zone, as taken by the `InMemoryAuthority`.
+pub type ZoneRecords = BTreeMap<RrKey, RecordSet>;
+
+/// TTL of the structured records published without one.
+const DEFAULT_TTL: u32 = 3600;
+
+/// Name of the Nostr tags holding a structured record, e.g.
+/// `["record", "www", "A", "1.2.3.4", "300"]`.
+const RECORD_TAG: &str = "record";
+
+/// Name of the Nostr tags holding a record in wire format, e.g. `["rr", "<hex>"]`.
+const WIRE_RECORD_TAG: &str = "rr";
+
+/// Format in which a zone is published in a Nostr event.
+pub trait ZoneDecoder: Send + Sync {
+    /// Whether `event` holds a zone in this format.
+    fn detects(&self, event: &nostr_sdk::Event) -> bool;
+
+    /// Records of the zone `zone_name` held by `event`.
+    fn decode(
+        &self,
+        event: &nostr_sdk::Event,
+        zone_name: &Name,
+    ) -> Result<ZoneRecords, ZoneDecodeError>;
+}
+
+/// Decoder of the zones of Nostr events, trying each of its formats in order.
+pub struct ZoneDecoders {
+    decoders: Vec<Box<dyn ZoneDecoder>>,
+}
+
+impl Default for ZoneDecoders {
+    /// Wire-format record tags first, then record tags, JSON content and finally master-file
+    /// content.
+    fn default() -> Self {
+        Self::new(vec![
+            Box::new(WireZoneDecoder),
+            Box::new(TagsZoneDecoder),
+            Box::new(JsonZoneDecoder),
+            Box::new(MasterFileZoneDecoder),
+        ])
+    }
+}
+
+impl ZoneDecoders {
+    pub fn new(decoders: Vec<Box<dyn ZoneDecoder>>) -> Self {
+        Self { decoders }
+    }
+
+    /// Records of the zone `zone_name` held by `event`, in the first format detected.
+    pub fn decode(
+        &self,
+        event: &nostr_sdk::Event,
+        zone_name: &Name,
+    ) -> Result<ZoneRecords, ZoneDecodeError> {
+        let decoder = self
+            .decoders
+            .iter()
+            .find(|decoder| decoder.detects(event))
+            .ok_or(ZoneDecodeError::UnknownFormat)?;
+        decoder.decode(event, zone_name)
+    }
+}
+
+/// Add `record` to the record set of its name and type in `records`.
+pub fn insert_record(records: &mut ZoneRecords, record: Record) {
+    let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+    records
+        .entry(key)
+        .or_insert_with(|| RecordSet::new(record.name(), record.record_type(), 0))
+        .insert(record, 0);
+}
+
+/// Records of the master-file text `zone_file`, relative to `zone_name`.
+fn parse_master_file(zone_file: &str, zone_name: &Name) -> Result<ZoneRecords, ZoneDecodeError> {
+    let (_, records) = Parser::new(zone_file, None, Some(zone_name.clone()))
+        .parse()
+        .map_err(ZoneDecodeError::Parse)?;
+    Ok(records)
+}
+
+#[derive(Debug)]
+pub enum ZoneDecodeError {
+    /// No decoder detected the format of the event.
+    UnknownFormat,
+
+    /// A structured record is malformed.
+    InvalidRecord(String),
+
+    /// The content is not valid JSON for a zone.
+    Json(serde_json::Error),
+
+    /// The resulting master file is not valid.
+    Parse(ParseError),
+
+    /// A record in wire format is not valid.
+    Wire(ProtoError),
+}
+
+impl Display for ZoneDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoneDecodeError::UnknownFormat => write!(f, "unknown zone format"),
+            ZoneDecodeError::InvalidRecord(record) => write!(f, "invalid record: {}", record),
+            ZoneDecodeError::Json(e) => write!(f, "invalid JSON zone: {}", e),
+            ZoneDecodeError::Parse(e) => write!(f, "invalid zone file: {}", e),
+            ZoneDecodeError::Wire(e) => write!(f, "invalid wire-format record: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ZoneDecodeError {}
+
+/// Zone published as RFC 1035 master-file text in the content of the event.
+pub struct MasterFileZoneDecoder;
+
+impl ZoneDecoder for MasterFileZoneDecoder {
+    fn detects(&self, _event: &nostr_sdk::Event) -> bool {
+        true
+    }
+
+    fn decode(
+        &self,
+        event: &nostr_sdk::Event,
+        zone_name: &Name,
+    ) -> Result<ZoneRecords, ZoneDecodeError> {
+        parse_master_file(&event.content, zone_name)
+    }
+}
+
+/// Zone published as JSON in the content of the event, either a list of records or an object
+/// with a `records` list, e.g. `{"records": [{"name": "www", "type": "A", "value": "1.2.3.4"}]}`.
+pub struct JsonZoneDecoder;
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum JsonZone {
+    Records(Vec<StructuredRecord>),
+    Zone { records: Vec<StructuredRecord> },
+}
+
+impl ZoneDecoder for JsonZoneDecoder {
+    fn detects(&self, event: &nostr_sdk::Event) -> bool {
+        let content = event.content.trim_start();
+        content.starts_with('{') || content.starts_with('[')
+    }
+
+    fn decode(
+        &self,
+        event: &nostr_sdk::Event,
+        zone_name: &Name,
+    ) -> Result<ZoneRecords, ZoneDecodeError> {
+        let records = match serde_json::from_str(&event.content).map_err(ZoneDecodeError::Json)? {
+            JsonZone::Records(records) => records,
+            JsonZone::Zone { records } => records,
+        };
+        parse_master_file(
+            &structured_master_file(event, zone_name, &records)?,
+            zone_name,
+        )
+    }
+}
+
+/// Zone published as `record` tags of the event, `["record", <name>, <type>, <value>, <ttl>?]`.
+pub struct TagsZoneDecoder;
+
+impl ZoneDecoder for TagsZoneDecoder {
+    fn detects(&self, event: &nostr_sdk::Event) -> bool {
+        record_tags(event).next().is_some()
+    }
+
+    fn decode(
+        &self,
+        event: &nostr_sdk::Event,
+        zone_name: &Name,
+    ) -> Result<ZoneRecords, ZoneDecodeError> {
+        let records = record_tags(event)
+            .map(|tag| match tag {
+                [_, name, record_type, value] => Ok(StructuredRecord {
+                    name: name.clone(),
+                    record_type: record_type.clone(),
+                    value: value.clone(),
+                    ttl: None,
+                }),
+                [_, name, record_type, value, ttl] => Ok(StructuredRecord {
+                    name: name.clone(),
+                    record_type: record_type.clone(),
+                    value: value.clone(),
+                    ttl: Some(
+                        ttl.parse()
+                            .map_err(|_| ZoneDecodeError::InvalidRecord(tag.join(" ")))?,
+                    ),
+                }),
+                _ => Err(ZoneDecodeError::InvalidRecord(tag.join(" "))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        parse_master_file(
+            &structured_master_file(event, zone_name, &records)?,
+            zone_name,
+        )
+    }
+}
+
+fn record_tags(event: &nostr_sdk::Event) -> impl Iterator<Item = &[String]> {
+    tags_named(&event.tags, RECORD_TAG)
+}
+
+fn tags_named<'a>(
+    tags: &'a nostr_sdk::Tags,
+    tag_name: &'a str,
+) -> impl Iterator<Item = &'a [String]> {
+    tags.iter()
+        .map(|tag| tag.as_slice())
+        .filter(move |tag| tag.first().is_some_and(|name| name == tag_name))
+}
+
+/// Zone published as `rr` tags of the event, each one a hex-encoded record in wire format as in
+/// RFC 1035 section 4.1.3, `["rr", <hex>]`.
+///
+/// Unlike the text formats, it carries any record type, such as the DNSKEY, RRSIG and NSEC
+/// records of a zone signed by its owner. The names are absolute and the RRSIG records are
+/// attached to the record sets they cover.
+pub struct WireZoneDecoder;
+
+impl ZoneDecoder for WireZoneDecoder {
+    fn detects(&self, event: &nostr_sdk::Event) -> bool {
+        tags_named(&event.tags, WIRE_RECORD_TAG).next().is_some()
+    }
+
+    fn decode(
+        &self,
+        event: &nostr_sdk::Event,
+        _zone_name: &Name,
+    ) -> Result<ZoneRecords, ZoneDecodeError> {
+        decode_wire_record_tags(&event.tags)
+    }
+}
+
+/// Records of the `rr` tags of `tags`, the inverse of `wire_record_tags`, e.g. of a zone event
+/// queued unsigned.
+pub fn decode_wire_record_tags(tags: &nostr_sdk::Tags) -> Result<ZoneRecords, ZoneDecodeError> {
+    let mut records = ZoneRecords::new();
+    let mut rrsigs = vec![];
+    for tag in tags_named(tags, WIRE_RECORD_TAG) {
+        let invalid_record = || ZoneDecodeError::InvalidRecord(tag.join(" "));
+        let [_, hex_record] = tag else {
+            return Err(invalid_record());
+        };
+        let bytes = Vec::from_hex(hex_record).map_err(|_| invalid_record())?;
+        let record = Record::from_bytes(&bytes).map_err(ZoneDecodeError::Wire)?;
+        if record.record_type() == RecordType::RRSIG {
+            rrsigs.push(record);
+        } else {
+            insert_record(&mut records, record);
+        }
+    }
+    for rrsig in rrsigs {
+        let type_covered = rrsig
+            .data()
+            .and_then(|rdata| rdata.as_dnssec()?.as_rrsig())
+            .map(|rrsig| rrsig.type_covered());
+        let key =
+            type_covered.map(|type_covered| RrKey::new(LowerName::new(rrsig.name()), type_covered));
+        // Signatures of records that are not published cannot be served.
+        if let Some(record_set) = key.and_then(|key| records.get_mut(&key)) {
+            record_set.insert_rrsig(rrsig);
+        }
+    }
+    Ok(records)
+}
+
+/// `rr` tags publishing `records`, RRSIG records included, the inverse of `WireZoneDecoder`.
+pub fn wire_record_tags(records: &ZoneRecords) -> Result<Vec<nostr_sdk::Tag>, ProtoError> {
+    let mut tags = vec![];
+    for record_set in records.values() {
+        for record in record_set.records(true, SupportedAlgorithms::all()) {
+            let hex_record = record.to_bytes()?.to_lower_hex_string();
+            tags.push(nostr_sdk::Tag::custom(
+                nostr_sdk::TagKind::custom(WIRE_RECORD_TAG),
+                [hex_record],
+            ));
+        }
+    }
+    Ok(tags)
+}
+
+/// Record of a zone published in a structured format, with a name relative to the zone.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StructuredRecord {
+    #[serde(default = "apex_name")]
+    name: String,
+
+    #[serde(rename = "type")]
+    record_type: String,
+
+    value: String,
+
+    ttl: Option<u32>,
+}
+
+fn apex_name() -> String {
+    "@".into()
+}
+
+impl StructuredRecord {
+    fn to_master_file_line(&self) -> Result<String, ZoneDecodeError> {
+        let is_valid = !self.name.is_empty()
+            && !self.name.contains(char::is_whitespace)
+            && !self.name.starts_with('$')
+            && !self.record_type.is_empty()
+            && self.record_type.chars().all(|c| c.is_ascii_alphanumeric())
+            && !self.value.contains(['\n', '\r']);
+        if !is_valid {
+            return Err(ZoneDecodeError::InvalidRecord(format!("{:?}", self)));
+        }
+        Ok(format!(
+            "{} {} IN {} {}\n",
+            self.name,
+            self.ttl.unwrap_or(DEFAULT_TTL),
+            self.record_type,
+            self.value
+        ))
+    }
+}
+
+/// Master file of structured records, adding an SOA when the owner did not publish one.
+fn structured_master_file(
+    event: &nostr_sdk::Event,
+    zone_name: &Name,
+    records: &[StructuredRecord],
+) -> Result<String, ZoneDecodeError> {
+    let mut zone_file = String::new();
+    let has_soa = records
+        .iter()
+        .any(|record| record.record_type.eq_ignore_ascii_case("SOA"));
+    if !has_soa {
+        let serial = event.created_at.as_u64() as u32;
+        zone_file.push_str(&format!(
+            "@ {} IN SOA {} hostmaster.{} {} 3600 600 86400 300\n",
+            DEFAULT_TTL, zone_name, zone_name, serial
+        ));
+    }
+    for record in records {
+        zone_file.push_str(&record.to_master_file_line()?);
+    }
+    Ok(zone_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::rr::{LowerName, RecordType};
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag};
+
+    fn zone_event(content: &str, tags: Vec<Vec<&str>>) -> nostr_sdk::Event {
+        EventBuilder::new(Kind::Custom(30053), content)
+            .tags(tags.into_iter().map(|tag| Tag::parse(tag).unwrap()))
+            .sign_with_keys(&Keys::generate())
+            .unwrap()
+    }
+
+    fn record_set<'a>(records: &'a ZoneRecords, name: &str, rtype: RecordType) -> &'a RecordSet {
+        let name = LowerName::new(&name.parse().unwrap());
+        &records[&RrKey::new(name, rtype)]
+    }
+
+    #[test]
+    fn test_decode_master_file() {
+        let event = zone_event(
+            "@ 3600 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+             1 3600 600 86400 300\n\
+             www 300 IN A 1.2.3.4\n",
+            vec![],
+        );
+        let zone_name = "alice.nostr.dns.name.".parse().unwrap();
+        let records = ZoneDecoders::default().decode(&event, &zone_name).unwrap();
+        let www = record_set(&records, "www.alice.nostr.dns.name.", RecordType::A);
+        assert_eq!(www.ttl(), 300);
+    }
+
+    #[test]
+    fn test_decode_json() {
+        let event = zone_event(
+            r#"{"records": [
+                {"name": "www", "type": "A", "value": "1.2.3.4", "ttl": 300},
+                {"type": "TXT", "value": "\"hello world\""}
+            ]}"#,
+            vec![],
+        );
+        let zone_name = "alice.nostr.dns.name.".parse().unwrap();
+        let records = ZoneDecoders::default().decode(&event, &zone_name).unwrap();
+        assert_eq!(
+            record_set(&records, "www.alice.nostr.dns.name.", RecordType::A).ttl(),
+            300
+        );
+        assert_eq!(
+            record_set(&records, "alice.nostr.dns.name.", RecordType::TXT).ttl(),
+            DEFAULT_TTL
+        );
+        record_set(&records, "alice.nostr.dns.name.", RecordType::SOA);
+    }
+
+    #[test]
+    fn test_decode_tags() {
+        let event = zone_event(
+            "",
+            vec![
+                vec!["d", "alice"],
+                vec!["record", "www", "A", "1.2.3.4", "300"],
+                vec!["record", "@", "MX", "10 mail"],
+            ],
+        );
+        let zone_name = "alice.nostr.dns.name.".parse().unwrap();
+        let records = ZoneDecoders::default().decode(&event, &zone_name).unwrap();
+        assert_eq!(
+            record_set(&records, "www.alice.nostr.dns.name.", RecordType::A).ttl(),
+            300
+        );
+        record_set(&records, "alice.nostr.dns.name.", RecordType::MX);
+        record_set(&records, "alice.nostr.dns.name.", RecordType::SOA);
+    }
+
+    #[tokio::test]
+    async fn test_decode_wire() {
+        use crate::zone_signer::{SigningAlgorithm, ZoneSigner};
+        use hickory_server::{
+            authority::ZoneType,
+            proto::rr::dnssec::{Algorithm, KeyPair},
+            store::in_memory::InMemoryAuthority,
+        };
+
+        let zone_name: Name = "alice.nostr.dns.name.".parse().unwrap();
+        let zone_file = "@ 3600 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+                         1 3600 600 86400 300\n\
+                         www 300 IN A 1.2.3.4\n";
+        let records = parse_master_file(zone_file, &zone_name).unwrap();
+        let mut authority =
+            InMemoryAuthority::new(zone_name.clone(), records, ZoneType::Primary, false).unwrap();
+        let pkcs8_key = KeyPair::generate_pkcs8(Algorithm::ED25519).unwrap();
+        ZoneSigner::new(zone_name.clone(), SigningAlgorithm::Ed25519, pkcs8_key)
+            .unwrap()
+            .sign(&mut authority)
+            .unwrap();
+        let signed_records = authority
+            .records()
+            .await
+            .iter()
+            .map(|(key, record_set)| (key.clone(), (**record_set).clone()))
+            .collect();
+        let event = EventBuilder::new(Kind::Custom(30053), "")
+            .tags(wire_record_tags(&signed_records).unwrap())
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+
+        let records = ZoneDecoders::default().decode(&event, &zone_name).unwrap();
+        let www = record_set(&records, "www.alice.nostr.dns.name.", RecordType::A);
+        assert_eq!(www.rrsigs().len(), 1);
+        record_set(&records, "alice.nostr.dns.name.", RecordType::DNSKEY);
+        record_set(&records, "www.alice.nostr.dns.name.", RecordType::NSEC);
+
+        let event = zone_event("", vec![vec!["rr", "not hex"]]);
+        assert!(matches!(
+            ZoneDecoders::default().decode(&event, &zone_name),
+            Err(ZoneDecodeError::InvalidRecord(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_rejects_invalid_records() {
+        let zone_name = "alice.nostr.dns.name.".parse().unwrap();
+        let event = zone_event(
+            "",
+            vec![vec!["record", "www", "A", "1.2.3.4\nevil 300 IN A 6.6.6.6"]],
+        );
+        assert!(matches!(
+            ZoneDecoders::default().decode(&event, &zone_name),
+            Err(ZoneDecodeError::InvalidRecord(_))
+        ));
+        let event = zone_event("", vec![vec!["record", "www", "A"]]);
+        assert!(matches!(
+            ZoneDecoders::default().decode(&event, &zone_name),
+            Err(ZoneDecodeError::InvalidRecord(_))
+        ));
+    }
+}
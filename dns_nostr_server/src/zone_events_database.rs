@@ -0,0 +1,346 @@
+use nostr_sdk::JsonUtil;
+use rusqlite::OptionalExtension;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Time to wait for the Name-Token indexer to release the shared SQLite database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Last verified zone event of each owner and label, stored next to the indexed Name-Tokens.
+///
+/// The events are served as a stale fallback while the relays are unreachable, in the spirit of
+/// RFC 8767, so a relay outage or a restart during one does not wipe out the zones.
+///
+/// The zones updated on behalf of owners whose Nostr keys the operator does not hold are queued
+/// in it as unsigned events, until the wallets of the owners sign and publish them.
+#[derive(Clone)]
+pub struct ZoneEventsDatabase {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+}
+
+impl ZoneEventsDatabase {
+    pub async fn create(database_path: &Path) -> rusqlite::Result<Self> {
+        let sqlite = rusqlite::Connection::open(database_path)?;
+        sqlite.busy_timeout(BUSY_TIMEOUT)?;
+        Self::from_connection(sqlite).await
+    }
+
+    async fn from_connection(sqlite: rusqlite::Connection) -> rusqlite::Result<Self> {
+        let this = Self {
+            connection: Arc::new(Mutex::new(sqlite)),
+        };
+        this.create_tables().await?;
+        Ok(this)
+    }
+
+    /// Connection to the database, still usable after a panic while it was held, as every
+    /// statement leaves the tables consistent.
+    fn connection(&self) -> MutexGuard<'_, rusqlite::Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn create_tables(&self) -> rusqlite::Result<()> {
+        let connection = self.connection();
+        // The identifier is the `d` tag of addressable events and empty for text notes.
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS zone_events (
+                pubkey CHAR(64) NOT NULL,
+                kind UNSIGNED INTEGER NOT NULL,
+                identifier TEXT NOT NULL,
+                created_at UNSIGNED INTEGER NOT NULL,
+                event_json TEXT NOT NULL,
+                confirmed_at UNSIGNED INTEGER NOT NULL,
+                PRIMARY KEY (pubkey, kind, identifier)
+            )",
+            [],
+        )?;
+        // Zones updated on behalf of the owners, waiting for their wallets to sign them.
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS pending_zone_events (
+                pubkey CHAR(64) NOT NULL,
+                kind UNSIGNED INTEGER NOT NULL,
+                identifier TEXT NOT NULL,
+                created_at UNSIGNED INTEGER NOT NULL,
+                unsigned_event_json TEXT NOT NULL,
+                PRIMARY KEY (pubkey, kind, identifier)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Store `event` as the latest zone event of its owner, kind and identifier, confirmed by
+    /// the relays now. Events older than the stored one are ignored, and the pending events it
+    /// supersedes are dropped.
+    pub async fn save_event(&self, event: &nostr_sdk::Event) -> rusqlite::Result<()> {
+        self.save_event_confirmed_at(event, unix_time_now()).await
+    }
+
+    async fn save_event_confirmed_at(
+        &self,
+        event: &nostr_sdk::Event,
+        confirmed_at: u64,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection();
+        connection.execute(
+            "INSERT INTO zone_events
+                (pubkey, kind, identifier, created_at, event_json, confirmed_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT (pubkey, kind, identifier) DO UPDATE SET
+                    created_at = excluded.created_at,
+                    event_json = excluded.event_json,
+                    confirmed_at = excluded.confirmed_at
+                WHERE excluded.created_at >= zone_events.created_at",
+            rusqlite::params![
+                event.pubkey.to_hex(),
+                event.kind.as_u16(),
+                event.tags.identifier().unwrap_or_default(),
+                event.created_at.as_u64(),
+                event.as_json(),
+                confirmed_at,
+            ],
+        )?;
+        connection.execute(
+            "DELETE FROM pending_zone_events
+                WHERE pubkey = ?1 AND kind = ?2 AND identifier = ?3 AND created_at <= ?4",
+            rusqlite::params![
+                event.pubkey.to_hex(),
+                event.kind.as_u16(),
+                event.tags.identifier().unwrap_or_default(),
+                event.created_at.as_u64(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Queue `event` for the wallet of its owner to sign and publish, replacing the pending
+    /// event of the same kind and identifier.
+    pub async fn queue_unsigned_event(
+        &self,
+        event: &nostr_sdk::UnsignedEvent,
+    ) -> rusqlite::Result<()> {
+        let connection = self.connection();
+        connection.execute(
+            "INSERT OR REPLACE INTO pending_zone_events
+                (pubkey, kind, identifier, created_at, unsigned_event_json)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![
+                event.pubkey.to_hex(),
+                event.kind.as_u16(),
+                event.tags.identifier().unwrap_or_default(),
+                event.created_at.as_u64(),
+                event.as_json(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Events queued for the wallet of `pubkey` to sign and publish.
+    pub async fn get_unsigned_events(
+        &self,
+        pubkey: &nostr_sdk::PublicKey,
+    ) -> rusqlite::Result<Vec<nostr_sdk::UnsignedEvent>> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT unsigned_event_json FROM pending_zone_events
+            WHERE pubkey = ?1 ORDER BY created_at",
+        )?;
+        let events = statement
+            .query_map([pubkey.to_hex()], |row| row.get::<_, String>(0))?
+            .map(|event_json| unsigned_event(event_json?))
+            .collect();
+        events
+    }
+
+    /// Event of `pubkey`, `kind` and `identifier` queued for the wallet of `pubkey` to sign and
+    /// publish, if any.
+    pub async fn get_unsigned_event(
+        &self,
+        pubkey: &nostr_sdk::PublicKey,
+        kind: nostr_sdk::Kind,
+        identifier: &str,
+    ) -> rusqlite::Result<Option<nostr_sdk::UnsignedEvent>> {
+        let connection = self.connection();
+        let event_json: Option<String> = connection
+            .query_row(
+                "SELECT unsigned_event_json FROM pending_zone_events
+                WHERE pubkey = ?1 AND kind = ?2 AND identifier = ?3",
+                rusqlite::params![pubkey.to_hex(), kind.as_u16(), identifier],
+                |row| row.get(0),
+            )
+            .optional()?;
+        event_json.map(unsigned_event).transpose()
+    }
+
+    /// Stored event of `pubkey`, `kind` and `identifier` confirmed by the relays at most
+    /// `max_staleness` ago.
+    pub async fn get_event(
+        &self,
+        pubkey: &nostr_sdk::PublicKey,
+        kind: nostr_sdk::Kind,
+        identifier: &str,
+        max_staleness: Duration,
+    ) -> rusqlite::Result<Option<nostr_sdk::Event>> {
+        let min_confirmed_at = unix_time_now().saturating_sub(max_staleness.as_secs());
+        let connection = self.connection();
+        let event_json: Option<String> = connection
+            .query_row(
+                "SELECT event_json FROM zone_events
+                WHERE pubkey = ?1 AND kind = ?2 AND identifier = ?3 AND confirmed_at >= ?4",
+                rusqlite::params![pubkey.to_hex(), kind.as_u16(), identifier, min_confirmed_at],
+                |row| row.get(0),
+            )
+            .optional()?;
+        // Events that do not parse nor verify anymore are not served.
+        let event = event_json
+            .and_then(|event_json| nostr_sdk::Event::from_json(event_json).ok())
+            .filter(|event| event.pubkey == *pubkey && event.verify().is_ok());
+        Ok(event)
+    }
+}
+
+/// Unsigned event of the `unsigned_event_json` column.
+fn unsigned_event(event_json: String) -> rusqlite::Result<nostr_sdk::UnsignedEvent> {
+    nostr_sdk::UnsignedEvent::from_json(event_json).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::{EventBuilder, Keys, Kind, Tag, Timestamp};
+
+    const ZONE_EVENT_KIND: Kind = Kind::Custom(30053);
+    const MAX_STALENESS: Duration = Duration::from_secs(3600);
+
+    async fn create_database() -> ZoneEventsDatabase {
+        let sqlite = rusqlite::Connection::open_in_memory().unwrap();
+        ZoneEventsDatabase::from_connection(sqlite).await.unwrap()
+    }
+
+    fn zone_event(keys: &Keys, label: &str, created_at: u64) -> nostr_sdk::Event {
+        EventBuilder::new(ZONE_EVENT_KIND, "")
+            .tag(Tag::identifier(label))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_save_event() {
+        let database = create_database().await;
+        let keys = Keys::generate();
+        let pubkey = keys.public_key();
+        let new_event = zone_event(&keys, "alice", 2_000);
+
+        database.save_event(&new_event).await.unwrap();
+        database
+            .save_event(&zone_event(&keys, "alice", 1_000))
+            .await
+            .unwrap();
+        assert_eq!(
+            database
+                .get_event(&pubkey, ZONE_EVENT_KIND, "alice", MAX_STALENESS)
+                .await
+                .unwrap(),
+            Some(new_event)
+        );
+        assert_eq!(
+            database
+                .get_event(&pubkey, ZONE_EVENT_KIND, "bob", MAX_STALENESS)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            database
+                .get_event(&pubkey, Kind::TextNote, "alice", MAX_STALENESS)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queue_unsigned_event() {
+        let database = create_database().await;
+        let keys = Keys::generate();
+        let pubkey = keys.public_key();
+        let unsigned_event = |created_at: u64| {
+            EventBuilder::new(ZONE_EVENT_KIND, "")
+                .tag(Tag::identifier("alice"))
+                .custom_created_at(Timestamp::from(created_at))
+                .build(pubkey)
+        };
+
+        database
+            .queue_unsigned_event(&unsigned_event(1_000))
+            .await
+            .unwrap();
+        database
+            .queue_unsigned_event(&unsigned_event(2_000))
+            .await
+            .unwrap();
+        let pending_events = database.get_unsigned_events(&pubkey).await.unwrap();
+        assert_eq!(pending_events.len(), 1);
+        assert_eq!(pending_events[0].created_at, Timestamp::from(2_000));
+        let pending_event = database
+            .get_unsigned_event(&pubkey, ZONE_EVENT_KIND, "alice")
+            .await
+            .unwrap();
+        assert_eq!(pending_event.as_ref(), pending_events.first());
+        let pending_event = database
+            .get_unsigned_event(&pubkey, ZONE_EVENT_KIND, "bob")
+            .await
+            .unwrap();
+        assert_eq!(pending_event, None);
+
+        database
+            .save_event(&zone_event(&keys, "alice", 2_000))
+            .await
+            .unwrap();
+        assert!(database
+            .get_unsigned_events(&pubkey)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_event_max_staleness() {
+        let database = create_database().await;
+        let keys = Keys::generate();
+        let event = zone_event(&keys, "alice", 1_000);
+        let confirmed_at = unix_time_now() - 2 * MAX_STALENESS.as_secs();
+
+        database
+            .save_event_confirmed_at(&event, confirmed_at)
+            .await
+            .unwrap();
+        let stale_event = database
+            .get_event(&keys.public_key(), ZONE_EVENT_KIND, "alice", MAX_STALENESS)
+            .await
+            .unwrap();
+        assert_eq!(stale_event, None);
+
+        database.save_event(&event).await.unwrap();
+        let event = database
+            .get_event(&keys.public_key(), ZONE_EVENT_KIND, "alice", MAX_STALENESS)
+            .await
+            .unwrap();
+        assert!(event.is_some());
+    }
+}
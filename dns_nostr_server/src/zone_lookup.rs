@@ -0,0 +1,281 @@
+use hickory_server::proto::{
+    rr::{rdata::CNAME, LowerName, Name, RData, Record, RecordSet, RecordType, RrKey},
+    serialize::binary::BinDecodable,
+};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// DNAME record type of RFC 6672, unknown to hickory, which keeps its data undecoded.
+pub const DNAME: RecordType = RecordType::Unknown(39);
+
+/// Records of a zone, as held by the `InMemoryAuthority`.
+pub type AuthorityRecords = BTreeMap<RrKey, Arc<RecordSet>>;
+
+/// Outcome of looking up a name in a zone, following RFC 1034 section 4.3.2 with the wildcards of
+/// RFC 4592 and the DNAME of RFC 6672.
+#[derive(Debug, PartialEq)]
+pub enum ZoneLookup {
+    /// The records of the queried type, named after the queried name even when they come from a
+    /// wildcard.
+    Answer(Arc<RecordSet>),
+
+    /// Records aliasing the queried name to `target`, a CNAME or a DNAME followed by the CNAME
+    /// synthesized from it, to add to the answer before looking up `target`.
+    Alias {
+        records: Vec<Arc<RecordSet>>,
+        target: LowerName,
+    },
+
+    /// The name exists, or matches a wildcard, but has no records of the queried type.
+    NoData,
+
+    /// The name does not exist in the zone.
+    NxDomain,
+}
+
+/// Look up the `rtype` records of `name` in the zone `apex`.
+pub fn lookup(
+    records: &AuthorityRecords,
+    apex: &LowerName,
+    name: &LowerName,
+    rtype: RecordType,
+) -> ZoneLookup {
+    if !apex.zone_of(name) {
+        return ZoneLookup::NxDomain;
+    }
+    if let Some(dname) = find_dname(records, apex, name) {
+        return redirect(name, dname);
+    }
+    if exists(records, name) {
+        return lookup_node(records, name, name, rtype);
+    }
+    let wildcard = ancestors(apex, name)
+        .find(|ancestor| exists(records, ancestor))
+        .and_then(|closest_encloser| wildcard_of(&closest_encloser));
+    match wildcard {
+        Some(wildcard) if exists(records, &wildcard) => {
+            lookup_node(records, &wildcard, name, rtype)
+        }
+        _ => ZoneLookup::NxDomain,
+    }
+}
+
+/// Look up the `rtype` records of `node`, answering them for `name`.
+fn lookup_node(
+    records: &AuthorityRecords,
+    node: &LowerName,
+    name: &LowerName,
+    rtype: RecordType,
+) -> ZoneLookup {
+    let get = |record_type| {
+        let record_set = records.get(&RrKey::new(node.clone(), record_type))?;
+        Some(if node == name {
+            record_set.clone()
+        } else {
+            synthesize(record_set, name)
+        })
+    };
+    if let Some(record_set) = get(rtype) {
+        return ZoneLookup::Answer(record_set);
+    }
+    let Some(cname) = get(RecordType::CNAME) else {
+        return ZoneLookup::NoData;
+    };
+    let target = cname
+        .records_without_rrsigs()
+        .next()
+        .and_then(Record::data)
+        .and_then(RData::as_cname)
+        .map(|cname| LowerName::new(&cname.0));
+    match target {
+        Some(target) => ZoneLookup::Alias {
+            records: vec![cname],
+            target,
+        },
+        None => ZoneLookup::NoData,
+    }
+}
+
+/// DNAME of the highest ancestor of `name` in the zone `apex` having one.
+fn find_dname<'a>(
+    records: &'a AuthorityRecords,
+    apex: &LowerName,
+    name: &LowerName,
+) -> Option<&'a Arc<RecordSet>> {
+    let ancestors = ancestors(apex, name).collect::<Vec<_>>();
+    ancestors
+        .into_iter()
+        .rev()
+        .find_map(|ancestor| records.get(&RrKey::new(ancestor, DNAME)))
+}
+
+/// Alias of `name` through the DNAME of one of its ancestors.
+///
+/// When the substituted name would be too long, the DNAME is answered alone.
+fn redirect(name: &LowerName, dname: &Arc<RecordSet>) -> ZoneLookup {
+    let Some(dname_target) = dname.records_without_rrsigs().next().and_then(dname_target) else {
+        return ZoneLookup::Answer(dname.clone());
+    };
+    let query_name = Name::from(name);
+    let prefix_len = query_name.iter().len() - dname.name().iter().len();
+    let target = Name::from_labels(query_name.iter().take(prefix_len))
+        .and_then(|prefix| prefix.append_domain(&dname_target));
+    let Ok(target) = target else {
+        return ZoneLookup::Answer(dname.clone());
+    };
+    // The synthesized CNAME is not signed, validators synthesize it again from the DNAME.
+    let mut cname = RecordSet::with_ttl(query_name, RecordType::CNAME, dname.ttl());
+    cname.add_rdata(RData::CNAME(CNAME(target.clone())));
+    ZoneLookup::Alias {
+        records: vec![dname.clone(), Arc::new(cname)],
+        target: LowerName::new(&target),
+    }
+}
+
+/// Target of the DNAME `record`, whose data hickory keeps undecoded.
+pub fn dname_target(record: &Record) -> Option<Name> {
+    match record.data()? {
+        RData::Unknown { code, rdata } if *code == DNAME => Name::from_bytes(rdata.anything()).ok(),
+        _ => None,
+    }
+}
+
+/// `record_set` of a wildcard, renamed to `name`.
+fn synthesize(record_set: &RecordSet, name: &LowerName) -> Arc<RecordSet> {
+    let name = Name::from(name);
+    let mut synthesized =
+        RecordSet::with_ttl(name.clone(), record_set.record_type(), record_set.ttl());
+    for rdata in record_set.records_without_rrsigs().filter_map(Record::data) {
+        synthesized.add_rdata(rdata.clone());
+    }
+    for rrsig in record_set.rrsigs() {
+        let mut rrsig = rrsig.clone();
+        rrsig.set_name(name.clone());
+        synthesized.insert_rrsig(rrsig);
+    }
+    Arc::new(synthesized)
+}
+
+/// Wildcard `*.<closest_encloser>`, the source of synthesis of RFC 4592, unless the name would
+/// be too long.
+fn wildcard_of(closest_encloser: &LowerName) -> Option<LowerName> {
+    let wildcard = Name::from_ascii("*").ok()?;
+    let wildcard = wildcard.append_domain(&Name::from(closest_encloser)).ok()?;
+    Some(LowerName::new(&wildcard))
+}
+
+/// Whether `name` owns records or is an empty non-terminal of the zone.
+fn exists(records: &AuthorityRecords, name: &LowerName) -> bool {
+    records.keys().any(|key| name.zone_of(&key.name))
+}
+
+/// Ancestors of `name` up to the zone `apex`, from the closest one.
+fn ancestors<'a>(apex: &'a LowerName, name: &LowerName) -> impl Iterator<Item = LowerName> + 'a {
+    std::iter::successors(Some(name.clone()), |name| {
+        (name.num_labels() > 0).then(|| name.base_name())
+    })
+    .skip(1)
+    .take_while(move |ancestor| apex.zone_of(ancestor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zone_decoder::insert_record;
+    use hickory_server::proto::{
+        rr::rdata::NULL, serialize::binary::BinEncodable, serialize::txt::Parser,
+    };
+
+    fn zone_records() -> AuthorityRecords {
+        let zone_name: Name = "alice.nostr.dns.name.".parse().unwrap();
+        let zone_file = "@ 3600 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+                         1 3600 600 86400 300\n\
+                         * 300 IN A 1.2.3.4\n\
+                         www 300 IN CNAME bob.nostr.dns.name.\n\
+                         c.b 300 IN TXT \"empty non-terminal\"\n\
+                         *.b 300 IN CNAME www\n";
+        let (_, mut records) = Parser::new(zone_file, None, Some(zone_name.clone()))
+            .parse()
+            .unwrap();
+        let dname_target: Name = "example.com.".parse().unwrap();
+        let rdata = RData::Unknown {
+            code: DNAME,
+            rdata: NULL::with(dname_target.to_bytes().unwrap()),
+        };
+        let owner = Name::from_ascii("legacy")
+            .unwrap()
+            .append_domain(&zone_name);
+        insert_record(&mut records, Record::from_rdata(owner.unwrap(), 300, rdata));
+        records
+            .into_iter()
+            .map(|(key, record_set)| (key, Arc::new(record_set)))
+            .collect()
+    }
+
+    fn lookup_name(name: &str, rtype: RecordType) -> ZoneLookup {
+        let apex = LowerName::new(&"alice.nostr.dns.name.".parse().unwrap());
+        let name = LowerName::new(&name.parse().unwrap());
+        lookup(&zone_records(), &apex, &name, rtype)
+    }
+
+    fn answer_name(lookup: ZoneLookup) -> Option<String> {
+        match lookup {
+            ZoneLookup::Answer(record_set) => Some(record_set.name().to_string()),
+            _ => None,
+        }
+    }
+
+    fn alias_target(lookup: ZoneLookup) -> Option<String> {
+        match lookup {
+            ZoneLookup::Alias { target, .. } => Some(target.to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let name = "a.b.alice.nostr.dns.name.";
+        assert_eq!(
+            alias_target(lookup_name(name, RecordType::A)).as_deref(),
+            Some("www.alice.nostr.dns.name.")
+        );
+        let name = "x.y.alice.nostr.dns.name.";
+        assert_eq!(
+            answer_name(lookup_name(name, RecordType::A)).as_deref(),
+            Some(name)
+        );
+        let name = "x.alice.nostr.dns.name.";
+        assert_eq!(lookup_name(name, RecordType::TXT), ZoneLookup::NoData);
+        // Empty non-terminals are not matched by the wildcards above them.
+        let name = "b.alice.nostr.dns.name.";
+        assert_eq!(lookup_name(name, RecordType::A), ZoneLookup::NoData);
+        let name = "d.c.b.alice.nostr.dns.name.";
+        assert_eq!(lookup_name(name, RecordType::A), ZoneLookup::NxDomain);
+    }
+
+    #[test]
+    fn test_cname() {
+        let name = "www.alice.nostr.dns.name.";
+        assert_eq!(
+            alias_target(lookup_name(name, RecordType::A)).as_deref(),
+            Some("bob.nostr.dns.name.")
+        );
+        assert_eq!(
+            answer_name(lookup_name(name, RecordType::CNAME)).as_deref(),
+            Some(name)
+        );
+    }
+
+    #[test]
+    fn test_dname() {
+        let name = "www.legacy.alice.nostr.dns.name.";
+        let ZoneLookup::Alias { records, target } = lookup_name(name, RecordType::A) else {
+            panic!("DNAME not followed");
+        };
+        assert_eq!(target.to_string(), "www.example.com.");
+        assert_eq!(records[0].record_type(), DNAME);
+        assert_eq!(records[1].name().to_string(), name);
+        // The owner of the DNAME is not redirected.
+        let name = "legacy.alice.nostr.dns.name.";
+        assert_eq!(lookup_name(name, RecordType::A), ZoneLookup::NoData);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::zone_decoder::ZoneRecords;
+use hickory_server::proto::rr::{LowerName, Name, RecordType, RrKey};
+use tracing::warn;
+
+/// Restrictions an operator puts on the records of the zones published by the owners.
+///
+/// Whatever the zone text says, e.g. absolute names or `$ORIGIN` directives, an owner only
+/// controls the names under its own label, and the apex of its zone is served by the name
+/// servers of the origin. The records breaking the policy are dropped and logged.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ZonePolicy {
+    /// Serve the NS records below the apex of a zone, delegating part of it to other servers.
+    pub allow_delegations: bool,
+}
+
+impl ZonePolicy {
+    /// Records of the zone `zone_name` allowed by the policy.
+    pub fn scope(&self, zone_name: &Name, records: ZoneRecords) -> ZoneRecords {
+        let apex = LowerName::new(zone_name);
+        records
+            .into_iter()
+            .filter(|(key, _)| match self.violation(&apex, key) {
+                None => true,
+                Some(violation) => {
+                    warn!(
+                        zone = %zone_name,
+                        name = %key.name,
+                        rtype = %key.record_type,
+                        violation,
+                        "dropped records"
+                    );
+                    false
+                }
+            })
+            .collect()
+    }
+
+    /// Why the records of `key` are not allowed in the zone `apex`, if they are not.
+    fn violation(&self, apex: &LowerName, key: &RrKey) -> Option<&'static str> {
+        let is_apex = key.name == *apex;
+        match key.record_type {
+            _ if !apex.zone_of(&key.name) => Some("outside of the zone"),
+            RecordType::SOA if !is_apex => Some("SOA outside of the apex"),
+            RecordType::NS if is_apex => Some("NS of the apex are the origin's"),
+            RecordType::NS if !self.allow_delegations => Some("delegations are not allowed"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::serialize::txt::Parser;
+
+    fn zone_records(zone_name: &Name) -> ZoneRecords {
+        let zone_file = "@ 3600 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+                         1 3600 600 86400 300\n\
+                         @ 3600 IN NS ns.example.com.\n\
+                         www 300 IN A 1.2.3.4\n\
+                         www 300 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+                         1 3600 600 86400 300\n\
+                         sub 300 IN NS ns.example.com.\n\
+                         bob.nostr.dns.name. 300 IN A 6.6.6.6\n\
+                         $ORIGIN nostr.dns.name.\n\
+                         carol 300 IN A 6.6.6.6\n";
+        let (_, records) = Parser::new(zone_file, None, Some(zone_name.clone()))
+            .parse()
+            .unwrap();
+        records
+    }
+
+    fn keys(records: &ZoneRecords) -> Vec<(String, RecordType)> {
+        records
+            .keys()
+            .map(|key| (key.name.to_string(), key.record_type))
+            .collect()
+    }
+
+    #[test]
+    fn test_scope() {
+        let zone_name = "alice.nostr.dns.name.".parse().unwrap();
+        let records = ZonePolicy::default().scope(&zone_name, zone_records(&zone_name));
+        assert_eq!(
+            keys(&records),
+            vec![
+                ("alice.nostr.dns.name.".into(), RecordType::SOA),
+                ("www.alice.nostr.dns.name.".into(), RecordType::A),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scope_allows_delegations() {
+        let zone_name = "alice.nostr.dns.name.".parse().unwrap();
+        let policy = ZonePolicy {
+            allow_delegations: true,
+        };
+        let records = policy.scope(&zone_name, zone_records(&zone_name));
+        assert!(keys(&records).contains(&("sub.alice.nostr.dns.name.".into(), RecordType::NS)));
+        assert!(!keys(&records).contains(&("alice.nostr.dns.name.".into(), RecordType::NS)));
+    }
+}
@@ -0,0 +1,202 @@
+use hickory_server::{
+    proto::rr::{
+        dnssec::{
+            rdata::{DNSKEY, DS},
+            Algorithm, DigestType, DnsSecResult, KeyFormat, KeyPair, Private, SigSigner,
+        },
+        Name,
+    },
+    store::in_memory::InMemoryAuthority,
+};
+use std::{path::Path, time::Duration};
+
+/// Default validity of the signatures, renewed when half of it has elapsed.
+pub const DEFAULT_SIGNATURE_LIFETIME: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Algorithms of the operator keys, the ones supported for PKCS#8 keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum SigningAlgorithm {
+    #[serde(rename = "ECDSAP256SHA256")]
+    EcdsaP256Sha256,
+
+    #[serde(rename = "ECDSAP384SHA384")]
+    EcdsaP384Sha384,
+
+    #[serde(rename = "ED25519")]
+    Ed25519,
+}
+
+impl From<SigningAlgorithm> for Algorithm {
+    fn from(algorithm: SigningAlgorithm) -> Self {
+        match algorithm {
+            SigningAlgorithm::EcdsaP256Sha256 => Algorithm::ECDSAP256SHA256,
+            SigningAlgorithm::EcdsaP384Sha384 => Algorithm::ECDSAP384SHA384,
+            SigningAlgorithm::Ed25519 => Algorithm::ED25519,
+        }
+    }
+}
+
+/// Online DNSSEC signer of an origin and of the zones of its Name-Token labels, holding a key of
+/// the operator.
+///
+/// The zones of the labels are not delegated, so to the resolvers they are part of the origin:
+/// every zone is signed with the key published as the DNSKEY of the origin, which the parent
+/// zone of the origin vouches for with the DS record given by `ds`. Denial of existence uses the
+/// NSEC chain of each zone, the one of the origin covering the labels without a Name-Token.
+pub struct ZoneSigner {
+    algorithm: Algorithm,
+    pkcs8_key: Vec<u8>,
+    signer_name: Name,
+    signature_lifetime: Duration,
+}
+
+impl ZoneSigner {
+    /// Signer of the zones of `origin` with the PKCS#8 encoded key `pkcs8_key`.
+    pub fn new(
+        origin: Name,
+        algorithm: SigningAlgorithm,
+        pkcs8_key: Vec<u8>,
+    ) -> DnsSecResult<Self> {
+        let zone_signer = Self {
+            algorithm: algorithm.into(),
+            pkcs8_key,
+            signer_name: origin,
+            signature_lifetime: DEFAULT_SIGNATURE_LIFETIME,
+        };
+        zone_signer.key_pair()?;
+        Ok(zone_signer)
+    }
+
+    /// Signer of the zones of `origin` with the PKCS#8 DER key file at `key_path`.
+    pub fn from_key_file(
+        origin: Name,
+        algorithm: SigningAlgorithm,
+        key_path: &Path,
+    ) -> DnsSecResult<Self> {
+        let pkcs8_key = std::fs::read(key_path)
+            .map_err(|e| format!("failed to read {}: {}", key_path.display(), e))?;
+        Self::new(origin, algorithm, pkcs8_key)
+    }
+
+    /// Make the signatures valid for `signature_lifetime`.
+    pub fn with_signature_lifetime(mut self, signature_lifetime: Duration) -> Self {
+        self.signature_lifetime = signature_lifetime;
+        self
+    }
+
+    /// Time after which a signed zone must be signed again, half the lifetime of its signatures
+    /// so resolvers caching them never hold expired ones.
+    pub fn resign_interval(&self) -> Duration {
+        self.signature_lifetime / 2
+    }
+
+    /// Add the DNSKEY, NSEC and RRSIG records to `authority`, incrementing its SOA serial.
+    pub fn sign(&self, authority: &mut InMemoryAuthority) -> DnsSecResult<()> {
+        let signer = SigSigner::dnssec(
+            self.dnskey()?,
+            self.key_pair()?,
+            self.signer_name.clone(),
+            self.signature_lifetime,
+        );
+        authority.add_zone_signing_key_mut(signer)?;
+        authority.secure_zone_mut()
+    }
+
+    pub fn dnskey(&self) -> DnsSecResult<DNSKEY> {
+        self.key_pair()?.to_dnskey(self.algorithm)
+    }
+
+    /// DS record to publish in the parent zone of the origin.
+    pub fn ds(&self) -> DnsSecResult<DS> {
+        // The key tag of `KeyPair::to_ds` is not the one of the DNSKEY, so it is built here.
+        let dnskey = self.dnskey()?;
+        let digest = dnskey.to_digest(&self.signer_name, DigestType::SHA256)?;
+        Ok(DS::new(
+            dnskey.calculate_key_tag()?,
+            self.algorithm,
+            DigestType::SHA256,
+            digest.as_ref().to_vec(),
+        ))
+    }
+
+    /// Key pair decoded for each use, as it cannot be shared between the signed zones.
+    fn key_pair(&self) -> DnsSecResult<KeyPair<Private>> {
+        KeyFormat::Pkcs8.decode_key(&self.pkcs8_key, None, self.algorithm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::{
+        authority::{Authority, LookupOptions, ZoneType},
+        proto::{
+            rr::{dnssec::SupportedAlgorithms, RecordType},
+            serialize::txt::Parser,
+        },
+    };
+
+    fn zone_signer() -> ZoneSigner {
+        let pkcs8_key = KeyPair::generate_pkcs8(Algorithm::ED25519).unwrap();
+        ZoneSigner::new(
+            "nostr.dns.name.".parse().unwrap(),
+            SigningAlgorithm::Ed25519,
+            pkcs8_key,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_new_rejects_invalid_key() {
+        let zone_signer = ZoneSigner::new(
+            "nostr.dns.name.".parse().unwrap(),
+            SigningAlgorithm::Ed25519,
+            b"not a key".to_vec(),
+        );
+        assert!(zone_signer.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sign() {
+        let zone_file = "@ 3600 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+                         1 3600 600 86400 300\n\
+                         www 300 IN A 1.2.3.4\n";
+        let zone_name: Name = "alice.nostr.dns.name.".parse().unwrap();
+        let (_, records) = Parser::new(zone_file, None, Some(zone_name.clone()))
+            .parse()
+            .unwrap();
+        let mut authority =
+            InMemoryAuthority::new(zone_name, records, ZoneType::Primary, false).unwrap();
+        let zone_signer = zone_signer();
+        zone_signer.sign(&mut authority).unwrap();
+
+        let lookup_options = LookupOptions::for_dnssec(true, SupportedAlgorithms::all());
+        let name = "www.alice.nostr.dns.name.".parse().unwrap();
+        let lookup = authority
+            .lookup(&name, RecordType::A, lookup_options)
+            .await
+            .unwrap();
+        let rrsig = lookup
+            .iter()
+            .find_map(|record| record.data()?.as_dnssec()?.as_rrsig())
+            .unwrap();
+        assert_eq!(rrsig.signer_name(), &"nostr.dns.name.".parse().unwrap());
+        assert_eq!(
+            rrsig.key_tag(),
+            zone_signer.dnskey().unwrap().calculate_key_tag().unwrap()
+        );
+        let nsec = authority.get_nsec_records(&name, lookup_options).await;
+        assert!(nsec.unwrap().iter().next().is_some());
+    }
+
+    #[test]
+    fn test_ds() {
+        let zone_signer = zone_signer();
+        let ds = zone_signer.ds().unwrap();
+        assert_eq!(
+            ds.key_tag(),
+            zone_signer.dnskey().unwrap().calculate_key_tag().unwrap()
+        );
+        assert_eq!(ds.digest_type(), DigestType::SHA256);
+    }
+}
@@ -0,0 +1,212 @@
+use hickory_server::{
+    proto::{
+        op::{Message, MessageType, OpCode, Query},
+        rr::{Name, Record, RecordType},
+        serialize::binary::BinEncodable,
+    },
+    store::in_memory::InMemoryAuthority,
+};
+use ipnet::IpNet;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+use tracing::{error, warn};
+
+/// Default time between two checks of the Name-Tokens and zones for changes.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time to wait for a secondary to acknowledge a NOTIFY before sending it again.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Times a NOTIFY is sent to a secondary that does not acknowledge it.
+const NOTIFY_ATTEMPTS: usize = 3;
+
+/// Transfers of the whole origin zone, assembled from the zones of every indexed Name-Token, to
+/// secondary servers.
+///
+/// Transfers are allowed to the clients whose address is in one of the allowed networks, or
+/// that sign their requests with one of the allowed TSIG keys. The secondaries to notify are sent
+/// a NOTIFY, as in RFC 1996, every time the assembled zone changes.
+pub struct ZoneTransfers {
+    allowed_networks: Vec<IpNet>,
+    tsig_key_names: Vec<Name>,
+    notify_addrs: Vec<SocketAddr>,
+    zone: Mutex<Option<TransferZone>>,
+}
+
+/// Origin zone last assembled for the secondaries.
+#[derive(Clone)]
+pub struct TransferZone {
+    pub serial: u32,
+
+    /// Hash of the Name-Tokens and zone events the zone was assembled from.
+    pub fingerprint: u64,
+
+    pub authority: Arc<InMemoryAuthority>,
+
+    pub assembled_at: Instant,
+}
+
+impl Default for ZoneTransfers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ZoneTransfers {
+    /// Transfers allowed to no client.
+    pub fn new() -> Self {
+        Self {
+            allowed_networks: vec![],
+            tsig_key_names: vec![],
+            notify_addrs: vec![],
+            zone: Mutex::new(None),
+        }
+    }
+
+    /// Allow the transfers to the clients in `allowed_networks`.
+    pub fn with_allowed_networks(mut self, allowed_networks: Vec<IpNet>) -> Self {
+        self.allowed_networks = allowed_networks;
+        self
+    }
+
+    /// Allow the transfers signed with the TSIG keys named `tsig_key_names`, as verified by the
+    /// `DnsRequestHandler`.
+    pub fn with_tsig_key_names(mut self, tsig_key_names: Vec<Name>) -> Self {
+        self.tsig_key_names = tsig_key_names;
+        self
+    }
+
+    /// Notify the secondaries at `notify_addrs` of the changes of the zone.
+    pub fn with_notify_addrs(mut self, notify_addrs: Vec<SocketAddr>) -> Self {
+        self.notify_addrs = notify_addrs;
+        self
+    }
+
+    /// Whether a transfer is allowed to a client at `src`, that signed its request with the TSIG
+    /// key `tsig_key_name`, if any.
+    pub fn allows(&self, src: IpAddr, tsig_key_name: Option<&Name>) -> bool {
+        let src = match src {
+            IpAddr::V6(src) => src.to_ipv4_mapped().map_or(IpAddr::V6(src), IpAddr::V4),
+            src => src,
+        };
+        self.allowed_networks
+            .iter()
+            .any(|network| network.contains(&src))
+            || tsig_key_name
+                .is_some_and(|tsig_key_name| self.tsig_key_names.contains(tsig_key_name))
+    }
+
+    /// Zone last assembled, if any.
+    pub fn zone(&self) -> Option<TransferZone> {
+        self.zone.lock().unwrap().clone()
+    }
+
+    /// Serial of the zone last assembled, if any.
+    pub fn serial(&self) -> Option<u32> {
+        self.zone.lock().unwrap().as_ref().map(|zone| zone.serial)
+    }
+
+    /// Serial of the next zone, `candidate` unless it would not be greater than the current one.
+    pub fn next_serial(&self, candidate: u32) -> u32 {
+        next_serial(self.serial(), candidate)
+    }
+
+    pub fn set_zone(&self, zone: TransferZone) {
+        *self.zone.lock().unwrap() = Some(zone);
+    }
+
+    /// Send a NOTIFY of the new `soa` to the secondaries, retrying the ones that do not answer.
+    pub async fn notify(&self, soa: &Record) {
+        let mut message = Message::new();
+        message
+            .set_id(notify_id())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Notify)
+            .set_authoritative(true)
+            .add_query(Query::query(soa.name().clone(), RecordType::SOA))
+            .add_answer(soa.clone());
+        let message = match message.to_bytes() {
+            Ok(message) => message,
+            Err(e) => {
+                error!(zone = %soa.name(), error = %e, "failed to encode NOTIFY");
+                return;
+            }
+        };
+        for notify_addr in &self.notify_addrs {
+            if let Err(e) = send_notify(&message, *notify_addr).await {
+                warn!(zone = %soa.name(), secondary = %notify_addr, error = %e, "failed to notify");
+            }
+        }
+    }
+}
+
+/// Serial following `last_serial`, in the serial number arithmetic of RFC 1982.
+fn next_serial(last_serial: Option<u32>, candidate: u32) -> u32 {
+    match last_serial {
+        Some(last_serial) if (candidate.wrapping_sub(last_serial) as i32) <= 0 => {
+            last_serial.wrapping_add(1)
+        }
+        _ => candidate,
+    }
+}
+
+/// Send the NOTIFY `message` to `notify_addr` until it answers.
+async fn send_notify(message: &[u8], notify_addr: SocketAddr) -> io::Result<()> {
+    let bind_addr = match notify_addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(notify_addr).await?;
+    let mut response = [0; 512];
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send(message).await?;
+        if let Ok(received) = tokio::time::timeout(NOTIFY_TIMEOUT, socket.recv(&mut response)).await
+        {
+            received?;
+            return Ok(());
+        }
+    }
+    Err(io::ErrorKind::TimedOut.into())
+}
+
+/// Message id of a NOTIFY, which only needs to differ between consecutive messages.
+fn notify_id() -> u16 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let zone_transfers = ZoneTransfers::new()
+            .with_allowed_networks(vec!["192.0.2.0/24".parse().unwrap()])
+            .with_tsig_key_names(vec!["transfer.".parse().unwrap()]);
+        assert!(zone_transfers.allows("192.0.2.53".parse().unwrap(), None));
+        assert!(zone_transfers.allows("::ffff:192.0.2.53".parse().unwrap(), None));
+        assert!(!zone_transfers.allows("198.51.100.1".parse().unwrap(), None));
+        let tsig_key_name = "transfer.".parse().unwrap();
+        assert!(zone_transfers.allows("198.51.100.1".parse().unwrap(), Some(&tsig_key_name)));
+        let tsig_key_name = "other.".parse().unwrap();
+        assert!(!zone_transfers.allows("198.51.100.1".parse().unwrap(), Some(&tsig_key_name)));
+    }
+
+    #[test]
+    fn test_next_serial() {
+        assert_eq!(next_serial(None, 42), 42);
+        assert_eq!(next_serial(Some(41), 42), 42);
+        assert_eq!(next_serial(Some(42), 42), 43);
+        assert_eq!(next_serial(Some(50), 42), 51);
+        assert_eq!(next_serial(Some(u32::MAX), 3), 3);
+    }
+}
@@ -0,0 +1,597 @@
+use crate::{zone_decoder::ZoneRecords, zone_events_database::ZoneEventsDatabase};
+use hickory_server::{
+    authority::{MessageRequest, UpdateRequest, UpdateResult},
+    proto::{
+        op::ResponseCode,
+        rr::{
+            dnssec::{
+                rdata::{tsig::TsigAlgorithm, DNSSECRData, SIG},
+                tsig::TSigner,
+                Verifier,
+            },
+            domain::Label,
+            rdata::SOA,
+            DNSClass, LowerName, Name, RData, Record, RecordSet, RecordType, RrKey,
+        },
+        serialize::binary::BinEncodable,
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+/// Seconds of clock skew allowed between the signers of the updates and the server.
+pub const MAX_CLOCK_SKEW: u16 = 300;
+
+/// TTL of the SOA of the zones created by an update.
+const DEFAULT_SOA_TTL: u32 = 3600;
+
+/// HMAC algorithms of the TSIG keys, as named by `tsig-keygen`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum TsigKeyAlgorithm {
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+
+    #[serde(rename = "hmac-sha384")]
+    HmacSha384,
+
+    #[serde(rename = "hmac-sha512")]
+    HmacSha512,
+}
+
+impl From<TsigKeyAlgorithm> for TsigAlgorithm {
+    fn from(algorithm: TsigKeyAlgorithm) -> Self {
+        match algorithm {
+            TsigKeyAlgorithm::HmacSha256 => TsigAlgorithm::HmacSha256,
+            TsigKeyAlgorithm::HmacSha384 => TsigAlgorithm::HmacSha384,
+            TsigKeyAlgorithm::HmacSha512 => TsigAlgorithm::HmacSha512,
+        }
+    }
+}
+
+/// Keys an owner handed to the operator to update the zone of its label.
+#[derive(Clone, Default)]
+pub struct LabelUpdateKeys {
+    /// TSIG keys the updates of the label can be signed with.
+    pub tsig_signers: Vec<TSigner>,
+
+    /// Nostr key of the Name-Token, delegated by its owner so the updated zones are published
+    /// without going through its wallet.
+    pub nostr_keys: Option<nostr_sdk::Keys>,
+}
+
+/// RFC 2136 dynamic updates of the zones of the Name-Token labels.
+///
+/// Updates signed with SIG(0) are verified with the KEY records of the zone being updated. The
+/// owner publishes them in a zone event signed with the Nostr key of its Name-Token, so only the
+/// holder of that key can grant them. Updates signed with TSIG are verified with the keys
+/// configured for the label.
+///
+/// The updated zone is published as a new zone event when the operator holds the Nostr key of
+/// the Name-Token, and otherwise queued unsigned in the zone events database, for the wallet of
+/// the owner to sign and publish it. Until then, the following updates apply to the queued zone,
+/// so none of them is lost. The queue is listed with `--pending-zone-events`.
+#[derive(Clone)]
+pub struct ZoneUpdates {
+    zone_events_database: ZoneEventsDatabase,
+    label_keys: HashMap<String, LabelUpdateKeys>,
+}
+
+impl ZoneUpdates {
+    pub fn new(zone_events_database: ZoneEventsDatabase) -> Self {
+        Self {
+            zone_events_database,
+            label_keys: HashMap::new(),
+        }
+    }
+
+    /// Accept the updates of the zone of `label` signed with `label_keys` too.
+    pub fn with_label_keys(mut self, label: &Label, label_keys: LabelUpdateKeys) -> Self {
+        self.label_keys.insert(label_key(label), label_keys);
+        self
+    }
+
+    /// Keys handed by the owner of `label`, none when it did not hand any.
+    pub fn label_keys(&self, label: &Label) -> LabelUpdateKeys {
+        self.label_keys
+            .get(&label_key(label))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Zone event of `kind` and `identifier` queued for the wallet of `pubkey`, on which the
+    /// next updates of the zone build until the owner publishes it.
+    pub async fn pending_zone_event(
+        &self,
+        pubkey: &nostr_sdk::PublicKey,
+        kind: nostr_sdk::Kind,
+        identifier: &str,
+    ) -> rusqlite::Result<Option<nostr_sdk::UnsignedEvent>> {
+        self.zone_events_database
+            .get_unsigned_event(pubkey, kind, identifier)
+            .await
+    }
+
+    /// Queue `zone_event` for the wallet of the owner to sign and publish, in place of the zone
+    /// event already queued for the label.
+    pub async fn queue_zone_event(
+        &self,
+        zone_event: &nostr_sdk::UnsignedEvent,
+    ) -> rusqlite::Result<()> {
+        self.zone_events_database
+            .queue_unsigned_event(zone_event)
+            .await
+    }
+}
+
+fn label_key(label: &Label) -> String {
+    label.to_lowercase().to_ascii()
+}
+
+/// Records of a zone `zone_name` created by an update, holding only its SOA.
+pub fn new_zone_records(zone_name: &Name) -> ZoneRecords {
+    let serial = unix_time_now() as u32;
+    let rname = Name::from_ascii("hostmaster")
+        .and_then(|hostmaster| hostmaster.append_domain(zone_name))
+        .unwrap_or_else(|_| zone_name.clone());
+    let soa = SOA::new(zone_name.clone(), rname, serial, 3600, 600, 86400, 300);
+    let soa_record = Record::from_rdata(zone_name.clone(), DEFAULT_SOA_TTL, RData::SOA(soa));
+    let mut records = ZoneRecords::new();
+    let mut record_set = RecordSet::new(zone_name, RecordType::SOA, 0);
+    record_set.insert(soa_record, 0);
+    records.insert(
+        RrKey::new(LowerName::new(zone_name), RecordType::SOA),
+        record_set,
+    );
+    records
+}
+
+/// Check that `update` is signed by a TSIG key of `tsig_signers` or with SIG(0) by a KEY record
+/// of the zone, see RFC 2136 section 3.3, refusing it otherwise.
+///
+/// The signatures are checked over the request as re-encoded by the server, as for SIG(0) in
+/// hickory, so clients compressing names differently are not authenticated.
+pub fn authorize(
+    update: &MessageRequest,
+    records: &ZoneRecords,
+    tsig_signers: &[TSigner],
+) -> UpdateResult<()> {
+    let now = unix_time_now();
+    let is_authorized =
+        update.sig0().iter().any(
+            |signature| match signature.data().and_then(RData::as_dnssec) {
+                Some(DNSSECRData::SIG(sig)) => verify_sig0(update, sig, records, now),
+                Some(DNSSECRData::TSIG(_)) => verify_tsig(update, signature, tsig_signers, now),
+                _ => false,
+            },
+        );
+    if !is_authorized {
+        warn!(
+            id = update.id(),
+            zone = %update.zone().name(),
+            "refused unauthenticated update"
+        );
+        return Err(ResponseCode::Refused);
+    }
+    Ok(())
+}
+
+fn verify_sig0(update: &MessageRequest, sig: &SIG, records: &ZoneRecords, now: u64) -> bool {
+    // Signers like hickory's sign with no validity period, so the clock skew is allowed around it.
+    let now = now as u32;
+    let max_clock_skew = u32::from(MAX_CLOCK_SKEW);
+    if now.saturating_add(max_clock_skew) < sig.sig_inception()
+        || now > sig.sig_expiration().saturating_add(max_clock_skew)
+    {
+        return false;
+    }
+    let key = RrKey::new(LowerName::new(sig.signer_name()), RecordType::KEY);
+    records
+        .get(&key)
+        .into_iter()
+        .flat_map(RecordSet::records_without_rrsigs)
+        .filter_map(|record| record.data()?.as_dnssec()?.as_key())
+        .any(|key| key.verify_message(update, sig.sig(), sig).is_ok())
+}
+
+fn verify_tsig(
+    update: &MessageRequest,
+    signature: &Record,
+    tsig_signers: &[TSigner],
+    now: u64,
+) -> bool {
+    tsig_signer_of(update, signature, tsig_signers, now).is_some()
+}
+
+/// Key of `tsig_signers` that signed `request` with TSIG, and the MAC of the request, which
+/// signatures of the response cover. Like updates, the request is verified as re-encoded.
+pub fn verify_request_tsig(
+    request: &MessageRequest,
+    tsig_signers: &[TSigner],
+) -> Option<(TSigner, Vec<u8>)> {
+    let now = unix_time_now();
+    request
+        .sig0()
+        .iter()
+        .filter(|signature| signature.record_type() == RecordType::TSIG)
+        .find_map(|signature| tsig_signer_of(request, signature, tsig_signers, now))
+}
+
+fn tsig_signer_of(
+    message: &MessageRequest,
+    signature: &Record,
+    tsig_signers: &[TSigner],
+    now: u64,
+) -> Option<(TSigner, Vec<u8>)> {
+    let message = message.to_bytes().ok()?;
+    tsig_signers
+        .iter()
+        .filter(|tsig_signer| tsig_signer.signer_name() == signature.name())
+        .find_map(|tsig_signer| {
+            let (mac, valid_times, _) =
+                tsig_signer.verify_message_byte(None, &message, true).ok()?;
+            valid_times
+                .contains(&now)
+                .then(|| (tsig_signer.clone(), mac))
+        })
+}
+
+/// Apply `update` to the `records` of the zone `zone_name`, returning whether the zone changed.
+///
+/// The prerequisites are checked and the updates applied as in RFC 2136 sections 3.2 and 3.4,
+/// and the serial of the zone incremented as in section 3.6. The DNSSEC records are signed by
+/// the operator or the owner, so updates of them are refused.
+pub fn update_zone(
+    update: &MessageRequest,
+    zone_name: &Name,
+    records: &mut ZoneRecords,
+) -> UpdateResult<bool> {
+    let zone_name = LowerName::new(zone_name);
+    verify_prerequisites(&zone_name, records, update.prerequisites())?;
+    pre_scan(&zone_name, update.updates())?;
+    let updated = apply_updates(&zone_name, records, update.updates());
+    let updates_soa = update
+        .updates()
+        .iter()
+        .any(|record| record.record_type() == RecordType::SOA);
+    if updated && !updates_soa {
+        increment_serial(&zone_name, records);
+    }
+    Ok(updated)
+}
+
+/// Check the prerequisite section of an update, see RFC 2136 section 3.2.
+fn verify_prerequisites(
+    zone_name: &LowerName,
+    records: &ZoneRecords,
+    prerequisites: &[Record],
+) -> UpdateResult<()> {
+    let mut required_rrsets: BTreeMap<RrKey, Vec<&RData>> = BTreeMap::new();
+    for prerequisite in prerequisites {
+        let name = LowerName::new(prerequisite.name());
+        let rtype = prerequisite.record_type();
+        if prerequisite.ttl() != 0 {
+            return Err(ResponseCode::FormErr);
+        }
+        if !zone_name.zone_of(&name) {
+            return Err(ResponseCode::NotZone);
+        }
+        let name_in_use = records.keys().any(|key| key.name == name);
+        let rrset_exists = records.contains_key(&RrKey::new(name.clone(), rtype));
+        match prerequisite.dns_class() {
+            DNSClass::ANY | DNSClass::NONE if !has_empty_rdata(prerequisite) => {
+                return Err(ResponseCode::FormErr)
+            }
+            DNSClass::ANY if rtype == RecordType::ANY && !name_in_use => {
+                return Err(ResponseCode::NXDomain)
+            }
+            DNSClass::ANY if rtype != RecordType::ANY && !rrset_exists => {
+                return Err(ResponseCode::NXRRSet)
+            }
+            DNSClass::NONE if rtype == RecordType::ANY && name_in_use => {
+                return Err(ResponseCode::YXDomain)
+            }
+            DNSClass::NONE if rtype != RecordType::ANY && rrset_exists => {
+                return Err(ResponseCode::YXRRSet)
+            }
+            DNSClass::ANY | DNSClass::NONE => {}
+            DNSClass::IN => {
+                let rdata = prerequisite.data().ok_or(ResponseCode::FormErr)?;
+                required_rrsets
+                    .entry(RrKey::new(name, rtype))
+                    .or_default()
+                    .push(rdata);
+            }
+            _ => return Err(ResponseCode::FormErr),
+        }
+    }
+    // The value dependent prerequisites must match whole record sets of the zone.
+    for (key, required_rdatas) in required_rrsets {
+        let rdatas: Vec<&RData> = records
+            .get(&key)
+            .into_iter()
+            .flat_map(RecordSet::records_without_rrsigs)
+            .filter_map(Record::data)
+            .collect();
+        let matches = required_rdatas.iter().all(|rdata| rdatas.contains(rdata))
+            && rdatas.iter().all(|rdata| required_rdatas.contains(rdata));
+        if !matches {
+            return Err(ResponseCode::NXRRSet);
+        }
+    }
+    Ok(())
+}
+
+/// Check the update section before applying any of it, see RFC 2136 section 3.4.1.
+fn pre_scan(zone_name: &LowerName, updates: &[Record]) -> UpdateResult<()> {
+    for update in updates {
+        if !zone_name.zone_of(&LowerName::new(update.name())) {
+            return Err(ResponseCode::NotZone);
+        }
+        let rtype = update.record_type();
+        if is_dnssec_type(rtype) {
+            warn!(name = %update.name(), %rtype, "refused update of DNSSEC records");
+            return Err(ResponseCode::Refused);
+        }
+        let is_meta_type = matches!(rtype, RecordType::AXFR | RecordType::IXFR);
+        let is_valid = match update.dns_class() {
+            DNSClass::IN => !is_meta_type && rtype != RecordType::ANY,
+            DNSClass::ANY => update.ttl() == 0 && has_empty_rdata(update) && !is_meta_type,
+            DNSClass::NONE => update.ttl() == 0 && !is_meta_type && rtype != RecordType::ANY,
+            _ => false,
+        };
+        if !is_valid {
+            return Err(ResponseCode::FormErr);
+        }
+    }
+    Ok(())
+}
+
+/// Apply the update section, see RFC 2136 section 3.4.2, returning whether the zone changed.
+fn apply_updates(zone_name: &LowerName, records: &mut ZoneRecords, updates: &[Record]) -> bool {
+    let mut updated = false;
+    for update in updates {
+        let name = LowerName::new(update.name());
+        let rtype = update.record_type();
+        let key = RrKey::new(name.clone(), rtype);
+        let is_apex = name == *zone_name;
+        match update.dns_class() {
+            // Delete RRsets, never the SOA and NS records of the apex.
+            DNSClass::ANY => match rtype {
+                RecordType::SOA | RecordType::NS if is_apex => {}
+                RecordType::ANY => {
+                    let num_rrsets = records.len();
+                    records.retain(|key, _| {
+                        key.name != name
+                            || (is_apex
+                                && matches!(key.record_type, RecordType::SOA | RecordType::NS))
+                    });
+                    updated |= records.len() != num_rrsets;
+                }
+                _ => updated |= records.remove(&key).is_some(),
+            },
+            // Delete an RR from an RRset, the record set refusing to delete the SOA or last NS.
+            DNSClass::NONE => {
+                if let Some(record_set) = records.get_mut(&key) {
+                    updated |= record_set.remove(update, 0);
+                    if record_set.is_empty() {
+                        records.remove(&key);
+                    }
+                }
+            }
+            // Add to an RRset, unless a CNAME would coexist with other data.
+            _ => {
+                let has_cname = records.contains_key(&RrKey::new(name.clone(), RecordType::CNAME));
+                let has_other_data = records
+                    .keys()
+                    .any(|key| key.name == name && key.record_type != RecordType::CNAME);
+                let conflicts = match rtype {
+                    RecordType::CNAME => has_other_data,
+                    RecordType::SOA => !is_apex,
+                    _ => has_cname,
+                };
+                if conflicts {
+                    continue;
+                }
+                updated |= records
+                    .entry(key)
+                    .or_insert_with(|| RecordSet::new(update.name(), rtype, 0))
+                    .insert(update.clone(), 0);
+            }
+        }
+    }
+    updated
+}
+
+/// Increment the serial of the SOA of the apex, so secondaries and caches see the change.
+fn increment_serial(zone_name: &LowerName, records: &mut ZoneRecords) {
+    let key = RrKey::new(zone_name.clone(), RecordType::SOA);
+    let Some(record_set) = records.get_mut(&key) else {
+        return;
+    };
+    let Some(mut soa_record) = record_set.records_without_rrsigs().next().cloned() else {
+        return;
+    };
+    let Some(mut soa) = soa_record.data().and_then(RData::as_soa).cloned() else {
+        return;
+    };
+    soa.increment_serial();
+    soa_record.set_data(Some(RData::SOA(soa)));
+    record_set.insert(soa_record, 0);
+}
+
+fn has_empty_rdata(record: &Record) -> bool {
+    matches!(record.data(), None | Some(RData::NULL(..)))
+}
+
+/// Whether `rtype` records are maintained by the signer of the zone rather than published.
+pub fn is_dnssec_type(rtype: RecordType) -> bool {
+    matches!(
+        rtype,
+        RecordType::DNSKEY
+            | RecordType::DS
+            | RecordType::RRSIG
+            | RecordType::NSEC
+            | RecordType::NSEC3
+            | RecordType::NSEC3PARAM
+    )
+}
+
+fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_server::proto::{
+        op::{update_message, Message},
+        rr::dnssec::{Algorithm, KeyFormat, KeyPair, SigSigner},
+        serialize::{binary::BinDecodable, txt::Parser},
+    };
+
+    fn zone_name() -> Name {
+        "alice.nostr.dns.name.".parse().unwrap()
+    }
+
+    fn zone_records() -> ZoneRecords {
+        let zone_file = "@ 3600 IN SOA alice.nostr.dns.name. hostmaster.alice.nostr.dns.name. \
+                         1 3600 600 86400 300\n\
+                         www 300 IN A 1.2.3.4\n";
+        let (_, records) = Parser::new(zone_file, None, Some(zone_name()))
+            .parse()
+            .unwrap();
+        records
+    }
+
+    fn a_record(name: &str, address: &str) -> Record {
+        let name = Name::from_ascii(name).unwrap();
+        Record::from_rdata(name, 300, RData::A(address.parse().unwrap()))
+    }
+
+    fn record_set(record: Record) -> RecordSet {
+        let mut record_set = RecordSet::new(record.name(), record.record_type(), 0);
+        record_set.insert(record, 0);
+        record_set
+    }
+
+    fn message_request(message: &Message) -> MessageRequest {
+        MessageRequest::from_bytes(&message.to_bytes().unwrap()).unwrap()
+    }
+
+    fn tsig_signer(key: &[u8]) -> TSigner {
+        TSigner::new(
+            key.to_vec(),
+            TsigAlgorithm::HmacSha256,
+            "alice-key.".parse().unwrap(),
+            MAX_CLOCK_SKEW,
+        )
+        .unwrap()
+    }
+
+    fn soa_serial(records: &ZoneRecords) -> u32 {
+        let key = RrKey::new(LowerName::new(&zone_name()), RecordType::SOA);
+        records[&key]
+            .records_without_rrsigs()
+            .find_map(|record| record.data()?.as_soa())
+            .unwrap()
+            .serial()
+    }
+
+    #[test]
+    fn test_authorize_tsig() {
+        let create = record_set(a_record("mail.alice.nostr.dns.name.", "5.6.7.8"));
+        let mut message = update_message::create(create, zone_name(), false);
+        let tsig_signer = tsig_signer(b"owner secret");
+        message
+            .finalize(&tsig_signer, unix_time_now() as u32)
+            .unwrap();
+        let update = message_request(&message);
+
+        assert!(authorize(&update, &zone_records(), &[tsig_signer]).is_ok());
+        assert_eq!(
+            authorize(&update, &zone_records(), &[self::tsig_signer(b"other")]),
+            Err(ResponseCode::Refused)
+        );
+        assert_eq!(
+            authorize(&update, &zone_records(), &[]),
+            Err(ResponseCode::Refused)
+        );
+    }
+
+    #[test]
+    fn test_authorize_sig0() {
+        let pkcs8_key = KeyPair::generate_pkcs8(Algorithm::ED25519).unwrap();
+        let key_pair = KeyFormat::Pkcs8
+            .decode_key(&pkcs8_key, None, Algorithm::ED25519)
+            .unwrap();
+        let key = key_pair.to_sig0key(Algorithm::ED25519).unwrap();
+        let sig0_signer = SigSigner::sig0(key.clone(), key_pair, zone_name());
+
+        let create = record_set(a_record("mail.alice.nostr.dns.name.", "5.6.7.8"));
+        let mut message = update_message::create(create, zone_name(), false);
+        message
+            .finalize(&sig0_signer, unix_time_now() as u32)
+            .unwrap();
+        let update = message_request(&message);
+
+        let mut records = zone_records();
+        assert_eq!(
+            authorize(&update, &records, &[]),
+            Err(ResponseCode::Refused)
+        );
+        let key_record = Record::from_rdata(zone_name(), 300, RData::DNSSEC(DNSSECRData::KEY(key)));
+        records.insert(
+            RrKey::new(LowerName::new(&zone_name()), RecordType::KEY),
+            record_set(key_record),
+        );
+        assert!(authorize(&update, &records, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_update_zone() {
+        let mut records = zone_records();
+        let create = record_set(a_record("mail.alice.nostr.dns.name.", "5.6.7.8"));
+        let update = message_request(&update_message::create(create.clone(), zone_name(), false));
+        assert_eq!(update_zone(&update, &zone_name(), &mut records), Ok(true));
+        let mail = RrKey::new(
+            LowerName::new(&"mail.alice.nostr.dns.name.".parse().unwrap()),
+            RecordType::A,
+        );
+        assert!(records.contains_key(&mail));
+        assert_eq!(soa_serial(&records), 2);
+
+        // Creating a record set requires it not to exist yet.
+        assert_eq!(
+            update_zone(&update, &zone_name(), &mut records),
+            Err(ResponseCode::YXRRSet)
+        );
+
+        let delete = update_message::delete_by_rdata(create, zone_name(), false);
+        let update = message_request(&delete);
+        assert_eq!(update_zone(&update, &zone_name(), &mut records), Ok(true));
+        assert!(!records.contains_key(&mail));
+        assert_eq!(soa_serial(&records), 3);
+
+        let outside = record_set(a_record("bob.nostr.dns.name.", "6.6.6.6"));
+        let parent_zone_name = "nostr.dns.name.".parse().unwrap();
+        let append = update_message::append(outside, parent_zone_name, false, false);
+        let update = message_request(&append);
+        assert_eq!(
+            update_zone(&update, &zone_name(), &mut records),
+            Err(ResponseCode::NotZone)
+        );
+    }
+
+    #[test]
+    fn test_new_zone_records() {
+        let records = new_zone_records(&zone_name());
+        assert_eq!(records.len(), 1);
+        assert!(soa_serial(&records) > 0);
+    }
+}